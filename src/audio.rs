@@ -0,0 +1,744 @@
+// Decoder for MPEG-1 audio layers I and II as specified by ISO
+// 11172-3. Like the video decoder, it follows the structure of
+// pl_mpeg, but uses floating point arithmetic and the reference
+// matrixing of the synthesis filter bank.
+
+use super::DecodeError;
+use bitstream_io::BitRead;
+use std::io::Read;
+
+use log::trace;
+
+/// Bit rates in kbit/s by bitrate_index. Index 0 is the free format.
+const LAYER_1_BIT_RATES: [u32; 15] = [
+    0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+];
+const LAYER_2_BIT_RATES: [u32; 15] = [
+    0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+];
+const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+const MODE_JOINT_STEREO: u8 = 1;
+const MODE_MONO: u8 = 3;
+
+/// Layer II quantization classes of Table 3-B.4: number of steps,
+/// whether three consecutive samples are grouped into one code, and
+/// bits per code.
+#[rustfmt::skip]
+const QUANT_CLASSES: [(u32, bool, u32); 17] = [
+    (3, true, 5),
+    (5, true, 7),
+    (7, false, 3),
+    (9, true, 10),
+    (15, false, 4),
+    (31, false, 5),
+    (63, false, 6),
+    (127, false, 7),
+    (255, false, 8),
+    (511, false, 9),
+    (1023, false, 10),
+    (2047, false, 11),
+    (4095, false, 12),
+    (8191, false, 13),
+    (16383, false, 14),
+    (32767, false, 15),
+    (65535, false, 16),
+];
+
+/// Columns of the Layer II bit allocation tables 3-B.2a to 3-B.2d.
+/// Each maps an allocation to the quantization class, counting from
+/// 1. Allocation 0 means no samples are transmitted.
+#[rustfmt::skip]
+const ALLOCATIONS: [&[u8]; 5] = [
+    &[0, 1, 2, 17],
+    &[0, 1, 2, 3, 4, 5, 6, 17],
+    &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 17],
+    &[0, 1, 3, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17],
+    &[0, 1, 2, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16],
+];
+
+/// Bits of the allocation field and column of `ALLOCATIONS` for each
+/// subband of the tables for high bit rates, 3-B.2a and 3-B.2b. They
+/// only differ in the number of subbands, 27 and 30.
+#[rustfmt::skip]
+const HIGH_RATE_SUBBANDS: [(u32, usize); 30] = [
+    (4, 3), (4, 3), (4, 3),
+    (4, 2), (4, 2), (4, 2), (4, 2), (4, 2), (4, 2), (4, 2), (4, 2),
+    (3, 1), (3, 1), (3, 1), (3, 1), (3, 1), (3, 1),
+    (3, 1), (3, 1), (3, 1), (3, 1), (3, 1), (3, 1),
+    (2, 0), (2, 0), (2, 0), (2, 0), (2, 0), (2, 0), (2, 0),
+];
+
+/// Like `HIGH_RATE_SUBBANDS` for the tables for low bit rates,
+/// 3-B.2c and 3-B.2d, with 8 and 12 subbands.
+#[rustfmt::skip]
+const LOW_RATE_SUBBANDS: [(u32, usize); 12] = [
+    (4, 4), (4, 4),
+    (3, 4), (3, 4), (3, 4), (3, 4), (3, 4), (3, 4), (3, 4), (3, 4), (3, 4), (3, 4),
+];
+
+/// Synthesis window D[i] of Table 3-B.3 in units of 2^-16, for i from
+/// 0 to 256. The window is symmetric, D[512 - i] = D[i], except that
+/// the sign flips every 64 coefficients, which is not included here.
+#[rustfmt::skip]
+const SYNTHESIS_WINDOW: [i32; 257] = [
+         0,     -1,     -1,     -1,     -1,     -1,     -1,     -2,
+        -2,     -2,     -2,     -3,     -3,     -4,     -4,     -5,
+        -5,     -6,     -7,     -7,     -8,     -9,    -10,    -11,
+       -13,    -14,    -16,    -17,    -19,    -21,    -24,    -26,
+       -29,    -31,    -35,    -38,    -41,    -45,    -49,    -53,
+       -58,    -63,    -68,    -73,    -79,    -85,    -91,    -97,
+      -104,   -111,   -117,   -125,   -132,   -139,   -147,   -154,
+      -161,   -169,   -176,   -183,   -190,   -196,   -202,   -208,
+      -213,   -218,   -222,   -225,   -227,   -228,   -228,   -227,
+      -224,   -221,   -215,   -208,   -200,   -189,   -177,   -163,
+      -146,   -127,   -106,    -83,    -57,    -29,      2,     36,
+        72,    111,    153,    197,    244,    294,    347,    401,
+       459,    519,    581,    645,    711,    779,    848,    919,
+       991,   1064,   1137,   1210,   1283,   1356,   1428,   1498,
+      1567,   1634,   1698,   1759,   1817,   1870,   1919,   1962,
+      2001,   2032,   2057,   2075,   2085,   2087,   2080,   2063,
+      2037,   2000,   1952,   1893,   1822,   1739,   1644,   1535,
+      1414,   1280,   1131,    970,    794,    605,    402,    185,
+       -45,   -288,   -545,   -814,  -1095,  -1388,  -1692,  -2006,
+     -2330,  -2663,  -3004,  -3351,  -3705,  -4063,  -4425,  -4788,
+     -5153,  -5517,  -5879,  -6237,  -6589,  -6935,  -7271,  -7597,
+     -7910,  -8209,  -8491,  -8755,  -8998,  -9219,  -9416,  -9585,
+     -9727,  -9838,  -9916,  -9959,  -9966,  -9935,  -9863,  -9750,
+     -9592,  -9389,  -9139,  -8840,  -8492,  -8092,  -7640,  -7134,
+     -6574,  -5959,  -5288,  -4561,  -3776,  -2935,  -2037,  -1082,
+       -70,    998,   2122,   3300,   4533,   5818,   7154,   8540,
+      9975,  11455,  12980,  14548,  16155,  17799,  19478,  21189,
+     22929,  24694,  26482,  28289,  30112,  31947,  33791,  35640,
+     37489,  39336,  41176,  43006,  44821,  46617,  48390,  50137,
+     51853,  53534,  55178,  56778,  58333,  59838,  61289,  62684,
+     64019,  65290,  66494,  67629,  68692,  69679,  70590,  71420,
+     72169,  72835,  73415,  73908,  74313,  74630,  74856,  74992,
+     75038,
+];
+
+/// Subband samples of a frame, indexed by channel, time and subband.
+/// Layer I frames have 12 samples per subband, Layer II frames 36.
+type Subbands = [[[f32; 32]; 36]; 2];
+
+type AudioBitReader<'a> = bitstream_io::BitReader<&'a [u8], bitstream_io::BigEndian>;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Header {
+    layer: u8,
+    // Whether the header is followed by a CRC.
+    crc: bool,
+    // In bit/s.
+    bit_rate: u32,
+    pub(crate) sample_rate: u32,
+    padding: bool,
+    mode: u8,
+    mode_extension: u8,
+}
+
+impl Header {
+    /// Whether `b` starts with a sync word followed by a header
+    /// without reserved values.
+    pub(crate) fn is_header(b: &[u8; 4]) -> bool {
+        b[0] == 0xFF
+            && (b[1] & 0xF0) == 0xF0
+            && (b[1] & 0b0110) != 0
+            && (b[2] >> 4) != 0x0F
+            && (b[2] & 0b1100) != 0b1100
+    }
+
+    pub(crate) fn parse(b: &[u8; 4]) -> Result<Header, DecodeError> {
+        if (b[1] & 0x08) == 0 {
+            return Err(DecodeError::Unsupported("MPEG-2 audio"));
+        }
+        let layer = 4 - ((b[1] >> 1) & 0b11);
+        let bit_rates = match layer {
+            1 => &LAYER_1_BIT_RATES,
+            2 => &LAYER_2_BIT_RATES,
+            _ => return Err(DecodeError::Unsupported("MPEG audio layer III")),
+        };
+        let bit_rate = bit_rates[usize::from(b[2] >> 4)] * 1000;
+        if bit_rate == 0 {
+            return Err(DecodeError::Unsupported("free format audio"));
+        }
+
+        Ok(Header {
+            layer,
+            crc: (b[1] & 0x01) == 0,
+            bit_rate,
+            sample_rate: SAMPLE_RATES[usize::from((b[2] >> 2) & 0b11)],
+            padding: (b[2] & 0b10) != 0,
+            mode: b[3] >> 6,
+            mode_extension: (b[3] >> 4) & 0b11,
+        })
+    }
+
+    /// Length of the frame in bytes, including the header.
+    pub(crate) fn frame_len(&self) -> usize {
+        let padding = u32::from(self.padding);
+        let len = match self.layer {
+            1 => (12 * self.bit_rate / self.sample_rate + padding) * 4,
+            _ => 144 * self.bit_rate / self.sample_rate + padding,
+        };
+        len as usize
+    }
+
+    /// Number of samples per channel in the frame.
+    pub(crate) fn samples(&self) -> u32 {
+        if self.layer == 1 {
+            384
+        } else {
+            1152
+        }
+    }
+
+    fn channels(&self) -> usize {
+        if self.mode == MODE_MONO {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// First subband in which both channels share the samples
+    /// (intensity stereo).
+    fn bound(&self, sblimit: usize) -> usize {
+        if self.mode == MODE_JOINT_STEREO {
+            (usize::from(self.mode_extension) + 1) * 4
+        } else {
+            sblimit
+        }
+        .min(sblimit)
+    }
+
+    /// The bit allocation table of a Layer II frame, selected by the
+    /// bit rate per channel and the sample rate.
+    fn layer_2_subbands(&self) -> &'static [(u32, usize)] {
+        let bit_rate = self.bit_rate / self.channels() as u32;
+        if bit_rate <= 48000 {
+            if self.sample_rate == 32000 {
+                &LOW_RATE_SUBBANDS
+            } else {
+                &LOW_RATE_SUBBANDS[..8]
+            }
+        } else if bit_rate <= 80000 || self.sample_rate == 48000 {
+            &HIGH_RATE_SUBBANDS[..27]
+        } else {
+            &HIGH_RATE_SUBBANDS
+        }
+    }
+}
+
+/// PCM samples of one decoded audio frame.
+pub struct AudioFrame {
+    sample_rate: u32,
+    channels: u8,
+    samples: Vec<i16>,
+}
+
+impl AudioFrame {
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// 1 for mono streams, 2 for stereo and dual channel streams.
+    pub fn channels(&self) -> u8 {
+        self.channels
+    }
+
+    /// Samples of all channels, interleaved. 384 per channel for
+    /// Layer I, 1152 for Layer II.
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    pub fn into_samples(self) -> Vec<i16> {
+        self.samples
+    }
+}
+
+/// Decodes an MPEG-1 audio elementary stream of Layer I or Layer II,
+/// e.g., a .mp2 file or an `MpegAudioStream` demultiplexed from a
+/// system stream. Data between frames is skipped.
+pub struct MpegAudioDecoder<R: Read> {
+    source: R,
+    // Data of the current frame after the header.
+    frame: Vec<u8>,
+    // Matrixing coefficients N[i][k] of the synthesis filter bank.
+    matrix: Vec<[f32; 32]>,
+    // The synthesis window D[i] including signs.
+    window: Vec<f32>,
+    // Synthesis filter bank state for each channel.
+    v: [[f32; 1024]; 2],
+}
+
+impl<R: Read> MpegAudioDecoder<R> {
+    pub fn new(source: R) -> MpegAudioDecoder<R> {
+        let matrix = (0..64)
+            .map(|i| {
+                std::array::from_fn(|k| {
+                    (((16 + i) * (2 * k + 1)) as f64 * std::f64::consts::PI / 64.0).cos() as f32
+                })
+            })
+            .collect();
+        let window = (0..512)
+            .map(|i| {
+                let d = SYNTHESIS_WINDOW[i.min(512 - i)] as f32 / 65536.0;
+                if (i / 64) % 2 == 1 {
+                    -d
+                } else {
+                    d
+                }
+            })
+            .collect();
+
+        MpegAudioDecoder {
+            source,
+            frame: vec![],
+            matrix,
+            window,
+            v: [[0.0; 1024]; 2],
+        }
+    }
+
+    /// Decodes the next frame. Returns `None` at the end of the
+    /// stream. A frame cut short by the end of the stream is dropped.
+    pub fn next_frame(&mut self) -> Result<Option<AudioFrame>, DecodeError> {
+        let header = match self.sync()? {
+            Some(b) => Header::parse(&b)?,
+            None => return Ok(None),
+        };
+        trace!("audio frame {:?}", header);
+
+        self.frame.resize(header.frame_len() - 4, 0);
+        if !read_fully(&mut self.source, &mut self.frame)? {
+            return Ok(None);
+        }
+
+        let mut bs = bitstream_io::BitReader::endian(&self.frame[..], bitstream_io::BigEndian);
+        if header.crc {
+            bs.skip(16)?;
+        }
+
+        let mut subbands = [[[0.0; 32]; 36]; 2];
+        let n = match header.layer {
+            1 => read_layer_1(&mut bs, &header, &mut subbands)?,
+            _ => read_layer_2(&mut bs, &header, &mut subbands)?,
+        };
+
+        let channels = header.channels();
+        let mut samples = vec![0; n * 32 * channels];
+        for t in 0..n {
+            for (ch, subbands) in subbands.iter().enumerate().take(channels) {
+                let pcm = self.synthesize(ch, &subbands[t]);
+                for (j, x) in pcm.iter().enumerate() {
+                    samples[(t * 32 + j) * channels + ch] =
+                        (x * 32768.0).round().clamp(-32768.0, 32767.0) as i16;
+                }
+            }
+        }
+
+        Ok(Some(AudioFrame {
+            sample_rate: header.sample_rate,
+            channels: channels as u8,
+            samples,
+        }))
+    }
+
+    /// Reads up to and including the next frame header. Returns `None`
+    /// at the end of the stream.
+    fn sync(&mut self) -> Result<Option<[u8; 4]>, DecodeError> {
+        let mut b = [0; 4];
+        if !read_fully(&mut self.source, &mut b)? {
+            return Ok(None);
+        }
+
+        let mut skipped = 0;
+        while !Header::is_header(&b) {
+            b.copy_within(1.., 0);
+            if !read_fully(&mut self.source, &mut b[3..])? {
+                return Ok(None);
+            }
+            skipped += 1;
+        }
+        if skipped > 0 {
+            trace!("skipped {} bytes before audio frame", skipped);
+        }
+        Ok(Some(b))
+    }
+
+    /// Turns one sample of each subband into 32 PCM samples, see
+    /// Figure A.2 of ISO 11172-3.
+    fn synthesize(&mut self, ch: usize, s: &[f32; 32]) -> [f32; 32] {
+        let v = &mut self.v[ch];
+        v.copy_within(0..960, 64);
+        for (v, n) in v.iter_mut().zip(&self.matrix) {
+            *v = n.iter().zip(s).map(|(n, s)| n * s).sum();
+        }
+
+        let mut pcm = [0.0; 32];
+        for (j, pcm) in pcm.iter_mut().enumerate() {
+            for i in 0..8 {
+                *pcm += self.window[i * 64 + j] * v[i * 128 + j];
+                *pcm += self.window[i * 64 + 32 + j] * v[i * 128 + 96 + j];
+            }
+        }
+        pcm
+    }
+}
+
+/// Like `read_exact`, but returns false if the stream ends first.
+pub(crate) fn read_fully<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<bool, DecodeError> {
+    match r.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn read_scalefactor(bs: &mut AudioBitReader) -> Result<f32, DecodeError> {
+    let index = bs.read::<u8>(6)?;
+    if index == 63 {
+        return Err(DecodeError::ForbiddenValue("scale factor"));
+    }
+    Ok(2f32.powf(1.0 - f32::from(index) / 3.0))
+}
+
+/// Maps a code of a quantizer with `steps` steps to the interval
+/// (-1, 1).
+fn dequantize(code: u32, steps: u32) -> f32 {
+    (2.0 * code as f32 - (steps - 1) as f32) / steps as f32
+}
+
+// The loops follow the syntax of the audio data in the standard.
+#[allow(clippy::needless_range_loop)]
+fn read_layer_1(
+    bs: &mut AudioBitReader,
+    header: &Header,
+    subbands: &mut Subbands,
+) -> Result<usize, DecodeError> {
+    let channels = header.channels();
+    let bound = header.bound(32);
+
+    // Bits per sample.
+    let mut bits = [[0; 32]; 2];
+    for sb in 0..32 {
+        for ch in 0..channels {
+            if ch == 1 && sb >= bound {
+                bits[1][sb] = bits[0][sb];
+                continue;
+            }
+            let allocation = bs.read::<u32>(4)?;
+            if allocation == 15 {
+                return Err(DecodeError::ForbiddenValue("bit allocation"));
+            }
+            bits[ch][sb] = if allocation == 0 { 0 } else { allocation + 1 };
+        }
+    }
+
+    let mut scalefactors = [[0.0; 32]; 2];
+    for sb in 0..32 {
+        for ch in 0..channels {
+            if bits[ch][sb] != 0 {
+                scalefactors[ch][sb] = read_scalefactor(bs)?;
+            }
+        }
+    }
+
+    for t in 0..12 {
+        for sb in 0..32 {
+            let mut code = 0;
+            for ch in 0..channels {
+                if bits[ch][sb] == 0 {
+                    continue;
+                }
+                if ch == 0 || sb < bound {
+                    code = bs.read(bits[ch][sb])?;
+                }
+                subbands[ch][t][sb] =
+                    dequantize(code, (1 << bits[ch][sb]) - 1) * scalefactors[ch][sb];
+            }
+        }
+    }
+    Ok(12)
+}
+
+fn read_layer_2(
+    bs: &mut AudioBitReader,
+    header: &Header,
+    subbands: &mut Subbands,
+) -> Result<usize, DecodeError> {
+    let channels = header.channels();
+    let table = header.layer_2_subbands();
+    let sblimit = table.len();
+    let bound = header.bound(sblimit);
+
+    // Quantization class, counting from 1, or 0 if there are no
+    // samples.
+    let mut classes = [[0; 32]; 2];
+    for (sb, &(bits, column)) in table.iter().enumerate() {
+        for ch in 0..channels {
+            if ch == 1 && sb >= bound {
+                classes[1][sb] = classes[0][sb];
+                continue;
+            }
+            let allocation = bs.read::<u32>(bits)? as usize;
+            classes[ch][sb] = usize::from(ALLOCATIONS[column][allocation]);
+        }
+    }
+
+    // Scale factor selection information: which of the three parts
+    // of the frame share a scale factor.
+    let mut scfsi = [[0; 32]; 2];
+    for sb in 0..sblimit {
+        for ch in 0..channels {
+            if classes[ch][sb] != 0 {
+                scfsi[ch][sb] = bs.read::<u8>(2)?;
+            }
+        }
+    }
+
+    let mut scalefactors = [[[0.0; 3]; 32]; 2];
+    for sb in 0..sblimit {
+        for ch in 0..channels {
+            if classes[ch][sb] == 0 {
+                continue;
+            }
+            let sf = &mut scalefactors[ch][sb];
+            match scfsi[ch][sb] {
+                0 => {
+                    sf[0] = read_scalefactor(bs)?;
+                    sf[1] = read_scalefactor(bs)?;
+                    sf[2] = read_scalefactor(bs)?;
+                }
+                1 => {
+                    sf[0] = read_scalefactor(bs)?;
+                    sf[1] = sf[0];
+                    sf[2] = read_scalefactor(bs)?;
+                }
+                2 => {
+                    sf[0] = read_scalefactor(bs)?;
+                    sf[1] = sf[0];
+                    sf[2] = sf[0];
+                }
+                _ => {
+                    sf[0] = read_scalefactor(bs)?;
+                    sf[1] = read_scalefactor(bs)?;
+                    sf[2] = sf[1];
+                }
+            }
+        }
+    }
+
+    // Twelve granules of three samples per subband.
+    for gr in 0..12 {
+        for sb in 0..sblimit {
+            let mut codes = [0; 3];
+            for ch in 0..channels {
+                if classes[ch][sb] == 0 {
+                    continue;
+                }
+                let (steps, grouped, bits) = QUANT_CLASSES[classes[ch][sb] - 1];
+                if ch == 0 || sb < bound {
+                    if grouped {
+                        let mut code = bs.read::<u32>(bits)?;
+                        for c in codes.iter_mut() {
+                            *c = code % steps;
+                            code /= steps;
+                        }
+                    } else {
+                        for c in codes.iter_mut() {
+                            *c = bs.read(bits)?;
+                        }
+                    }
+                }
+                for (i, c) in codes.iter().enumerate() {
+                    subbands[ch][gr * 3 + i][sb] =
+                        dequantize(*c, steps) * scalefactors[ch][sb][gr / 4];
+                }
+            }
+        }
+    }
+    Ok(36)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitstream_io::BitWrite;
+
+    /// Analysis filter bank of the encoder, Figure C.4 of ISO
+    /// 11172-3. The inverse of `MpegAudioDecoder::synthesize`.
+    struct Analysis {
+        x: [f32; 512],
+        window: Vec<f32>,
+    }
+
+    impl Analysis {
+        fn new() -> Analysis {
+            let decoder = MpegAudioDecoder::new(&[][..]);
+            Analysis {
+                x: [0.0; 512],
+                window: decoder.window.iter().map(|d| d / 32.0).collect(),
+            }
+        }
+
+        fn analyze(&mut self, input: &[f32]) -> [f32; 32] {
+            self.x.copy_within(0..480, 32);
+            for (x, input) in self.x[..32].iter_mut().zip(input.iter().rev()) {
+                *x = *input;
+            }
+            let y: Vec<f32> = (0..64)
+                .map(|i| {
+                    (0..8)
+                        .map(|j| self.window[i + 64 * j] * self.x[i + 64 * j])
+                        .sum()
+                })
+                .collect();
+            std::array::from_fn(|k| {
+                (0..64)
+                    .map(|i| {
+                        let m = ((2 * k + 1) as f64 * (i as f64 - 16.0) * std::f64::consts::PI
+                            / 64.0)
+                            .cos();
+                        m as f32 * y[i]
+                    })
+                    .sum()
+            })
+        }
+    }
+
+    /// Encodes mono 48 kHz audio at 384 kbit/s. Only the lowest 8
+    /// subbands are transmitted, with the finest quantizer.
+    fn encode(layer: u8, input: &[f32]) -> Vec<u8> {
+        // Samples per frame, bit_rate_index, allocation bits per
+        // subband, allocation of the finest quantizer and its steps.
+        let (len, bit_rate_index, allocation_bits, allocation, steps): (_, _, Vec<u32>, _, _) =
+            match layer {
+                1 => (384, 12, vec![4; 32], 14, 32767),
+                _ => (
+                    1152,
+                    14,
+                    HIGH_RATE_SUBBANDS[..27].iter().map(|s| s.0).collect(),
+                    15,
+                    65535,
+                ),
+            };
+        let code_bits = 32 - u32::leading_zeros(steps);
+        let scalefactor = |i: u32| 2f32.powf(1.0 - i as f32 / 3.0);
+
+        let mut analysis = Analysis::new();
+        let mut data = vec![];
+        for frame in input.chunks_exact(len) {
+            let subbands: Vec<[f32; 32]> = frame.chunks(32).map(|x| analysis.analyze(x)).collect();
+
+            let header = [
+                0xFF,
+                0xF9 | ((4 - layer) << 1),
+                bit_rate_index << 4 | 0x04,
+                0xC0,
+            ];
+            let mut bs = bitstream_io::BitWriter::endian(header.to_vec(), bitstream_io::BigEndian);
+            for (sb, bits) in allocation_bits.iter().enumerate() {
+                bs.write(*bits, if sb < 8 { allocation } else { 0 })
+                    .unwrap();
+            }
+            if layer == 2 {
+                // One scale factor for all three parts.
+                for _ in 0..8 {
+                    bs.write(2, 2).unwrap();
+                }
+            }
+            let mut scalefactors = [0; 8];
+            for (sb, sf) in scalefactors.iter_mut().enumerate() {
+                let peak = subbands.iter().map(|s| s[sb].abs()).fold(0.0, f32::max);
+                *sf = (0..63).rev().find(|i| scalefactor(*i) >= peak).unwrap_or(0);
+                bs.write(6, *sf).unwrap();
+            }
+            // Layer II transmits three consecutive samples of a
+            // subband at a time, Layer I one.
+            let group = if layer == 1 { 1 } else { 3 };
+            for samples in subbands.chunks(group) {
+                for sb in 0..8 {
+                    for s in samples {
+                        let x = s[sb] / scalefactor(scalefactors[sb]);
+                        let code = ((x * steps as f32 + (steps - 1) as f32) / 2.0)
+                            .round()
+                            .clamp(0.0, (steps - 1) as f32);
+                        bs.write(code_bits, code as u32).unwrap();
+                    }
+                }
+            }
+
+            bs.byte_align().unwrap();
+            let mut frame = bs.into_writer();
+            frame.resize(Header::parse(&header).unwrap().frame_len(), 0);
+            data.extend(frame);
+        }
+        data
+    }
+
+    fn round_trip(layer: u8) {
+        let input: Vec<f32> = (0..1152 * 4)
+            .map(|i| 0.5 * (i as f32 * 1000.0 * 2.0 * std::f32::consts::PI / 48000.0).sin())
+            .collect();
+        let data = encode(layer, &input);
+
+        let mut decoder = MpegAudioDecoder::new(&data[..]);
+        let mut output = vec![];
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            assert_eq!((frame.sample_rate(), frame.channels()), (48000, 1));
+            output.extend(frame.samples().iter().map(|s| *s as f32 / 32768.0));
+        }
+        assert_eq!(output.len(), input.len());
+
+        // The filter banks delay the signal by 481 samples. Skip the
+        // onset of the tone, which has energy in subbands that are not
+        // transmitted.
+        let input = &input[512..];
+        let output = &output[512 + 481..];
+        let signal: f32 = input.iter().zip(output).map(|(x, _)| x * x).sum();
+        let noise: f32 = input
+            .iter()
+            .zip(output)
+            .map(|(x, y)| (x - y) * (x - y))
+            .sum();
+        let snr = 10.0 * (signal / noise).log10();
+        assert!(snr > 60.0, "SNR {} dB", snr);
+    }
+
+    #[test]
+    fn test_layer_1_round_trip() {
+        round_trip(1);
+    }
+
+    #[test]
+    fn test_layer_2_round_trip() {
+        round_trip(2);
+    }
+
+    #[test]
+    fn test_sync() {
+        let silence = encode(2, &[0.0; 1152]);
+        assert_eq!(silence.len(), 1152);
+
+        // Garbage before the frame is skipped, a truncated frame at the
+        // end is dropped.
+        let mut data = vec![0x00, 0x12, 0x34];
+        data.extend(&silence);
+        data.extend(&silence[..100]);
+        let mut decoder = MpegAudioDecoder::new(&data[..]);
+        let frame = decoder.next_frame().unwrap().unwrap();
+        assert!(frame.samples().iter().all(|s| *s == 0));
+        assert!(decoder.next_frame().unwrap().is_none());
+
+        // Layer III.
+        let mut data = silence.clone();
+        data[1] = 0xFB;
+        let mut decoder = MpegAudioDecoder::new(&data[..]);
+        assert!(matches!(
+            decoder.next_frame(),
+            Err(DecodeError::Unsupported(_))
+        ));
+    }
+}
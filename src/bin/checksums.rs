@@ -0,0 +1,64 @@
+use mpeg_ox::{crc32, md5, DecodeError, FrameWriter, I420Writer, MpegDecoder};
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, Write};
+
+extern crate env_logger;
+
+gflags::define! {
+    /// Input file. Use - to read from stdin.
+    -f, --file: &std::path::Path
+}
+
+gflags::define! {
+    /// Also write the decoded frames as raw I420 to this file.
+    -o, --output: &std::path::Path
+}
+
+// Print the MD5 and CRC-32 of every decoded frame in raw I420, one
+// line per frame in display order.
+
+fn checksums<R: Read + Seek>(decoder: &mut MpegDecoder<R>) -> Result<(), DecodeError> {
+    let mut dump = if OUTPUT.is_present() {
+        Some(I420Writer::new(BufWriter::new(File::create(OUTPUT.flag)?)))
+    } else {
+        None
+    };
+    let mut out = std::io::stdout().lock();
+
+    for (n, frame) in decoder.enumerate() {
+        let frame = frame?;
+        let mut data = I420Writer::new(vec![]);
+        data.write_frame(&frame)?;
+        let data = data.into_inner();
+
+        let digest: String = md5(&data).iter().map(|b| format!("{:02x}", b)).collect();
+        writeln!(out, "{} {} {:08x} {}", n, digest, crc32(&data), frame.pts())?;
+
+        if let Some(dump) = &mut dump {
+            dump.write_frame(&frame)?;
+        }
+    }
+
+    if let Some(dump) = dump {
+        dump.into_inner().flush()?;
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), DecodeError> {
+    env_logger::init();
+    let _args = gflags::parse();
+
+    if FILE.is_present() {
+        let path = FILE.flag;
+        if path.as_os_str() == "-" {
+            checksums(&mut MpegDecoder::from_stream(std::io::stdin().lock())?)?;
+        } else {
+            checksums(&mut MpegDecoder::new(path.to_str().unwrap())?)?;
+        }
+    } else {
+        gflags::print_help_and_exit(0);
+    }
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use mpeg_ox::MpegEncoder;
+
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Read};
+
+extern crate env_logger;
+
+gflags::define! {
+    /// Raw I420 input, e.g., as written by checksums --output. Use - to read from stdin.
+    -f, --file: &std::path::Path
+}
+
+gflags::define! {
+    /// Output MPEG-1 video elementary stream.
+    -o, --output: &std::path::Path
+}
+
+gflags::define! {
+    /// Width of the pictures.
+    --width: u16 = 0
+}
+
+gflags::define! {
+    /// Height of the pictures.
+    --height: u16 = 0
+}
+
+gflags::define! {
+    /// Frames per second, one of the MPEG-1 rates.
+    --rate: f32 = 25.0
+}
+
+gflags::define! {
+    /// Quantizer scale from 1 (best) to 31.
+    -q, --quantizer: u8 = 4
+}
+
+gflags::define! {
+    /// Pictures per group of pictures.
+    --group: u32 = 12
+}
+
+// Encode raw I420 pictures into an intra-only MPEG-1 video stream.
+
+fn encode<R: Read>(mut input: R) -> std::io::Result<()> {
+    let output = BufWriter::new(File::create(OUTPUT.flag)?);
+    let (width, height) = (WIDTH.flag, HEIGHT.flag);
+    let mut encoder = MpegEncoder::new(output, width, height, RATE.flag)?;
+    encoder.set_quantizer_scale(QUANTIZER.flag)?;
+    encoder.set_group_size(GROUP.flag)?;
+
+    let (width, height) = (usize::from(width), usize::from(height));
+    let mut picture = vec![0; width * height + 2 * width.div_ceil(2) * height.div_ceil(2)];
+    loop {
+        match input.read_exact(&mut picture) {
+            Ok(()) => encoder.encode_i420(&picture)?,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    encoder.finish()?;
+    Ok(())
+}
+
+fn main() -> std::io::Result<()> {
+    env_logger::init();
+    let _args = gflags::parse();
+
+    if FILE.is_present() && OUTPUT.is_present() {
+        let path = FILE.flag;
+        if path.as_os_str() == "-" {
+            encode(std::io::stdin().lock())?;
+        } else {
+            encode(std::io::BufReader::new(File::open(path)?))?;
+        }
+    } else {
+        gflags::print_help_and_exit(0);
+    }
+    Ok(())
+}
@@ -1,11 +1,11 @@
-use mpeg_ox::{MpegDecoder, PersistFrames};
+use mpeg_ox::{DecodeError, MpegDecoder, PersistFrames};
 
-use gflags;
-use std::io;
+use std::io::{Read, Seek};
 
 extern crate env_logger;
 
 gflags::define! {
+    /// Input file. Use - to read from stdin.
     -f, --file: &std::path::Path
 }
 
@@ -15,16 +15,22 @@ gflags::define! {
 
 // Extract key frames from a video source.
 
-fn main() -> io::Result<()> {
+fn extract<R: Read + Seek>(decoder: &mut MpegDecoder<R>) -> Result<(), DecodeError> {
+    decoder.stats = STATS.is_present();
+    decoder.parse_mpeg(&mut PersistFrames::new())
+}
+
+fn main() -> Result<(), DecodeError> {
     env_logger::init();
     let _args = gflags::parse();
 
     if FILE.is_present() {
         let path = FILE.flag;
-        let mut decoder = MpegDecoder::new(path.to_str().unwrap())?;
-
-        decoder.stats = STATS.is_present();
-        decoder.parse_mpeg(&mut PersistFrames::new())?;
+        if path.as_os_str() == "-" {
+            extract(&mut MpegDecoder::from_stream(std::io::stdin().lock())?)?;
+        } else {
+            extract(&mut MpegDecoder::new(path.to_str().unwrap())?)?;
+        }
     } else {
         gflags::print_help_and_exit(0);
     }
@@ -0,0 +1,55 @@
+use mpeg_ox::{DecodeError, MpegMuxer};
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+extern crate env_logger;
+
+gflags::define! {
+    /// Video elementary stream, e.g., a .m1v file.
+    -f, --file: &std::path::Path
+}
+
+gflags::define! {
+    /// Optional MPEG audio elementary stream, e.g., a .mp2 file.
+    -a, --audio: &std::path::Path
+}
+
+gflags::define! {
+    /// Output system stream.
+    -o, --output: &std::path::Path
+}
+
+gflags::define! {
+    /// Size of the packs in bytes.
+    --packet_size: usize = 2048
+}
+
+gflags::define! {
+    /// Mux rate in bytes/s.
+    --mux_rate: u32 = 1250000
+}
+
+// Multiplex elementary streams into an ISO 11172-1 system stream.
+
+fn main() -> Result<(), DecodeError> {
+    env_logger::init();
+    let _args = gflags::parse();
+
+    if !FILE.is_present() || !OUTPUT.is_present() {
+        gflags::print_help_and_exit(0);
+    }
+
+    let mut muxer = MpegMuxer::new(BufWriter::new(File::create(OUTPUT.flag)?));
+    muxer.set_packet_size(PACKET_SIZE.flag)?;
+    muxer.set_mux_rate(MUX_RATE.flag)?;
+
+    let video = File::open(FILE.flag)?;
+    if AUDIO.is_present() {
+        muxer.mux(video, File::open(AUDIO.flag)?)?;
+    } else {
+        muxer.mux_video(video)?;
+    }
+    muxer.into_inner().flush()?;
+    Ok(())
+}
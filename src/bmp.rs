@@ -8,8 +8,8 @@ struct BmpFileHeader {
 impl BmpFileHeader {
     fn serialize(&self) -> [u8; 14] {
         let mut ret = [0; 14];
-        ret[0] = 'B' as u8;
-        ret[1] = 'M' as u8;
+        ret[0] = b'B';
+        ret[1] = b'M';
         ret[2..6].copy_from_slice(&self.file_size.to_le_bytes());
         ret[10..14].copy_from_slice(&self.offset.to_le_bytes());
         ret
@@ -44,7 +44,7 @@ impl BmpImage {
         self,
         width: i32,
         height: i32,
-        pixels: &Vec<u8>,
+        pixels: &[u8],
         writer: &mut W,
     ) -> std::io::Result<()> {
         let bytes_per_pixel = 3i32;
@@ -65,12 +65,13 @@ impl BmpImage {
         bmp_file_header.offset = u32::try_from(bmp_file_header_size + bmp_dib_header_size).unwrap();
         writer.write_all(&bmp_file_header.serialize())?;
 
-        let mut bmp_core_header = BmpCoreHeader::default();
-        bmp_core_header.size = 12;
-        bmp_core_header.width = width.try_into().unwrap();
-        bmp_core_header.height = height.try_into().unwrap();
-        bmp_core_header.bits_per_pixel = (bytes_per_pixel * 8).try_into().unwrap();
-        bmp_core_header.nr_planes = 1;
+        let bmp_core_header = BmpCoreHeader {
+            size: 12,
+            width: width.try_into().unwrap(),
+            height: height.try_into().unwrap(),
+            nr_planes: 1,
+            bits_per_pixel: (bytes_per_pixel * 8).try_into().unwrap(),
+        };
         writer.write_all(&bmp_core_header.serialize())?;
 
         let padding = vec![0u8; padding_bytes.try_into().unwrap()];
@@ -101,9 +102,10 @@ mod test {
         let mut f = OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open("my.bmp")
             .unwrap();
 
-        bmp.write(3, 1, &pixels, &mut f);
+        bmp.write(3, 1, &pixels, &mut f).unwrap();
     }
 }
@@ -0,0 +1,493 @@
+use super::writer::FrameWriter;
+use super::{Frame, FRAME_RATES, FRAME_TYPE_I, GROUP_OF_PICTURES_START_VALUE};
+use super::{MACROBLOCK_INTRA, PICTURE_START_VALUE, SEQUENCE_END_VALUE};
+use super::{SEQUENCE_HEADER_START_VALUE, VIDEO_DCT_COEFF, VIDEO_DCT_SIZE_CHROMINANCE};
+use super::{VIDEO_DCT_SIZE_LUMINANCE, VIDEO_INTRA_QUANT_MATRIX};
+use super::{VIDEO_MACROBLOCK_ADDRESS_INCREMENT, VIDEO_MACROBLOCK_TYPE_INTRA, VIDEO_ZIG_ZAG};
+use bitstream_io::{BigEndian, BitWrite, BitWriter};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// Slice start codes number the macroblock rows from 1 to 175.
+const MAX_MB_ROWS: usize = 0xAF;
+
+/// A variable length code as value and length in bits.
+type Vlc = (u32, u32);
+
+/// Codes of a variable length code table in the format read by
+/// `read_huffman`, as pairs of value and code.
+fn vlc_codes<S: Copy>(table: &[(i16, S)]) -> Vec<(S, Vlc)> {
+    let mut codes = vec![];
+    let mut pending = vec![(0, (0, 0))];
+    while let Some((state, (code, len))) = pending.pop() {
+        for bit in 0..2 {
+            let (next, value) = table[(state + bit) as usize];
+            let vlc = ((code << 1) | bit as u32, len + 1);
+            match next {
+                0 => codes.push((value, vlc)),
+                n if n > 0 => pending.push((n, vlc)),
+                _ => {}
+            }
+        }
+    }
+    codes
+}
+
+fn vlc_code<S: Copy + PartialEq>(table: &[(i16, S)], value: S) -> Vlc {
+    vlc_codes(table)
+        .into_iter()
+        .find(|&(v, _)| v == value)
+        .map(|(_, vlc)| vlc)
+        .unwrap()
+}
+
+fn invalid_input(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, what)
+}
+
+/// The samples of a picture to encode. Samples outside the displayed
+/// area repeat the last row or column.
+struct Source<'a> {
+    // Data and stride of Y, Cb and Cr.
+    planes: [(&'a [u8], usize); 3],
+    width: usize,
+    height: usize,
+}
+
+impl Source<'_> {
+    fn block(&self, component: usize, x: usize, y: usize) -> [f64; 64] {
+        let (data, stride) = self.planes[component];
+        let (width, height) = if component == 0 {
+            (self.width, self.height)
+        } else {
+            (self.width.div_ceil(2), self.height.div_ceil(2))
+        };
+        std::array::from_fn(|i| {
+            let row = (y + i / 8).min(height - 1);
+            let col = (x + i % 8).min(width - 1);
+            f64::from(data[row * stride + col])
+        })
+    }
+}
+
+/// Encodes pictures into an MPEG-1 video elementary stream, e.g., to
+/// produce test streams. All pictures are I-pictures coded with a
+/// fixed quantizer scale and the default quantizer matrix. Each group
+/// of pictures is preceded by a sequence header.
+pub struct MpegEncoder<W: Write> {
+    bs: BitWriter<W, BigEndian>,
+    width: u16,
+    height: u16,
+    frame_rate_code: u8,
+    quantizer_scale: u8,
+    group_size: u32,
+    // Number of pictures encoded so far.
+    pictures: u64,
+    // Rows of the DCT basis: cos((2x + 1)uπ/16) scaled by C(u)/2.
+    dct: [[f64; 8]; 8],
+    dc_size_luma: Vec<Vlc>,
+    dc_size_chroma: Vec<Vlc>,
+    // By run << 8 | level like VIDEO_DCT_COEFF.
+    coefficients: HashMap<u16, Vlc>,
+    escape: Vlc,
+    address_increment: Vlc,
+    macroblock_type: Vlc,
+}
+
+impl<W: Write> MpegEncoder<W> {
+    /// Encodes pictures of `width` by `height` samples. `frame_rate`
+    /// must be one of the rates MPEG-1 defines, e.g., 25.0 or 29.97.
+    pub fn new(writer: W, width: u16, height: u16, frame_rate: f32) -> io::Result<Self> {
+        if width == 0 || width > 4095 || height == 0 || usize::from(height) > 16 * MAX_MB_ROWS {
+            return Err(invalid_input("picture size"));
+        }
+        let frame_rate_code = FRAME_RATES
+            .iter()
+            .skip(1)
+            .position(|&rate| (rate - frame_rate).abs() < 0.01)
+            .ok_or_else(|| invalid_input("frame rate"))?;
+
+        let dct = std::array::from_fn(|u| {
+            let c = if u == 0 { 0.5f64.sqrt() } else { 1.0 };
+            std::array::from_fn(|x| {
+                c / 2.0 * ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI / 16.0).cos()
+            })
+        });
+        let dc_size = |table| (0..=8).map(|size| vlc_code(table, size)).collect();
+
+        Ok(MpegEncoder {
+            bs: BitWriter::endian(writer, BigEndian),
+            width,
+            height,
+            frame_rate_code: frame_rate_code as u8 + 1,
+            quantizer_scale: 4,
+            group_size: 12,
+            pictures: 0,
+            dct,
+            dc_size_luma: dc_size(&VIDEO_DCT_SIZE_LUMINANCE),
+            dc_size_chroma: dc_size(&VIDEO_DCT_SIZE_CHROMINANCE),
+            coefficients: vlc_codes(&VIDEO_DCT_COEFF).into_iter().collect(),
+            escape: vlc_code(&VIDEO_DCT_COEFF, 0xffff),
+            address_increment: vlc_code(&VIDEO_MACROBLOCK_ADDRESS_INCREMENT, 1),
+            macroblock_type: vlc_code(&VIDEO_MACROBLOCK_TYPE_INTRA, MACROBLOCK_INTRA),
+        })
+    }
+
+    /// Quantizer scale from 1 to 31 for all following pictures. Lower
+    /// values give better quality and larger streams. Defaults to 4.
+    pub fn set_quantizer_scale(&mut self, quantizer_scale: u8) -> io::Result<()> {
+        if !(1..=31).contains(&quantizer_scale) {
+            return Err(invalid_input("quantizer scale"));
+        }
+        self.quantizer_scale = quantizer_scale;
+        Ok(())
+    }
+
+    /// Number of pictures per group of pictures. Defaults to 12.
+    pub fn set_group_size(&mut self, group_size: u32) -> io::Result<()> {
+        if group_size == 0 {
+            return Err(invalid_input("group size"));
+        }
+        self.group_size = group_size;
+        Ok(())
+    }
+
+    /// Encodes a picture in I420 layout as written by `I420Writer`:
+    /// the Y plane followed by the Cb and Cr planes, each subsampled
+    /// by two with odd sizes rounded up.
+    pub fn encode_i420(&mut self, data: &[u8]) -> io::Result<()> {
+        let (width, height) = (usize::from(self.width), usize::from(self.height));
+        let (cw, ch) = (width.div_ceil(2), height.div_ceil(2));
+        if data.len() != width * height + 2 * cw * ch {
+            return Err(invalid_input("I420 picture size"));
+        }
+        let (y, chroma) = data.split_at(width * height);
+        let (cb, cr) = chroma.split_at(cw * ch);
+        self.picture(&Source {
+            planes: [(y, width), (cb, cw), (cr, cw)],
+            width,
+            height,
+        })
+    }
+
+    /// Ends the sequence and returns the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.start_code(SEQUENCE_END_VALUE)?;
+        let mut writer = self.bs.into_writer();
+        writer.flush()?;
+        Ok(writer)
+    }
+
+    fn start_code(&mut self, value: u8) -> io::Result<()> {
+        self.bs.byte_align()?;
+        self.bs.write(32, 0x100 | u32::from(value))
+    }
+
+    fn vlc(&mut self, (code, len): Vlc) -> io::Result<()> {
+        self.bs.write(len, code)
+    }
+
+    fn sequence_header(&mut self) -> io::Result<()> {
+        self.start_code(SEQUENCE_HEADER_START_VALUE)?;
+        self.bs.write(12, self.width)?;
+        self.bs.write(12, self.height)?;
+        // Square pixels.
+        self.bs.write(4, 1)?;
+        self.bs.write(4, self.frame_rate_code)?;
+        // Variable bit rate.
+        self.bs.write(18, 0x3FFFFu32)?;
+        self.bs.write_bit(true)?;
+        // VBV buffer size in units of 16 kbit.
+        self.bs.write(10, 112)?;
+        // Not constrained, default quantizer matrices.
+        self.bs.write(3, 0)
+    }
+
+    fn group_of_pictures(&mut self) -> io::Result<()> {
+        self.start_code(GROUP_OF_PICTURES_START_VALUE)?;
+        let rate = FRAME_RATES[usize::from(self.frame_rate_code)].round() as u64;
+        let secs = self.pictures / rate;
+        // No drop frame flag.
+        self.bs.write(1, 0)?;
+        self.bs.write(5, (secs / 3600 % 24) as u32)?;
+        self.bs.write(6, (secs / 60 % 60) as u32)?;
+        self.bs.write_bit(true)?;
+        self.bs.write(6, (secs % 60) as u32)?;
+        self.bs.write(6, (self.pictures % rate) as u32)?;
+        // Closed group, no broken link.
+        self.bs.write(2, 0b10)
+    }
+
+    fn picture(&mut self, source: &Source) -> io::Result<()> {
+        let index = self.pictures % u64::from(self.group_size);
+        if index == 0 {
+            self.sequence_header()?;
+            self.group_of_pictures()?;
+        }
+
+        self.start_code(PICTURE_START_VALUE)?;
+        self.bs.write(10, (index % 1024) as u32)?;
+        self.bs.write(3, FRAME_TYPE_I)?;
+        // No VBV delay.
+        self.bs.write(16, 0xFFFF)?;
+        // No extra information.
+        self.bs.write_bit(false)?;
+
+        // One slice per row of macroblocks.
+        let mb_width = usize::from(self.width).div_ceil(16);
+        let mb_height = usize::from(self.height).div_ceil(16);
+        for row in 0..mb_height {
+            self.start_code(row as u8 + 1)?;
+            self.bs.write(5, self.quantizer_scale)?;
+            self.bs.write_bit(false)?;
+
+            let mut dc_predictor = [128; 3];
+            for col in 0..mb_width {
+                self.vlc(self.address_increment)?;
+                self.vlc(self.macroblock_type)?;
+                for i in 0..6 {
+                    let block = match i {
+                        0..=3 => source.block(0, col * 16 + i % 2 * 8, row * 16 + i / 2 * 8),
+                        _ => source.block(i - 3, col * 8, row * 8),
+                    };
+                    let plane_index = if i < 4 { 0 } else { i - 3 };
+                    self.intra_block(&block, i, &mut dc_predictor[plane_index])?;
+                }
+            }
+        }
+
+        self.pictures += 1;
+        Ok(())
+    }
+
+    fn fdct(&self, block: &[f64; 64]) -> [f64; 64] {
+        std::array::from_fn(|i| {
+            let (v, u) = (i / 8, i % 8);
+            let mut sum = 0.0;
+            for y in 0..8 {
+                for x in 0..8 {
+                    sum += self.dct[v][y] * self.dct[u][x] * block[y * 8 + x];
+                }
+            }
+            sum
+        })
+    }
+
+    /// Codes block `i` of an intra macroblock.
+    fn intra_block(
+        &mut self,
+        block: &[f64; 64],
+        i: usize,
+        dc_predictor: &mut i32,
+    ) -> io::Result<()> {
+        let coefficients = self.fdct(block);
+
+        // The DC coefficient is coded as the difference to the one of
+        // the previous block of the same component.
+        let dc = ((coefficients[0] / 8.0).round() as i32).clamp(0, 255);
+        let diff = dc - *dc_predictor;
+        *dc_predictor = dc;
+        let size = 32 - diff.unsigned_abs().leading_zeros();
+        self.vlc(if i < 4 {
+            self.dc_size_luma[size as usize]
+        } else {
+            self.dc_size_chroma[size as usize]
+        })?;
+        if size > 0 {
+            let coded = if diff > 0 {
+                diff
+            } else {
+                diff + (1 << size) - 1
+            };
+            self.bs.write(size, coded as u32)?;
+        }
+
+        let mut run = 0;
+        for &n in &VIDEO_ZIG_ZAG[1..] {
+            let n = usize::from(n);
+            let scale = f64::from(self.quantizer_scale) * f64::from(VIDEO_INTRA_QUANT_MATRIX[n]);
+            let level = ((8.0 * coefficients[n] / scale).round() as i32).clamp(-255, 255);
+            if level == 0 {
+                run += 1;
+                continue;
+            }
+            self.coefficient(run, level)?;
+            run = 0;
+        }
+
+        // End of block.
+        self.bs.write(2, 0b10)
+    }
+
+    fn coefficient(&mut self, run: u32, level: i32) -> io::Result<()> {
+        let key = (run << 8) as u16 | level.unsigned_abs().min(0xFF) as u16;
+        match self.coefficients.get(&key) {
+            // "1" is run 0, level 1 only as the first coefficient of
+            // non-intra blocks. It is "11" elsewhere.
+            Some(_) if key == 0x0001 => self.bs.write(2, 0b11)?,
+            Some(&vlc) => self.vlc(vlc)?,
+            None => {
+                self.vlc(self.escape)?;
+                self.bs.write(6, run)?;
+                if level.abs() < 128 {
+                    self.bs.write(8, level as u8)?;
+                } else if level > 0 {
+                    self.bs.write(16, level as u16)?;
+                } else {
+                    self.bs.write(16, (level + 256) as u16 | 0x8000)?;
+                }
+                return Ok(());
+            }
+        }
+        self.bs.write_bit(level < 0)
+    }
+}
+
+impl<W: Write> FrameWriter for MpegEncoder<W> {
+    /// Encodes a decoded frame, e.g., to transcode a stream. The frame
+    /// must have the size the encoder was created with.
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        if (frame.width(), frame.height()) != (usize::from(self.width), usize::from(self.height)) {
+            return Err(invalid_input("frame size"));
+        }
+        let (y, cb, cr) = (frame.y(), frame.cb(), frame.cr());
+        let source = Source {
+            planes: [
+                (y.data(), y.stride()),
+                (cb.data(), cb.stride()),
+                (cr.data(), cr.stride()),
+            ],
+            width: frame.width(),
+            height: frame.height(),
+        };
+        self.picture(&source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{decode, i420, psnr};
+    use crate::{MpegDecoder, PictureType};
+    use std::io::Cursor;
+
+    // A picture in I420 with smooth gradients and some texture that
+    // moves with `t`.
+    fn picture(width: usize, height: usize, t: usize) -> Vec<u8> {
+        let sample = |x: usize, y: usize, scale: f64, phase: f64| {
+            let wave = ((x + t) as f64 / scale + phase).sin() * (y as f64 / (scale + 2.0)).cos();
+            (128.0 + 2.0 * x as f64 - y as f64 + 60.0 * wave).clamp(16.0, 235.0) as u8
+        };
+        let (cw, ch) = (width.div_ceil(2), height.div_ceil(2));
+        let mut data = vec![];
+        data.extend((0..width * height).map(|i| sample(i % width, i / width, 3.0, 0.0)));
+        data.extend((0..cw * ch).map(|i| sample(i % cw, i / cw, 5.0, 1.0)));
+        data.extend((0..cw * ch).map(|i| sample(i % cw, i / cw, 7.0, 2.0)));
+        data
+    }
+
+    fn encode(quantizer_scale: u8) -> Vec<u8> {
+        let mut encoder = MpegEncoder::new(vec![], 50, 34, 25.0).unwrap();
+        encoder.set_quantizer_scale(quantizer_scale).unwrap();
+        for t in 0..3 {
+            encoder.encode_i420(&picture(50, 34, t)).unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_vlc_codes() {
+        assert_eq!(vlc_code(&VIDEO_MACROBLOCK_ADDRESS_INCREMENT, 1), (0b1, 1));
+        assert_eq!(
+            vlc_code(&VIDEO_MACROBLOCK_ADDRESS_INCREMENT, 33),
+            (0b000_0001_1000, 11)
+        );
+        assert_eq!(vlc_code(&VIDEO_DCT_SIZE_LUMINANCE, 0), (0b100, 3));
+        assert_eq!(vlc_code(&VIDEO_DCT_SIZE_CHROMINANCE, 8), (0b1111_1110, 8));
+        assert_eq!(vlc_code(&VIDEO_DCT_COEFF, 0xffff), (0b00_0001, 6));
+        assert_eq!(vlc_code(&VIDEO_DCT_COEFF, 0x0102), (0b00_0110, 6));
+        assert_eq!(
+            vlc_code(&VIDEO_MACROBLOCK_TYPE_INTRA, MACROBLOCK_INTRA),
+            (0b1, 1)
+        );
+        // Run and level pairs besides the escape.
+        assert_eq!(vlc_codes(&VIDEO_DCT_COEFF).len(), 112);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let frames = decode(&encode(2));
+        assert_eq!(frames.len(), 3);
+        for (t, frame) in frames.iter().enumerate() {
+            assert_eq!((frame.width(), frame.height()), (50, 34));
+            assert_eq!((frame.coded_width(), frame.coded_height()), (64, 48));
+            assert_eq!(frame.picture_type(), PictureType::I);
+            assert_eq!(frame.pts(), 3600 * t as u64);
+
+            let (decoded, original) = (i420(frame), picture(50, 34, t));
+            let (y, chroma) = (50 * 34, 25 * 17);
+            assert!(psnr(&decoded[..y], &original[..y]) > 40.0);
+            assert!(psnr(&decoded[y..y + chroma], &original[y..y + chroma]) > 40.0);
+            assert!(psnr(&decoded[y + chroma..], &original[y + chroma..]) > 40.0);
+        }
+    }
+
+    #[test]
+    fn test_quantizer_scale() {
+        let (fine, coarse) = (encode(2), encode(31));
+        assert!(coarse.len() < fine.len() / 2);
+
+        let frame = &decode(&coarse)[0];
+        let quality = psnr(&i420(frame), &picture(50, 34, 0));
+        assert!(quality > 25.0 && quality < 40.0, "{}", quality);
+    }
+
+    #[test]
+    fn test_group_size() {
+        let mut encoder = MpegEncoder::new(Cursor::new(vec![]), 16, 16, 29.97).unwrap();
+        encoder.set_group_size(2).unwrap();
+        for t in 0..5 {
+            encoder.encode_i420(&picture(16, 16, t)).unwrap();
+        }
+        let stream = encoder.finish().unwrap().into_inner();
+
+        let mut decoder = MpegDecoder::from_stream(Cursor::new(stream)).unwrap();
+        let index = decoder.build_index().unwrap();
+        // Every picture is an I-picture, every second one starts a group.
+        let time_codes: Vec<u64> = index.entries().iter().map(|e| e.time_code()).collect();
+        assert_eq!(time_codes, [0, 3003, 6006, 9009, 12012]);
+        let starts: Vec<bool> = index.entries().iter().map(|e| e.starts_group()).collect();
+        assert_eq!(starts, [true, false, true, false, true]);
+        assert_eq!(decoder.count(), 5);
+    }
+
+    #[test]
+    fn test_transcode() {
+        let frames = decode(&encode(2));
+        let mut encoder = MpegEncoder::new(vec![], 50, 34, 25.0).unwrap();
+        encoder.set_quantizer_scale(2).unwrap();
+        for frame in &frames {
+            encoder.write_frame(frame).unwrap();
+        }
+        let transcoded = decode(&encoder.finish().unwrap());
+        assert_eq!(transcoded.len(), frames.len());
+        for (a, b) in frames.iter().zip(&transcoded) {
+            assert!(psnr(&i420(a), &i420(b)) > 40.0);
+        }
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        assert!(MpegEncoder::new(vec![], 16, 16, 26.0).is_err());
+        assert!(MpegEncoder::new(vec![], 0, 16, 25.0).is_err());
+        assert!(MpegEncoder::new(vec![], 16, 2801, 25.0).is_err());
+
+        let mut encoder = MpegEncoder::new(vec![], 16, 16, 25.0).unwrap();
+        assert!(encoder.encode_i420(&[0; 16 * 16]).is_err());
+        assert!(encoder.set_quantizer_scale(0).is_err());
+        assert!(encoder.set_quantizer_scale(32).is_err());
+        assert!(encoder.set_group_size(0).is_err());
+        let frame = &decode(&encode(2))[0];
+        assert!(encoder.write_frame(frame).is_err());
+    }
+}
@@ -0,0 +1,82 @@
+use std::fmt;
+use std::io;
+
+/// Errors reported while demultiplexing or decoding a stream.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// A bit sequence that does not match any entry of the
+    /// variable length code table being read.
+    InvalidVlc,
+    /// A block has more than 64 coefficients.
+    CoefficientOverflow,
+    /// A macroblock address beyond the end of the picture.
+    InvalidMacroblockAddress(i32),
+    /// Found a start code where a different one was expected.
+    UnexpectedStartCode(u8),
+    /// A system layer packet ends before its header or payload.
+    TruncatedPacket,
+    /// The stream uses a feature this decoder does not implement.
+    Unsupported(&'static str),
+    /// A field holds a value that the standard forbids.
+    ForbiddenValue(&'static str),
+    Io(io::Error),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::InvalidVlc => write!(f, "invalid variable length code"),
+            DecodeError::CoefficientOverflow => write!(f, "more than 64 coefficients in block"),
+            DecodeError::InvalidMacroblockAddress(addr) => {
+                write!(f, "macroblock address {} out of range", addr)
+            }
+            DecodeError::UnexpectedStartCode(code) => {
+                write!(f, "unexpected start code 0x{:02x}", code)
+            }
+            DecodeError::TruncatedPacket => write!(f, "truncated packet"),
+            DecodeError::Unsupported(what) => write!(f, "unsupported: {}", what),
+            DecodeError::ForbiddenValue(field) => write!(f, "forbidden value of {}", field),
+            DecodeError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(e: io::Error) -> Self {
+        // Errors of the demultiplexer reach the decoder wrapped in an
+        // io::Error. Unwrap them again.
+        if !e.get_ref().is_some_and(|inner| inner.is::<DecodeError>()) {
+            return DecodeError::Io(e);
+        }
+        let kind = e.kind();
+        match e.into_inner().map(|inner| inner.downcast::<DecodeError>()) {
+            Some(Ok(inner)) => *inner,
+            _ => DecodeError::Io(kind.into()),
+        }
+    }
+}
+
+impl From<DecodeError> for io::Error {
+    fn from(e: DecodeError) -> Self {
+        match e {
+            DecodeError::Io(e) => e,
+            _ => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+impl DecodeError {
+    /// True if the input ended in the middle of a read.
+    pub fn is_eof(&self) -> bool {
+        matches!(self, DecodeError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof)
+    }
+}
@@ -6,6 +6,13 @@
 // This module is an alternative IDCT implementation. It is only used
 // during tests to compare results against another IDCT variant.
 #![cfg(test)]
+// Keep the code close to the C original.
+#![allow(
+    clippy::identity_op,
+    clippy::erasing_op,
+    clippy::assign_op_pattern,
+    clippy::needless_range_loop
+)]
 
 fn pmul_1(y: &mut i32, z: &mut i32) {
     // int y2, y3;          \
@@ -127,7 +134,7 @@ pub fn idct_23002_2(P: &mut [i32; 64]) {
      *  - can be moved outside the transform, executed for non-zero coeffs only,
      *    or absorbed in quantization step. */
     for i in 0..64 {
-        block[i] = SCALE[i] * i32::from(P[i]);
+        block[i] = SCALE[i] * P[i];
     }
     block[0] += 1 << 12; /* bias DC for proper rounding */
 
@@ -142,6 +149,6 @@ pub fn idct_23002_2(P: &mut [i32; 64]) {
 
     /* right-shift and store the results: */
     for i in 0..64 {
-        P[i] = (block[i] >> 13).try_into().unwrap();
+        P[i] = block[i] >> 13;
     }
 }
@@ -0,0 +1,249 @@
+use super::{DecodeError, TIMESTAMP_WRAP};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Identifies a serialized index, followed by the format version.
+const MAGIC: [u8; 4] = *b"MOXI";
+const VERSION: u8 = 2;
+
+/// Size of a serialized entry: offset, time code, a flag for the PTS,
+/// the PTS, the temporal reference and a flag for the group start.
+const ENTRY_LEN: usize = 8 + 8 + 1 + 8 + 2 + 1;
+
+/// An I-picture in the index, i.e., a key frame that decoding can
+/// start from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub(crate) offset: u64,
+    pub(crate) time_code: u64,
+    pub(crate) pts: Option<u64>,
+    pub(crate) temporal_reference: u16,
+    pub(crate) group_start: bool,
+}
+
+impl IndexEntry {
+    /// Byte offset of the source to start decoding from. In video
+    /// elementary streams the start code of the group if the picture
+    /// starts one, of the picture otherwise. In system streams the
+    /// start of a pack before either.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Time code of the picture in units of the 90 kHz system clock,
+    /// the time code of its group plus its temporal reference.
+    pub fn time_code(&self) -> u64 {
+        self.time_code
+    }
+
+    /// PTS of the picture, if the system layer has one.
+    pub fn pts(&self) -> Option<u64> {
+        self.pts
+    }
+
+    /// Position of the picture in display order within its group.
+    pub fn temporal_reference(&self) -> u16 {
+        self.temporal_reference
+    }
+
+    /// Whether the picture is the first one of its group of pictures.
+    pub fn starts_group(&self) -> bool {
+        self.group_start
+    }
+}
+
+/// Byte offsets of all I-pictures of a stream. Built once by
+/// `MpegDecoder::build_index` and handed to `MpegDecoder::set_index`,
+/// it spares seeks from searching the source. The index can be stored
+/// next to the stream and loaded again.
+///
+/// Decoding from an I-picture within a group drops the B-pictures that
+/// follow it in coding order but come before it in display order. They
+/// also refer to the reference picture before it, like those at the
+/// start of an open group. In system streams, an I-picture is only
+/// indexed if a pack starts between it and the one before.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SeekIndex {
+    entries: Vec<IndexEntry>,
+}
+
+impl SeekIndex {
+    pub(crate) fn push(&mut self, entry: IndexEntry) {
+        self.entries.push(entry);
+    }
+
+    /// The I-pictures in stream order.
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    /// Time of a picture relative to the first one in units of the 90
+    /// kHz system clock.
+    pub(crate) fn time(&self, entry: &IndexEntry) -> u64 {
+        self.entries
+            .first()
+            .map_or(0, |first| group_time(first, entry))
+    }
+
+    /// The last picture presented at or before `time`, relative to
+    /// the first one. The first picture if all come later.
+    pub(crate) fn find(&self, time: u64) -> Option<&IndexEntry> {
+        let n = self.entries.partition_point(|e| self.time(e) <= time);
+        self.entries.get(n.saturating_sub(1))
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<(), DecodeError> {
+        w.write_all(&MAGIC)?;
+        w.write_all(&[VERSION])?;
+        w.write_all(&(self.entries.len() as u32).to_be_bytes())?;
+        for entry in &self.entries {
+            let mut buf = [0; ENTRY_LEN];
+            buf[0..8].copy_from_slice(&entry.offset.to_be_bytes());
+            buf[8..16].copy_from_slice(&entry.time_code.to_be_bytes());
+            if let Some(pts) = entry.pts {
+                buf[16] = 1;
+                buf[17..25].copy_from_slice(&pts.to_be_bytes());
+            }
+            buf[25..27].copy_from_slice(&entry.temporal_reference.to_be_bytes());
+            buf[27] = u8::from(entry.group_start);
+            w.write_all(&buf)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> Result<SeekIndex, DecodeError> {
+        let mut header = [0; 9];
+        r.read_exact(&mut header)?;
+        if header[..4] != MAGIC {
+            return Err(DecodeError::ForbiddenValue("seek index magic"));
+        }
+        if header[4] != VERSION {
+            return Err(DecodeError::Unsupported("seek index version"));
+        }
+
+        let n = u32::from_be_bytes(header[5..9].try_into().unwrap());
+        let mut entries = vec![];
+        for _ in 0..n {
+            let mut buf = [0; ENTRY_LEN];
+            r.read_exact(&mut buf)?;
+            let pts = match buf[16] {
+                0 => None,
+                1 => Some(u64::from_be_bytes(buf[17..25].try_into().unwrap())),
+                _ => return Err(DecodeError::ForbiddenValue("seek index entry")),
+            };
+            let group_start = match buf[27] {
+                0 => false,
+                1 => true,
+                _ => return Err(DecodeError::ForbiddenValue("seek index entry")),
+            };
+            entries.push(IndexEntry {
+                offset: u64::from_be_bytes(buf[0..8].try_into().unwrap()),
+                time_code: u64::from_be_bytes(buf[8..16].try_into().unwrap()),
+                pts,
+                temporal_reference: u16::from_be_bytes(buf[25..27].try_into().unwrap()),
+                group_start,
+            });
+        }
+        Ok(SeekIndex { entries })
+    }
+
+    /// Writes the index to a sidecar file at `path`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), DecodeError> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_to(&mut w)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Reads an index written by `save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<SeekIndex, DecodeError> {
+        let f = OpenOptions::new().read(true).open(path)?;
+        Self::read_from(&mut BufReader::new(f))
+    }
+}
+
+/// Time of the picture `entry` relative to the picture `first`.
+/// Pictures are timed by their PTS if both have one, by their time
+/// code otherwise. PTS wrap around, so a later picture may have a
+/// smaller one.
+pub(crate) fn group_time(first: &IndexEntry, entry: &IndexEntry) -> u64 {
+    match (first.pts, entry.pts) {
+        (Some(first), Some(pts)) => pts.wrapping_sub(first) % TIMESTAMP_WRAP,
+        _ => entry.time_code.saturating_sub(first.time_code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index() -> SeekIndex {
+        let mut index = SeekIndex::default();
+        for i in 0..4 {
+            index.push(IndexEntry {
+                offset: 1000 * i,
+                time_code: 3600 * i,
+                pts: (i > 0).then_some(9000 + 3600 * i),
+                temporal_reference: i as u16 % 2,
+                group_start: i % 2 == 0,
+            });
+        }
+        index
+    }
+
+    #[test]
+    fn test_serialize() {
+        let index = index();
+        let mut buf = vec![];
+        index.write_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), 9 + 4 * ENTRY_LEN);
+        assert_eq!(SeekIndex::read_from(&mut &buf[..]).unwrap(), index);
+
+        let mut invalid = buf.clone();
+        invalid[0] = b'X';
+        assert!(SeekIndex::read_from(&mut &invalid[..]).is_err());
+        let mut invalid = buf.clone();
+        invalid[4] = 1;
+        assert!(SeekIndex::read_from(&mut &invalid[..]).is_err());
+        let mut invalid = buf.clone();
+        invalid[9 + ENTRY_LEN - 1] = 2;
+        assert!(SeekIndex::read_from(&mut &invalid[..]).is_err());
+        assert!(SeekIndex::read_from(&mut &buf[..buf.len() - 1])
+            .unwrap_err()
+            .is_eof());
+
+        let path = std::env::temp_dir().join("mpeg-ox-test-serialize.idx");
+        index.save(&path).unwrap();
+        assert_eq!(SeekIndex::load(&path).unwrap(), index);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_find() {
+        // The first picture has no PTS, all are timed by time code.
+        let index = index();
+        assert_eq!(index.find(0).unwrap().offset(), 0);
+        assert_eq!(index.find(7199).unwrap().offset(), 1000);
+        assert_eq!(index.find(7200).unwrap().offset(), 2000);
+        assert_eq!(index.find(u64::MAX).unwrap().offset(), 3000);
+        assert_eq!(SeekIndex::default().find(0), None);
+    }
+
+    #[test]
+    fn test_pts_wrap() {
+        let mut index = SeekIndex::default();
+        for pts in [TIMESTAMP_WRAP - 3600, 0, 3600] {
+            index.push(IndexEntry {
+                offset: pts,
+                time_code: 0,
+                pts: Some(pts),
+                temporal_reference: 0,
+                group_start: true,
+            });
+        }
+        let times: Vec<_> = index.entries().iter().map(|e| index.time(e)).collect();
+        assert_eq!(times, [0, 3600, 7200]);
+        assert_eq!(index.find(3600).unwrap().offset(), 0);
+    }
+}
@@ -9,39 +9,149 @@
 
 // https://github.com/phoboslab/pl_mpeg
 
+mod audio;
 mod bmp;
+mod checksum;
+mod encoder;
+mod error;
 mod idct_23002_2;
+mod index;
+mod lookahead;
+mod mux;
 mod stream;
+#[cfg(test)]
+mod test_util;
+mod writer;
+
+pub use audio::{AudioFrame, MpegAudioDecoder};
+// For the checksums tool and the regression test, not part of the API.
+#[doc(hidden)]
+pub use checksum::{crc32, md5};
+pub use encoder::MpegEncoder;
+pub use error::DecodeError;
+use index::group_time;
+pub use index::{IndexEntry, SeekIndex};
+pub use lookahead::Lookahead;
+pub use mux::MpegMuxer;
+pub use stream::{AudioPacket, MpegAudioStream, MpegVideoStream};
+use stream::{KeyFrame, VideoTimestamps};
+pub use writer::{Component, FrameWriter, I420Writer, PgmWriter, PngWriter, PpmWriter};
+pub use writer::{WriteFrames, Y4mWriter};
 
 use bitstream_io::BitRead;
+use std::collections::VecDeque;
 use std::fmt::Write as FmtWrite;
 use std::fs::OpenOptions;
 use std::io;
-use std::io::Write;
 use std::io::{BufReader, Read, Seek, SeekFrom};
-use std::time::Instant;
-use stream::MpegVideoStream;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 extern crate log;
-use log::trace;
+use log::{trace, warn};
 
 const PROFILE: bool = false;
 
+const ISO_11172_END_CODE: u8 = 0xB9;
 const PACK_START_CODE: u8 = 0xBA;
 const SYSTEM_HEADER_START_CODE: u8 = 0xBB;
 const PACKET_START_CODE: u8 = 0xBC;
-#[allow(dead_code)]
+// Stream ids of the system header that refer to all audio or all
+// video streams.
+const ALL_AUDIO_STREAMS: u8 = 0xB8;
+const ALL_VIDEO_STREAMS: u8 = 0xB9;
+const PADDING_STREAM: u8 = 0xBE;
+const PRIVATE_STREAM_2: u8 = 0xBF;
+// Stuffing bytes allowed in a packet header.
+const MAX_STUFFING_BYTES: usize = 16;
 const AUDIO_STREAM_0_START_CODE: u8 = 0xC0;
+const AUDIO_STREAM_31_START_CODE: u8 = 0xDF;
 const VIDEO_STREAM_0_START_CODE: u8 = 0xE0;
+const VIDEO_STREAM_15_START_CODE: u8 = 0xEF;
 
 const GROUP_OF_PICTURES_START_VALUE: u8 = 0xB8;
 const SEQUENCE_HEADER_START_VALUE: u8 = 0xB3;
 const PICTURE_START_VALUE: u8 = 0x00;
 const START_EXTENSION: u8 = 0xB5;
 const START_USER_DATA: u8 = 0xB2;
+// extension_start_code_identifier of an MPEG-2 sequence_extension.
+const SEQUENCE_EXTENSION_ID: u8 = 0b0001;
+const SEQUENCE_END_VALUE: u8 = 0xB7;
 
 const FRAME_TYPE_I: u8 = 0b001;
 const FRAME_TYPE_P: u8 = 0b010;
+const FRAME_TYPE_B: u8 = 0b011;
+const FRAME_TYPE_D: u8 = 0b100;
+
+// Frames per second by the frame rate code of the sequence header.
+// Code 0 is forbidden.
+const FRAME_RATES: [f32; 9] = [
+    0.0,
+    24000. / 1001.,
+    24.0,
+    25.0,
+    29.97,
+    30.0,
+    50.0,
+    59.94,
+    60.0,
+];
+
+/// Layout of the pixels produced by `Frame::convert_into`. All
+/// formats have 8 bits per component. Alpha is always opaque.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb24,
+    Bgr24,
+    Rgba32,
+    Bgra32,
+    Argb32,
+    /// Luma only.
+    Gray8,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb24 | PixelFormat::Bgr24 => 3,
+            PixelFormat::Rgba32 | PixelFormat::Bgra32 | PixelFormat::Argb32 => 4,
+            PixelFormat::Gray8 => 1,
+        }
+    }
+
+    /// Positions of red, green and blue and of alpha within a pixel.
+    fn layout(self) -> ([usize; 3], Option<usize>) {
+        match self {
+            PixelFormat::Rgb24 => ([0, 1, 2], None),
+            PixelFormat::Bgr24 => ([2, 1, 0], None),
+            PixelFormat::Rgba32 => ([0, 1, 2], Some(3)),
+            PixelFormat::Bgra32 => ([2, 1, 0], Some(3)),
+            PixelFormat::Argb32 => ([1, 2, 3], Some(0)),
+            PixelFormat::Gray8 => ([0, 0, 0], None),
+        }
+    }
+}
+
+/// Coding type of a picture.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PictureType {
+    /// Intra-coded, decodable on its own.
+    I,
+    /// Predicted from the previous I- or P-picture.
+    P,
+    /// Predicted from the I- or P-pictures before and after it.
+    B,
+    /// DC coefficients only, for fast preview.
+    D,
+}
+
+// Macroblock type flags as decoded from the VIDEO_MACROBLOCK_TYPE_* tables.
+const MACROBLOCK_QUANT: u8 = 0b0_0001;
+const MACROBLOCK_MOTION_FORWARD: u8 = 0b0_0010;
+const MACROBLOCK_MOTION_BACKWARD: u8 = 0b0_0100;
+const MACROBLOCK_PATTERN: u8 = 0b0_1000;
+const MACROBLOCK_INTRA: u8 = 0b1_0000;
 
 #[rustfmt::skip]
 const VIDEO_INTRA_QUANT_MATRIX: [u8; 64] = [
@@ -55,6 +165,8 @@ const VIDEO_INTRA_QUANT_MATRIX: [u8; 64] = [
 	27, 29, 35, 38, 46, 56, 69, 83
 ];
 
+const VIDEO_NON_INTRA_QUANT_MATRIX: [u8; 64] = [16; 64];
+
 #[rustfmt::skip]
 const VIDEO_PREMULTIPLIER_MATRIX: [i32; 64] = [
 	  32, 44, 42, 38, 32, 25, 17,  9,
@@ -67,20 +179,51 @@ const VIDEO_PREMULTIPLIER_MATRIX: [i32; 64] = [
 	  9, 12, 12, 10,  9,  7,  5,  2
 ];
 
-const _: [u8; 8] = [0; std::mem::size_of::<SequenceHeader>()];
-
-struct SequenceHeader {
+#[derive(Clone)]
+pub struct SequenceHeader {
     raw: [u8; 8],
+    // Quantizer matrices in natural (row-major) order. Either the
+    // defaults or the ones loaded from the sequence header.
+    intra_quant_matrix: [u8; 64],
+    non_intra_quant_matrix: [u8; 64],
 }
 
 impl SequenceHeader {
-    fn new<F: std::io::Read>(f: &mut F) -> SequenceHeader {
+    fn new<F: std::io::Read>(f: &mut F) -> Result<SequenceHeader, DecodeError> {
         let mut buf: [u8; 8] = [0; 8];
-        f.read(&mut buf).expect("");
-        SequenceHeader {
-            //            raw: [data[0], data[1], data[2], data[3]],
-            raw: buf,
+        f.read_exact(&mut buf)?;
+
+        // A zero size would leave the picture without macroblocks.
+        if buf[0] == 0 && (buf[1] & 0xF0) == 0 {
+            return Err(DecodeError::ForbiddenValue("horizontal_size"));
+        }
+        if (buf[1] & 0x0F) == 0 && buf[2] == 0 {
+            return Err(DecodeError::ForbiddenValue("vertical_size"));
+        }
+
+        let mut intra_quant_matrix = VIDEO_INTRA_QUANT_MATRIX;
+        let mut non_intra_quant_matrix = VIDEO_NON_INTRA_QUANT_MATRIX;
+
+        // load_intra_quantizer_matrix is the second to last bit of
+        // the fixed part. The optional intra matrix is thus not byte
+        // aligned. Whatever follows ends on a byte boundary again.
+        let last = [buf[7]];
+        let mut bs =
+            bitstream_io::BitReader::endian((&last[..]).chain(&mut *f), bitstream_io::BigEndian);
+        bs.skip(6)?;
+
+        if bs.read_bit()? {
+            read_quant_matrix(&mut bs, &mut intra_quant_matrix)?;
         }
+        if bs.read_bit()? {
+            read_quant_matrix(&mut bs, &mut non_intra_quant_matrix)?;
+        }
+
+        Ok(SequenceHeader {
+            raw: buf,
+            intra_quant_matrix,
+            non_intra_quant_matrix,
+        })
     }
 
     fn hsize(&self) -> u16 {
@@ -92,36 +235,33 @@ impl SequenceHeader {
     fn aspect_ratio_str(&self) -> &str {
         let idx = (self.raw[3] & 0xF0) >> 4;
         let table: [&str; 5] = ["", "1:1", "4:3", "16:9", "2.21:1"];
-        table[idx as usize]
+        table.get(idx as usize).copied().unwrap_or("")
     }
     fn frame_rate(&self) -> f32 {
         let idx = self.raw[3] & 0x0F;
-        let table: [f32; 9] = [
-            0.0,
-            24000. / 1001.,
-            24.0,
-            25.0,
-            29.97,
-            30.0,
-            50.0,
-            59.94,
-            60.0,
-        ];
-        table[idx as usize]
+        FRAME_RATES.get(idx as usize).copied().unwrap_or(0.0)
+    }
+}
+
+/// Reads a quantizer matrix. The 64 values are transmitted in
+/// zig-zag order.
+fn read_quant_matrix<R: BitRead>(bs: &mut R, matrix: &mut [u8; 64]) -> io::Result<()> {
+    for i in VIDEO_ZIG_ZAG {
+        matrix[i as usize] = bs.read::<u8>(8)?;
     }
+    Ok(())
 }
 
+#[derive(Clone, Copy)]
 struct GroupOfPictures {
     raw: [u8; 4],
 }
 
 impl GroupOfPictures {
-    fn new<F: std::io::Read>(f: &mut F) -> Option<GroupOfPictures> {
+    fn new<F: std::io::Read>(f: &mut F) -> io::Result<GroupOfPictures> {
         let mut buf: [u8; 4] = [0; 4];
-        match f.read_exact(&mut buf) {
-            Ok(()) => Some(GroupOfPictures { raw: buf }),
-            Err(_) => None,
-        }
+        f.read_exact(&mut buf)?;
+        Ok(GroupOfPictures { raw: buf })
     }
 
     fn hour(&self) -> u8 {
@@ -129,7 +269,6 @@ impl GroupOfPictures {
     }
 
     fn min(&self) -> u8 {
-        println!("{:x?}", self.raw);
         ((self.raw[0] & 0b00000011) << 4) + ((self.raw[1] & 0b11110000) >> 4)
     }
 
@@ -140,18 +279,45 @@ impl GroupOfPictures {
     fn frame(&self) -> u8 {
         ((self.raw[2] & 0b00011111) << 1) + ((self.raw[3] & 0b10000000) >> 7)
     }
+
+    /// The time code of the picture with `temporal_reference` in
+    /// the group, in units of the 90 kHz system clock.
+    fn time_code(&self, temporal_reference: u16, frame_rate: f32) -> u64 {
+        let secs =
+            (u64::from(self.hour()) * 60 + u64::from(self.min())) * 60 + u64::from(self.sec());
+        let pictures = if frame_rate > 0.0 {
+            (f64::from(self.frame()) + f64::from(temporal_reference)) * 90000.0
+                / f64::from(frame_rate)
+        } else {
+            0.0
+        };
+        secs * 90000 + pictures.round() as u64
+    }
 }
 
 struct PictureHeader {
-    raw: [u8; 4],
+    raw: [u8; 5],
 }
 
 impl PictureHeader {
-    fn new<F: std::io::Read>(f: &mut F) -> Option<PictureHeader> {
-        let mut buf: [u8; 4] = [0; 4];
-        match f.read_exact(&mut buf) {
-            Ok(()) => Some(PictureHeader { raw: buf }),
-            Err(_) => None,
+    fn new<F: std::io::Read>(f: &mut F) -> io::Result<PictureHeader> {
+        let mut buf: [u8; 5] = [0; 5];
+        f.read_exact(&mut buf[0..4])?;
+        let mut hdr = PictureHeader { raw: buf };
+        // The motion vector parameters spill over into the fifth
+        // byte. I-pictures do not have them and the fifth byte may
+        // already be part of the next start code.
+        if hdr.frame_type() == FRAME_TYPE_P || hdr.frame_type() == FRAME_TYPE_B {
+            f.read_exact(&mut hdr.raw[4..5])?;
+        }
+        Ok(hdr)
+    }
+
+    /// The first two bytes of a picture header, enough for the
+    /// temporal reference and the picture type.
+    fn from_prefix(prefix: [u8; 2]) -> PictureHeader {
+        PictureHeader {
+            raw: [prefix[0], prefix[1], 0, 0, 0],
         }
     }
 
@@ -162,6 +328,26 @@ impl PictureHeader {
     fn frame_type(&self) -> u8 {
         (self.raw[1] & 0b00111000) >> 3
     }
+
+    /// Only valid for P- and B-pictures.
+    fn full_pel_forward_vector(&self) -> bool {
+        (self.raw[3] & 0b0000_0100) != 0
+    }
+
+    /// Only valid for P- and B-pictures.
+    fn forward_f_code(&self) -> u8 {
+        ((self.raw[3] & 0b0000_0011) << 1) + ((self.raw[4] & 0b1000_0000) >> 7)
+    }
+
+    /// Only valid for B-pictures.
+    fn full_pel_backward_vector(&self) -> bool {
+        (self.raw[4] & 0b0100_0000) != 0
+    }
+
+    /// Only valid for B-pictures.
+    fn backward_f_code(&self) -> u8 {
+        (self.raw[4] & 0b0011_1000) >> 3
+    }
 }
 
 fn is_slice_start_code(b: &[u8; 4]) -> bool {
@@ -171,18 +357,11 @@ fn is_slice_start_code(b: &[u8; 4]) -> bool {
 type MyBitReader<'a, T> =
     bitstream_io::BitReader<&'a mut std::io::BufReader<T>, bitstream_io::BigEndian>;
 
-fn parse_macroblock_type<T: std::io::Read>(bs: &mut MyBitReader<T>) -> Option<u8> {
-    if bs.read::<u8>(1).unwrap() == 1 {
-        // I-frame/picture
-        return Some(0b1_0000);
-    }
-
-    if bs.read::<u8>(1).unwrap() == 1 {
-        // I-frame/picture with quantizer
-        return Some(0b1_0001);
-    }
-
-    unimplemented!("unsupported macro block type!");
+fn parse_macroblock_type<T: std::io::Read>(
+    table: &[(i16, u8)],
+    bs: &mut MyBitReader<T>,
+) -> Result<u8, DecodeError> {
+    read_huffman(table, bs)
 }
 
 // fn vlc_decode<U: std::io::Read>(table: std::collections::HashMap<T, S>, bs: &mut MyBitReader<U>) -> Option<S> {
@@ -389,7 +568,150 @@ const VIDEO_MACROBLOCK_ADDRESS_INCREMENT: [(i16, i16); 80] = [
 	(       0,   23), (       0,   22),  //  39: 0000 0100 01x
 ];
 
-fn read_huffman<T, S>(table: &[(i16, S)], stream: &mut MyBitReader<T>) -> Option<S>
+#[rustfmt::skip]
+const VIDEO_MACROBLOCK_TYPE_INTRA: [(i16, u8); 4] = [
+	(  1 << 1,    0), (       0, MACROBLOCK_INTRA),  //   0: x
+	(      -1,    0), (       0, MACROBLOCK_QUANT | MACROBLOCK_INTRA),  //   1: 0x
+];
+
+// D-pictures only contain intra-coded macroblocks without quantizer.
+#[rustfmt::skip]
+const VIDEO_MACROBLOCK_TYPE_D: [(i16, u8); 2] = [
+	(      -1,    0), (       0, MACROBLOCK_INTRA),  //   0: x
+];
+
+#[rustfmt::skip]
+const VIDEO_MACROBLOCK_TYPE_PREDICTIVE: [(i16, u8); 14] = [
+	(  1 << 1,    0), (       0, MACROBLOCK_MOTION_FORWARD | MACROBLOCK_PATTERN),  //   0: x
+	(  2 << 1,    0), (       0, MACROBLOCK_PATTERN),  //   1: 0x
+	(  3 << 1,    0), (       0, MACROBLOCK_MOTION_FORWARD),  //   2: 00x
+	(  4 << 1,    0), (  5 << 1,    0),  //   3: 000x
+	(  6 << 1,    0), (       0, MACROBLOCK_QUANT | MACROBLOCK_PATTERN),  //   4: 0000x
+	(       0, MACROBLOCK_QUANT | MACROBLOCK_MOTION_FORWARD | MACROBLOCK_PATTERN), (       0, MACROBLOCK_INTRA),  //   5: 0001x
+	(      -1,    0), (       0, MACROBLOCK_QUANT | MACROBLOCK_INTRA),  //   6: 0000 0x
+];
+
+#[rustfmt::skip]
+const VIDEO_MACROBLOCK_TYPE_B: [(i16, u8); 22] = [
+	(  1 << 1,    0), (  2 << 1,    0),  //   0: x
+	(  3 << 1,    0), (  4 << 1,    0),  //   1: 0x
+	(       0, MACROBLOCK_MOTION_FORWARD | MACROBLOCK_MOTION_BACKWARD), (       0, MACROBLOCK_MOTION_FORWARD | MACROBLOCK_MOTION_BACKWARD | MACROBLOCK_PATTERN),  //   2: 1x
+	(  5 << 1,    0), (  6 << 1,    0),  //   3: 00x
+	(       0, MACROBLOCK_MOTION_BACKWARD), (       0, MACROBLOCK_MOTION_BACKWARD | MACROBLOCK_PATTERN),  //   4: 01x
+	(  7 << 1,    0), (  8 << 1,    0),  //   5: 000x
+	(       0, MACROBLOCK_MOTION_FORWARD), (       0, MACROBLOCK_MOTION_FORWARD | MACROBLOCK_PATTERN),  //   6: 001x
+	(  9 << 1,    0), ( 10 << 1,    0),  //   7: 0000x
+	(       0, MACROBLOCK_QUANT | MACROBLOCK_MOTION_FORWARD | MACROBLOCK_MOTION_BACKWARD | MACROBLOCK_PATTERN), (       0, MACROBLOCK_INTRA),  //   8: 0001x
+	(      -1,    0), (       0, MACROBLOCK_QUANT | MACROBLOCK_INTRA),  //   9: 0000 0x
+	(       0, MACROBLOCK_QUANT | MACROBLOCK_MOTION_BACKWARD | MACROBLOCK_PATTERN), (       0, MACROBLOCK_QUANT | MACROBLOCK_MOTION_FORWARD | MACROBLOCK_PATTERN),  //  10: 0000 1x
+];
+
+#[rustfmt::skip]
+const VIDEO_CODE_BLOCK_PATTERN: [(i16, u8); 126] = [
+	(  1 << 1,    0), (  2 << 1,    0),  //   0: x
+	(  3 << 1,    0), (  4 << 1,    0),  //   1: 0x
+	(  5 << 1,    0), (  6 << 1,    0),  //   2: 1x
+	(  7 << 1,    0), (  8 << 1,    0),  //   3: 00x
+	(  9 << 1,    0), ( 10 << 1,    0),  //   4: 01x
+	( 11 << 1,    0), ( 12 << 1,    0),  //   5: 10x
+	( 13 << 1,    0), (       0,   60),  //   6: 11x
+	( 14 << 1,    0), ( 15 << 1,    0),  //   7: 000x
+	( 16 << 1,    0), ( 17 << 1,    0),  //   8: 001x
+	( 18 << 1,    0), ( 19 << 1,    0),  //   9: 010x
+	( 20 << 1,    0), ( 21 << 1,    0),  //  10: 011x
+	( 22 << 1,    0), ( 23 << 1,    0),  //  11: 100x
+	(       0,   32), (       0,   16),  //  12: 101x
+	(       0,    8), (       0,    4),  //  13: 110x
+	( 24 << 1,    0), ( 25 << 1,    0),  //  14: 0000x
+	( 26 << 1,    0), ( 27 << 1,    0),  //  15: 0001x
+	( 28 << 1,    0), ( 29 << 1,    0),  //  16: 0010x
+	( 30 << 1,    0), ( 31 << 1,    0),  //  17: 0011x
+	(       0,   62), (       0,    2),  //  18: 0100x
+	(       0,   61), (       0,    1),  //  19: 0101x
+	(       0,   56), (       0,   52),  //  20: 0110x
+	(       0,   44), (       0,   28),  //  21: 0111x
+	(       0,   40), (       0,   20),  //  22: 1000x
+	(       0,   48), (       0,   12),  //  23: 1001x
+	( 32 << 1,    0), ( 33 << 1,    0),  //  24: 0000 0x
+	( 34 << 1,    0), ( 35 << 1,    0),  //  25: 0000 1x
+	( 36 << 1,    0), ( 37 << 1,    0),  //  26: 0001 0x
+	( 38 << 1,    0), ( 39 << 1,    0),  //  27: 0001 1x
+	( 40 << 1,    0), ( 41 << 1,    0),  //  28: 0010 0x
+	( 42 << 1,    0), ( 43 << 1,    0),  //  29: 0010 1x
+	(       0,   63), (       0,    3),  //  30: 0011 0x
+	(       0,   36), (       0,   24),  //  31: 0011 1x
+	( 44 << 1,    0), ( 45 << 1,    0),  //  32: 0000 00x
+	( 46 << 1,    0), ( 47 << 1,    0),  //  33: 0000 01x
+	( 48 << 1,    0), ( 49 << 1,    0),  //  34: 0000 10x
+	( 50 << 1,    0), ( 51 << 1,    0),  //  35: 0000 11x
+	( 52 << 1,    0), ( 53 << 1,    0),  //  36: 0001 00x
+	( 54 << 1,    0), ( 55 << 1,    0),  //  37: 0001 01x
+	( 56 << 1,    0), ( 57 << 1,    0),  //  38: 0001 10x
+	( 58 << 1,    0), ( 59 << 1,    0),  //  39: 0001 11x
+	(       0,   34), (       0,   18),  //  40: 0010 00x
+	(       0,   10), (       0,    6),  //  41: 0010 01x
+	(       0,   33), (       0,   17),  //  42: 0010 10x
+	(       0,    9), (       0,    5),  //  43: 0010 11x
+	(      -1,    0), ( 60 << 1,    0),  //  44: 0000 000x
+	( 61 << 1,    0), ( 62 << 1,    0),  //  45: 0000 001x
+	(       0,   58), (       0,   54),  //  46: 0000 010x
+	(       0,   46), (       0,   30),  //  47: 0000 011x
+	(       0,   57), (       0,   53),  //  48: 0000 100x
+	(       0,   45), (       0,   29),  //  49: 0000 101x
+	(       0,   38), (       0,   26),  //  50: 0000 110x
+	(       0,   37), (       0,   25),  //  51: 0000 111x
+	(       0,   43), (       0,   23),  //  52: 0001 000x
+	(       0,   51), (       0,   15),  //  53: 0001 001x
+	(       0,   42), (       0,   22),  //  54: 0001 010x
+	(       0,   50), (       0,   14),  //  55: 0001 011x
+	(       0,   41), (       0,   21),  //  56: 0001 100x
+	(       0,   49), (       0,   13),  //  57: 0001 101x
+	(       0,   35), (       0,   19),  //  58: 0001 110x
+	(       0,   11), (       0,    7),  //  59: 0001 111x
+	(       0,   39), (       0,   27),  //  60: 0000 0001x
+	(       0,   59), (       0,   55),  //  61: 0000 0010x
+	(       0,   47), (       0,   31),  //  62: 0000 0011x
+];
+
+#[rustfmt::skip]
+const VIDEO_MOTION: [(i16, i16); 68] = [
+	(  1 << 1,    0), (       0,    0),  //   0: x
+	(  2 << 1,    0), (  3 << 1,    0),  //   1: 0x
+	(  4 << 1,    0), (  5 << 1,    0),  //   2: 00x
+	(       0,    1), (       0,   -1),  //   3: 01x
+	(  6 << 1,    0), (  7 << 1,    0),  //   4: 000x
+	(       0,    2), (       0,   -2),  //   5: 001x
+	(  8 << 1,    0), (  9 << 1,    0),  //   6: 0000x
+	(       0,    3), (       0,   -3),  //   7: 0001x
+	( 10 << 1,    0), ( 11 << 1,    0),  //   8: 0000 0x
+	( 12 << 1,    0), ( 13 << 1,    0),  //   9: 0000 1x
+	(      -1,    0), ( 14 << 1,    0),  //  10: 0000 00x
+	( 15 << 1,    0), ( 16 << 1,    0),  //  11: 0000 01x
+	( 17 << 1,    0), ( 18 << 1,    0),  //  12: 0000 10x
+	(       0,    4), (       0,   -4),  //  13: 0000 11x
+	(      -1,    0), ( 19 << 1,    0),  //  14: 0000 001x
+	( 20 << 1,    0), ( 21 << 1,    0),  //  15: 0000 010x
+	(       0,    7), (       0,   -7),  //  16: 0000 011x
+	(       0,    6), (       0,   -6),  //  17: 0000 100x
+	(       0,    5), (       0,   -5),  //  18: 0000 101x
+	( 22 << 1,    0), ( 23 << 1,    0),  //  19: 0000 0011x
+	( 24 << 1,    0), ( 25 << 1,    0),  //  20: 0000 0100x
+	( 26 << 1,    0), ( 27 << 1,    0),  //  21: 0000 0101x
+	( 28 << 1,    0), ( 29 << 1,    0),  //  22: 0000 0011 0x
+	( 30 << 1,    0), ( 31 << 1,    0),  //  23: 0000 0011 1x
+	( 32 << 1,    0), ( 33 << 1,    0),  //  24: 0000 0100 0x
+	(       0,   10), (       0,  -10),  //  25: 0000 0100 1x
+	(       0,    9), (       0,   -9),  //  26: 0000 0101 0x
+	(       0,    8), (       0,   -8),  //  27: 0000 0101 1x
+	(       0,   16), (       0,  -16),  //  28: 0000 0011 00x
+	(       0,   15), (       0,  -15),  //  29: 0000 0011 01x
+	(       0,   14), (       0,  -14),  //  30: 0000 0011 10x
+	(       0,   13), (       0,  -13),  //  31: 0000 0011 11x
+	(       0,   12), (       0,  -12),  //  32: 0000 0100 00x
+	(       0,   11), (       0,  -11),  //  33: 0000 0100 01x
+];
+
+fn read_huffman<T, S>(table: &[(i16, S)], stream: &mut MyBitReader<T>) -> Result<S, DecodeError>
 where
     T: Read,
     S: bitstream_io::Numeric,
@@ -397,27 +719,33 @@ where
     let mut state: (i16, S) = (0, S::default());
 
     loop {
-        state = table[usize::try_from(state.0 + stream.read::<i16>(1).unwrap()).unwrap()];
-
-        if state.0 <= 0 {
+        let bit = stream.read::<i16>(1)?;
+        state = *table
+            .get((state.0 + bit) as usize)
+            .ok_or(DecodeError::InvalidVlc)?;
+
+        if state.0 < 0 {
+            // Invalid code.
+            return Err(DecodeError::InvalidVlc);
+        }
+        if state.0 == 0 {
             break;
         }
     }
-    Some(state.1)
+    Ok(state.1)
 }
 
 fn parse_dct_dc_size<T: std::io::Read>(
     table: &[(i16, i16); 18],
     bs: &mut MyBitReader<T>,
-) -> Option<u8> {
-    match read_huffman(table, bs) {
-        Some(i) => Some(u8::try_from(i).unwrap()),
-        None => None,
-    }
+) -> Result<u8, DecodeError> {
+    read_huffman(table, bs).map(|i| u8::try_from(i).unwrap())
 }
 
-#[allow(dead_code)]
-struct Plane {
+/// One color component of a frame, row by row. Planes cover whole
+/// macroblocks and can be larger than the picture.
+#[derive(Clone)]
+pub struct Plane {
     width: u16,
     height: u16,
     data: Vec<u8>,
@@ -431,35 +759,228 @@ impl Plane {
             data: vec![0; (i32::from(w) * i32::from(h)).try_into().unwrap()],
         }
     }
+
+    /// Number of samples per row.
+    pub fn width(&self) -> usize {
+        usize::from(self.width)
+    }
+
+    /// Number of rows.
+    pub fn height(&self) -> usize {
+        usize::from(self.height)
+    }
+
+    /// Distance in bytes between the starts of two rows.
+    pub fn stride(&self) -> usize {
+        usize::from(self.width)
+    }
+
+    /// All samples, `stride()` bytes per row.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The samples of row `y`.
+    pub fn row(&self, y: usize) -> &[u8] {
+        &self.data[y * self.stride()..][..self.width()]
+    }
 }
 
 struct Pack {
-    data: [u8; 8],
+    // System clock reference in units of the 90 kHz system clock.
+    scr: u64,
 }
 
 impl Pack {
-    fn parse<F: Read>(f: &mut F) -> io::Result<Self> {
-        let mut ret = Pack { data: [0; 8] };
-        f.read_exact(&mut ret.data)?;
-        Ok(ret)
+    fn parse<F: Read>(f: &mut F) -> Result<Self, DecodeError> {
+        // The SCR is followed by the mux rate.
+        let mut data = [0; 8];
+        read_packet_bytes(f, &mut data)?;
+        Ok(Pack {
+            scr: parse_timestamp(&data, 0, 0b0010)?,
+        })
     }
 }
 
-struct SystemHeader {
+/// System header of an ISO 11172-1 stream. Describes the elementary
+/// streams multiplexed into the system stream.
+#[derive(Clone, Debug)]
+pub struct SystemHeader {
+    // The header after the length field. At least the six bytes of
+    // fixed fields, followed by the stream table.
     data: Vec<u8>,
 }
 
+/// Entry of the stream table of the system header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferBound {
+    stream_id: u8,
+    buffer_size_bound: u32,
+    scale: bool,
+    marker: u8,
+}
+
+impl BufferBound {
+    /// Id of the stream, or 0xB8 and 0xB9 for all audio and all video
+    /// streams, respectively.
+    pub fn stream_id(&self) -> u8 {
+        self.stream_id
+    }
+
+    /// Upper bound of the STD input buffer size of the stream in
+    /// bytes.
+    pub fn buffer_size_bound(&self) -> u32 {
+        self.buffer_size_bound
+    }
+}
+
 impl SystemHeader {
-    fn parse<F: Read>(f: &mut F) -> io::Result<Self> {
+    /// Upper bound of the mux rate of all packs, in units of 50
+    /// bytes/s.
+    pub fn rate_bound(&self) -> u32 {
+        (u32::from(self.data[0] & 0x7F) << 15)
+            | (u32::from(self.data[1]) << 7)
+            | u32::from(self.data[2] >> 1)
+    }
+
+    /// Maximum number of audio streams that are decoded
+    /// simultaneously.
+    pub fn audio_bound(&self) -> u8 {
+        self.data[3] >> 2
+    }
+
+    /// Whether the stream has a fixed bit rate.
+    pub fn fixed(&self) -> bool {
+        (self.data[3] & 0b10) != 0
+    }
+
+    /// Whether the stream is a constrained system parameter stream
+    /// (CSPS).
+    pub fn constrained_parameters(&self) -> bool {
+        (self.data[3] & 0b01) != 0
+    }
+
+    /// Whether the audio sampling rate is locked to the system clock.
+    pub fn system_audio_lock(&self) -> bool {
+        (self.data[4] & 0x80) != 0
+    }
+
+    /// Whether the video picture rate is locked to the system clock.
+    pub fn system_video_lock(&self) -> bool {
+        (self.data[4] & 0x40) != 0
+    }
+
+    /// Maximum number of video streams that are decoded
+    /// simultaneously.
+    pub fn video_bound(&self) -> u8 {
+        self.data[4] & 0x1F
+    }
+
+    /// The stream table, in order.
+    pub fn buffer_bounds(&self) -> Vec<BufferBound> {
+        // Each entry is three bytes long and starts with a set bit.
+        self.data[6..]
+            .chunks_exact(3)
+            .take_while(|entry| (entry[0] & 0x80) != 0)
+            .map(|entry| {
+                let scale = (entry[1] & 0x20) != 0;
+                let size = (u32::from(entry[1] & 0x1F) << 8) | u32::from(entry[2]);
+                BufferBound {
+                    stream_id: entry[0],
+                    buffer_size_bound: size * if scale { 1024 } else { 128 },
+                    scale,
+                    marker: entry[1] >> 6,
+                }
+            })
+            .collect()
+    }
+
+    /// Ids of the streams listed in the header, in order. Besides
+    /// the ids of individual streams, 0xB8 and 0xB9 stand for all
+    /// audio and all video streams, respectively.
+    pub fn stream_ids(&self) -> Vec<u8> {
+        self.buffer_bounds()
+            .into_iter()
+            .map(|bound| bound.stream_id)
+            .collect()
+    }
+
+    /// Ids of the video streams listed in the header.
+    pub fn video_stream_ids(&self) -> Vec<u8> {
+        self.stream_ids()
+            .into_iter()
+            .filter(|id| is_video_stream(*id))
+            .collect()
+    }
+
+    /// Ids of the audio streams listed in the header.
+    pub fn audio_stream_ids(&self) -> Vec<u8> {
+        self.stream_ids()
+            .into_iter()
+            .filter(|id| is_audio_stream(*id))
+            .collect()
+    }
+
+    /// Checks the header against the constraints of ISO 11172-1,
+    /// section 2.4.4.2. Decoding does not depend on them, but a
+    /// violation indicates a broken multiplexer or a damaged stream.
+    /// The demultiplexer only logs violations as warnings. Callers
+    /// that want to reject such streams check the header themselves.
+    pub fn validate(&self) -> Result<(), DecodeError> {
+        if (self.data[0] & 0x80) == 0 || (self.data[2] & 0x01) == 0 || (self.data[4] & 0x20) == 0 {
+            return Err(DecodeError::ForbiddenValue("system header marker bit"));
+        }
+        if self.data[5] != 0xFF {
+            return Err(DecodeError::ForbiddenValue("system header reserved byte"));
+        }
+        if self.audio_bound() > 32 {
+            return Err(DecodeError::ForbiddenValue("audio_bound"));
+        }
+        if self.video_bound() > 16 {
+            return Err(DecodeError::ForbiddenValue("video_bound"));
+        }
+
+        let bounds = self.buffer_bounds();
+        if 6 + 3 * bounds.len() != self.data.len() {
+            return Err(DecodeError::ForbiddenValue("system header length"));
+        }
+        for (i, bound) in bounds.iter().enumerate() {
+            let audio = bound.stream_id == ALL_AUDIO_STREAMS || is_audio_stream(bound.stream_id);
+            let video = bound.stream_id == ALL_VIDEO_STREAMS || is_video_stream(bound.stream_id);
+            if bound.stream_id < PACKET_START_CODE && !audio && !video {
+                return Err(DecodeError::ForbiddenValue("system header stream id"));
+            }
+            if bounds[..i].iter().any(|b| b.stream_id == bound.stream_id) {
+                return Err(DecodeError::ForbiddenValue(
+                    "duplicate system header stream id",
+                ));
+            }
+            if bound.marker != 0b11 {
+                return Err(DecodeError::ForbiddenValue("system header marker bit"));
+            }
+            // Audio buffers are counted in units of 128 bytes, video
+            // buffers in units of 1024 bytes.
+            if (audio && bound.scale) || (video && !bound.scale) {
+                return Err(DecodeError::ForbiddenValue("STD_buffer_bound_scale"));
+            }
+        }
+        Ok(())
+    }
+
+    fn parse<F: Read>(f: &mut F) -> Result<Self, DecodeError> {
         let mut buf = [0; 2];
-        f.read_exact(&mut buf)?;
+        read_packet_bytes(f, &mut buf)?;
         let hdr_len = u16::from_be_bytes(buf);
 
         let mut ret = Self {
             data: vec![0; hdr_len.into()],
         };
 
-        f.read_exact(&mut ret.data.as_mut_slice())?;
+        read_packet_bytes(f, ret.data.as_mut_slice())?;
+
+        if ret.data.len() < 6 {
+            return Err(DecodeError::TruncatedPacket);
+        }
 
         Ok(ret)
     }
@@ -473,142 +994,318 @@ fn is_video_layer_start_code(b: &[u8; 4]) -> bool {
     b[0] == 0 && b[1] == 0 && b[2] == 1 && b[3] <= GROUP_OF_PICTURES_START_VALUE
 }
 
+fn is_video_stream(stream_id: u8) -> bool {
+    (VIDEO_STREAM_0_START_CODE..=VIDEO_STREAM_15_START_CODE).contains(&stream_id)
+}
+
+fn is_audio_stream(stream_id: u8) -> bool {
+    (AUDIO_STREAM_0_START_CODE..=AUDIO_STREAM_31_START_CODE).contains(&stream_id)
+}
+
 fn is_packet_start_code(b: &[u8; 4]) -> bool {
     b[0] == 0 && b[1] == 0 && b[2] == 1 && b[3] >= PACKET_START_CODE
 }
 
 struct Packet {
+    // Presentation and decoding time stamps in units of the 90 kHz
+    // system clock.
+    pts: Option<u64>,
+    dts: Option<u64>,
     data: Vec<u8>,
 }
 
 impl Packet {
-    fn parse<F: Read + Seek>(f: &mut F, stream_id: u8) -> io::Result<Self> {
-        let offset = f.stream_position().unwrap();
-        trace!(
-            "stream id=0x{:x} at offset {}(0x{:x})",
-            stream_id,
-            offset,
-            offset
-        );
+    fn parse<F: Read>(f: &mut F, stream_id: u8) -> Result<Self, DecodeError> {
+        trace!("stream id=0x{:x}", stream_id);
 
         let mut packet_len_buf = [0; 2];
-        f.read_exact(&mut packet_len_buf)?;
+        read_packet_bytes(f, &mut packet_len_buf)?;
         let packet_len = u16::from_be_bytes(packet_len_buf);
 
         trace!("packet len={}", packet_len);
 
         let mut data = vec![0; packet_len.into()];
 
-        f.read_exact(&mut data.as_mut_slice())?;
+        read_packet_bytes(f, data.as_mut_slice())?;
 
-        let mut idx = 0;
+        let mut packet = Packet {
+            pts: None,
+            dts: None,
+            data: vec![],
+        };
 
-        loop {
-            if data[idx] != 0xFF {
-                break;
+        match stream_id {
+            // Nothing to see in padding packets.
+            PADDING_STREAM => return Ok(packet),
+            // Private stream 2 packets have no header fields.
+            PRIVATE_STREAM_2 => {}
+            _ => {
+                let header_len = packet.parse_header(&data)?;
+                trace!("packet header len={}", header_len);
+                data.drain(..header_len);
             }
+        }
+
+        trace!("pts={:?} dts={:?}", packet.pts, packet.dts);
+
+        packet.data = data;
+        Ok(packet)
+    }
+
+    /// Parses the fields between the packet length and the packet
+    /// data, ISO 11172-1, section 2.4.3.3. Returns their length.
+    fn parse_header(&mut self, data: &[u8]) -> Result<usize, DecodeError> {
+        let byte_at = |idx: usize| data.get(idx).copied().ok_or(DecodeError::TruncatedPacket);
+
+        let mut idx = 0;
+
+        while byte_at(idx)? == 0xFF {
             idx += 1;
+            if idx > MAX_STUFFING_BYTES {
+                return Err(DecodeError::ForbiddenValue("packet stuffing"));
+            }
         }
 
-        // buffer scale and size
-        if (data[idx] & 0b01000000) > 0 {
+        // STD buffer scale and size
+        if (byte_at(idx)? >> 6) == 0b01 {
+            byte_at(idx + 1)?;
             idx += 2;
         }
 
-        // presentation time stamp (PTS)
-        if (data[idx] & 0b00110000) > 0 {
+        match byte_at(idx)? >> 4 {
+            // presentation time stamp (PTS)
+            0b0010 => {
+                self.pts = Some(parse_timestamp(data, idx, 0b0010)?);
+                idx += 5;
+            }
             // presentation time stamp (PTS) and decoding time stamp (DTS)
-            idx += 10;
-        } else if (data[idx] & 0b00100000) > 0 {
-            idx += 5;
-        } else {
-            idx += 1;
+            0b0011 => {
+                self.pts = Some(parse_timestamp(data, idx, 0b0011)?);
+                self.dts = Some(parse_timestamp(data, idx + 5, 0b0001)?);
+                idx += 10;
+            }
+            // no time stamps
+            _ if byte_at(idx)? == 0x0F => idx += 1,
+            _ => return Err(DecodeError::ForbiddenValue("packet header")),
         }
 
-        trace!("packet header len={}", idx);
+        Ok(idx)
+    }
+}
 
-        Ok(Packet {
-            data: data[idx..].to_vec(),
-        })
+/// Time stamps are 33 bits wide and wrap around after about 26.5
+/// hours. Differences between them are taken modulo this.
+const TIMESTAMP_WRAP: u64 = 1 << 33;
+
+/// Decodes the 33 bit time stamp in the five bytes at `idx`. The bits
+/// follow a four bit `prefix` and are split into groups of 3, 15 and
+/// 15 bits, each followed by a marker bit.
+fn parse_timestamp(data: &[u8], idx: usize, prefix: u8) -> Result<u64, DecodeError> {
+    let b = data.get(idx..idx + 5).ok_or(DecodeError::TruncatedPacket)?;
+    if (b[0] >> 4) != prefix {
+        return Err(DecodeError::ForbiddenValue("time stamp prefix"));
     }
+    if (b[0] & b[2] & b[4] & 0x01) == 0 {
+        return Err(DecodeError::ForbiddenValue("time stamp marker bit"));
+    }
+    Ok((u64::from(b[0] >> 1) & 0x07) << 30
+        | u64::from(b[1]) << 22
+        | u64::from(b[2] >> 1) << 15
+        | u64::from(b[3]) << 7
+        | u64::from(b[4] >> 1))
 }
 
-fn parse_pack<F: Read + Seek>(f: &mut F, data: &mut Vec<u8>) -> io::Result<()> {
-    Pack::parse(f)?;
+/// Encodes the 33 bit time stamp `t` after a four bit `prefix` in the
+/// layout read by `parse_timestamp`.
+fn timestamp_bytes(prefix: u8, t: u64) -> [u8; 5] {
+    [
+        prefix << 4 | 0x01 | ((t >> 29) & 0x0e) as u8,
+        (t >> 22) as u8,
+        0x01 | ((t >> 14) & 0xfe) as u8,
+        (t >> 7) as u8,
+        0x01 | ((t << 1) & 0xfe) as u8,
+    ]
+}
 
-    let mut buf = [0; 4];
-    f.read_exact(&mut buf)?;
+/// Reads exactly `buf.len()` bytes of a packet. Running out of data
+/// means the packet is truncated.
+fn read_packet_bytes<F: Read>(f: &mut F, buf: &mut [u8]) -> Result<(), DecodeError> {
+    f.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => DecodeError::TruncatedPacket,
+        _ => DecodeError::Io(e),
+    })
+}
 
-    if is_start_code(&buf, SYSTEM_HEADER_START_CODE) {
-        SystemHeader::parse(f)?;
-    } else {
-        f.seek(SeekFrom::Current(-4))?;
-    }
+/// Structures of the system layer.
+enum SystemItem {
+    Pack(Pack),
+    SystemHeader(SystemHeader),
+    Packet(u8, Packet),
+}
 
+/// Reads the next pack header, system header or packet from the
+/// system layer. Returns `None` at the end of the stream.
+fn next_system_item<F: Read>(f: &mut F) -> Result<Option<SystemItem>, DecodeError> {
     loop {
-        match f.read_exact(&mut buf) {
-            Err(e) => match e.kind() {
-                std::io::ErrorKind::UnexpectedEof => return Ok(()),
-                _ => return Err(e),
-            },
-            _ => {}
-        };
-
-        if !is_packet_start_code(&buf) {
-            f.seek(SeekFrom::Current(-4))?;
-            break;
+        let mut buf = [0; 4];
+        if let Err(e) = f.read_exact(&mut buf) {
+            match e.kind() {
+                std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                _ => return Err(e.into()),
+            }
         }
 
-        let packet = Packet::parse(f, buf[3])?;
-
-        if buf[3] == VIDEO_STREAM_0_START_CODE {
-            data.extend_from_slice(&packet.data);
+        if is_start_code(&buf, PACK_START_CODE) {
+            return Ok(Some(SystemItem::Pack(Pack::parse(f)?)));
+        } else if is_start_code(&buf, SYSTEM_HEADER_START_CODE) {
+            let header = SystemHeader::parse(f)?;
+            if let Err(e) = header.validate() {
+                warn!("invalid system header: {}", e);
+            }
+            return Ok(Some(SystemItem::SystemHeader(header)));
+        } else if is_start_code(&buf, ISO_11172_END_CODE) {
+            // Another stream may be concatenated to this one.
+        } else if is_packet_start_code(&buf) {
+            let packet = Packet::parse(f, buf[3])?;
+            return Ok(Some(SystemItem::Packet(buf[3], packet)));
+        } else {
+            return Err(DecodeError::UnexpectedStartCode(buf[3]));
         }
     }
-
-    Ok(())
 }
 
 /// Read pack payloads an iso11172 stream into `data`..
-pub fn iso11172_stream<F: Read + Seek>(f: &mut F, data: &mut Vec<u8>) -> io::Result<()> {
-    loop {
-        let mut buf = [0; 4];
-        match f.read_exact(&mut buf) {
-            Err(e) => match e.kind() {
-                std::io::ErrorKind::UnexpectedEof => return Ok(()),
-                _ => return Err(e),
-            },
-            _ => {}
-        }
-
-        if !is_start_code(&buf, PACK_START_CODE) {
-            assert!(false);
+pub fn iso11172_stream<F: Read>(f: &mut F, data: &mut Vec<u8>) -> Result<(), DecodeError> {
+    while let Some(item) = next_system_item(f)? {
+        if let SystemItem::Packet(VIDEO_STREAM_0_START_CODE, packet) = item {
+            data.extend_from_slice(&packet.data);
         }
-
-        parse_pack(f, data)?;
     }
+    Ok(())
 }
 
+#[derive(Clone)]
 pub struct Frame {
     width: u16,
     height: u16,
+    picture_type: u8,
+    temporal_reference: u16,
+    // Ranges of macroblock addresses that could not be decoded and
+    // were concealed instead.
+    concealed: Vec<Range<u32>>,
+    // Presentation and decoding time stamps in units of the 90 kHz
+    // system clock.
+    pts: u64,
+    dts: Option<u64>,
     y: Plane,
     cr: Plane,
     cb: Plane,
 }
 
 impl Frame {
-    fn new_dummy() -> Frame {
-        Frame::new(0, 0)
+    /// Macroblock address ranges that were concealed because their
+    /// slice was damaged. Empty for intact pictures. Addresses count
+    /// macroblocks in raster order, starting at the top-left one.
+    pub fn concealed_macroblocks(&self) -> &[Range<u32>] {
+        &self.concealed
+    }
+
+    /// Presentation time stamp in units of the 90 kHz system clock.
+    /// Taken from the system layer if it carries one for the picture,
+    /// otherwise derived from the frame rate. Video elementary
+    /// streams start at 0.
+    pub fn pts(&self) -> u64 {
+        self.pts
+    }
+
+    /// Decoding time stamp of the system layer, if present. Only
+    /// reference pictures of streams with B-pictures have one.
+    pub fn dts(&self) -> Option<u64> {
+        self.dts
+    }
+
+    /// The presentation time stamp as a duration since the start of
+    /// the system clock.
+    pub fn presentation_time(&self) -> Duration {
+        Duration::from_nanos(self.pts * 100_000 / 9)
+    }
+
+    /// Width of the picture as given by the sequence header.
+    pub fn width(&self) -> usize {
+        usize::from(self.width)
+    }
+
+    /// Height of the picture as given by the sequence header.
+    pub fn height(&self) -> usize {
+        usize::from(self.height)
+    }
+
+    /// Width of the luminance plane, a multiple of the macroblock
+    /// size. Columns beyond `width()` are not to be displayed.
+    pub fn coded_width(&self) -> usize {
+        self.y.width()
+    }
+
+    /// Height of the luminance plane, a multiple of the macroblock
+    /// size. Rows beyond `height()` are not to be displayed.
+    pub fn coded_height(&self) -> usize {
+        self.y.height()
+    }
+
+    /// The luminance plane.
+    pub fn y(&self) -> &Plane {
+        &self.y
+    }
+
+    /// The blue color difference plane, subsampled by two in both
+    /// directions.
+    pub fn cb(&self) -> &Plane {
+        &self.cb
+    }
+
+    /// The red color difference plane, subsampled by two in both
+    /// directions.
+    pub fn cr(&self) -> &Plane {
+        &self.cr
+    }
+
+    /// How the picture was coded.
+    pub fn picture_type(&self) -> PictureType {
+        match self.picture_type {
+            FRAME_TYPE_I => PictureType::I,
+            FRAME_TYPE_P => PictureType::P,
+            FRAME_TYPE_B => PictureType::B,
+            FRAME_TYPE_D => PictureType::D,
+            // Pictures of other types are never decoded.
+            t => unreachable!("picture type {}", t),
+        }
+    }
+
+    /// Position of the picture in display order within its group of
+    /// pictures.
+    pub fn temporal_reference(&self) -> u16 {
+        self.temporal_reference
+    }
+
+    /// Placeholder for a picture that could not be decoded.
+    fn new_dummy(temporal_reference: u16) -> Frame {
+        let mut frame = Frame::new(0, 0);
+        frame.temporal_reference = temporal_reference;
+        frame
     }
 
     fn new(w: u16, h: u16) -> Frame {
-        let macroblock_width = (w + 15) / 16;
-        let macroblock_height = (h + 15) / 16;
+        let macroblock_width = w.div_ceil(16);
+        let macroblock_height = h.div_ceil(16);
 
         Frame {
             width: w,
             height: h,
+            picture_type: FRAME_TYPE_I,
+            temporal_reference: 0,
+            concealed: vec![],
+            pts: 0,
+            dts: None,
 
             // * 16 because there are 16 pixel per macroblock.
             y: Plane::new(macroblock_width * 16, macroblock_height * 16),
@@ -619,121 +1316,121 @@ impl Frame {
         }
     }
 
-    fn put_pixel(
-        &self,
-        dest: &mut Vec<u8>,
-        d_index: i32,
-        y_index: i32,
-        rgb: (i32, i32, i32),
-        y_offset: i32,
-        dest_offset: i32,
-    ) {
-        let red_idx = 0;
-        let green_idx = 1;
-        let blue_idx = 2;
-        let idx: usize = (i32::from(y_index) + y_offset).try_into().unwrap();
-        let y: i32 = ((i32::from(self.y.data[idx]) - 16) * 76309) >> 16;
-        dest[usize::try_from(d_index + dest_offset + red_idx).unwrap()] = clamp(y + rgb.0);
-        dest[usize::try_from(d_index + dest_offset + green_idx).unwrap()] = clamp(y - rgb.1);
-        dest[usize::try_from(d_index + dest_offset + blue_idx).unwrap()] = clamp(y + rgb.2);
+    /// Converts the frame to tightly packed 8-bit RGB.
+    pub fn to_rgb(&self) -> Vec<u8> {
+        self.to_format(PixelFormat::Rgb24)
+    }
 
-        // print!("{} {} {} {}, ", usize::try_from(d_index + dest_offset + red_idx).unwrap(),
-        //        dest[usize::try_from(d_index + dest_offset + red_idx).unwrap()],
-        //        dest[usize::try_from(d_index + dest_offset + green_idx).unwrap()],
-        //        dest[usize::try_from(d_index + dest_offset + blue_idx).unwrap()]);
+    fn to_bgr(&self) -> Vec<u8> {
+        self.to_format(PixelFormat::Bgr24)
     }
 
-    fn to_rgb(&self) -> Vec<u8> {
-        unimplemented!();
+    /// Converts the frame to tightly packed pixels of `format`.
+    pub fn to_format(&self, format: PixelFormat) -> Vec<u8> {
+        let stride = self.width() * format.bytes_per_pixel();
+        let mut dest = vec![0; stride * self.height()];
+        self.convert_into(format, &mut dest, stride)
+            .expect("buffer sized for the frame");
+        dest
     }
 
-    // Convert a frame from YCrCb to BGR.
-    // Modeled after PLM_DEFINE_FRAME_CONVERT_FUNCTION from pl_mpeg.
-    fn to_bgr(&self) -> Vec<u8> {
+    /// Converts the displayed part of the frame to `format` and
+    /// writes it to `dest`, starting a new row every `stride` bytes.
+    /// Bytes between the rows are left as they are. Luma is expanded
+    /// from the video range 16 to 235 to the full range.
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` if `stride` is
+    /// shorter than a row or `dest` cannot hold all rows.
+    pub fn convert_into(
+        &self,
+        format: PixelFormat,
+        dest: &mut [u8],
+        stride: usize,
+    ) -> io::Result<()> {
         let start = Instant::now();
 
-        let bytes_per_pixel = 3i32;
-        let mut dest =
-            vec![
-                0;
-                usize::try_from(i32::from(self.width) * i32::from(self.height) * bytes_per_pixel)
-                    .unwrap()
-            ];
-        let stride: i32 = i32::from(self.width) * bytes_per_pixel;
-        // For some reason, we half the width and height here. The
-        // innermost loop sets 4 pixels at a time, compensating for
-        // halving the width and height.
-        let cols: i32 = (self.width >> 1).into();
-        let rows: i32 = (self.height >> 1).into();
-        let yw: i32 = i32::from(self.y.width);
-        let cw: i32 = i32::from(self.cb.width);
-
-        for row in 0..rows {
-            let mut c_index: i32 = row * cw;
-            let mut y_index: i32 = row * 2 * yw;
-            let mut d_index: i32 = (row * 2 * stride).into();
-
-            for _ in 0..cols {
-                let cr: i32 = i32::from(self.cr.data[usize::try_from(c_index).unwrap()]) - 128;
-                let cb: i32 = i32::from(self.cb.data[usize::try_from(c_index).unwrap()]) - 128;
+        let bytes_per_pixel = format.bytes_per_pixel();
+        let row_len = self.width() * bytes_per_pixel;
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        if stride < row_len {
+            return Err(invalid(format!(
+                "stride {} below row length {}",
+                stride, row_len
+            )));
+        }
+        if self.height() > 0 {
+            let len = (self.height() - 1) * stride + row_len;
+            if dest.len() < len {
+                return Err(invalid(format!(
+                    "buffer of {} bytes, need {}",
+                    dest.len(),
+                    len
+                )));
+            }
+        }
+
+        // Modeled after PLM_DEFINE_FRAME_CONVERT_FUNCTION from pl_mpeg.
+        let luma = |y: u8| ((i32::from(y) - 16) * 76309) >> 16;
+        let (rgb, alpha) = format.layout();
+        for row in 0..self.height() {
+            let y_row = &self.y.row(row)[..self.width()];
+            let cb_row = self.cb.row(row / 2);
+            let cr_row = self.cr.row(row / 2);
+            let dest_row = &mut dest[row * stride..][..row_len];
+
+            if format == PixelFormat::Gray8 {
+                for (d, &y) in dest_row.iter_mut().zip(y_row) {
+                    *d = clamp(luma(y));
+                }
+                continue;
+            }
+
+            for (col, (pixel, &y)) in dest_row
+                .chunks_exact_mut(bytes_per_pixel)
+                .zip(y_row)
+                .enumerate()
+            {
+                // Each chroma sample covers 2x2 luma samples.
+                let cr: i32 = i32::from(cr_row[col / 2]) - 128;
+                let cb: i32 = i32::from(cb_row[col / 2]) - 128;
                 let r: i32 = (cr * 104597) >> 16;
                 let g: i32 = (cb * 25674 + cr * 53278) >> 16;
                 let b: i32 = (cb * 132201) >> 16;
-                self.put_pixel(&mut dest, d_index, y_index, (b, g, r), 0, 0);
-                self.put_pixel(
-                    &mut dest,
-                    d_index,
-                    y_index,
-                    (b, g, r),
-                    1,
-                    bytes_per_pixel.into(),
-                );
-                self.put_pixel(
-                    &mut dest,
-                    d_index,
-                    y_index,
-                    (b, g, r),
-                    yw.into(),
-                    stride.into(),
-                );
-                self.put_pixel(
-                    &mut dest,
-                    d_index,
-                    y_index,
-                    (b, g, r),
-                    (yw + 1).into(),
-                    (stride + bytes_per_pixel).into(),
-                );
-                // println!("");
-                c_index += 1;
-                y_index += 2;
-                d_index += i32::from(2 * bytes_per_pixel);
+                let y = luma(y);
+                pixel[rgb[0]] = clamp(y + r);
+                pixel[rgb[1]] = clamp(y - g);
+                pixel[rgb[2]] = clamp(y + b);
+                if let Some(alpha) = alpha {
+                    pixel[alpha] = 255;
+                }
             }
         }
 
         let duration = start.elapsed();
 
         if PROFILE {
-            println!("to_rgb() took {:?}", duration);
+            println!("convert_into() took {:?}", duration);
         }
-
-        dest
+        Ok(())
     }
 }
 
 // #define PLM_BLOCK_SET(DEST, DEST_INDEX, DEST_WIDTH, SOURCE_INDEX, SOURCE_WIDTH, BLOCK_SIZE, OP) do { \
 // 	}} while(FALSE)
 
+/// Writes a `block_size` x `block_size` block into `dest`. The
+/// closure `f` receives the source index and the current destination
+/// value and returns the new destination value.
 fn block_set<F>(
-    dest: &mut Vec<u8>,
+    dest: &mut [u8],
     mut dest_idx: usize,
     dest_width: usize,
-    source_idx: usize,
+    mut source_idx: usize,
     source_width: usize,
     block_size: usize,
     f: F,
 ) where
-    F: Fn(usize) -> u8,
+    F: Fn(usize, u8) -> u8,
 {
     trace!(
         "block_set={} {} {} {}",
@@ -745,51 +1442,93 @@ fn block_set<F>(
 
     let dest_scan = dest_width - block_size;
     let source_scan = source_width - block_size;
-    let mut source_idx = 0;
 
     for _y in 0..block_size {
         for _x in 0..block_size {
-            // print!("{}={} ", dest_idx, op[source_idx]);
-            dest[dest_idx] = f(source_idx);
+            dest[dest_idx] = f(source_idx, dest[dest_idx]);
             source_idx += 1;
             dest_idx += 1;
         }
         source_idx += source_scan;
         dest_idx += dest_scan;
     }
-    // println!("");
 }
 
-#[allow(dead_code)]
-struct Container {
-    mb_row: i32,
-    mb_col: i32,
-    mb_addr: i32,
+/// Motion vector state of one prediction direction. Vectors are
+/// predicted from the previous macroblock within a slice.
+#[derive(Debug, Default, Copy, Clone)]
+struct MotionVector {
+    full_px: bool,
+    r_size: u8,
+    h: i32,
+    v: i32,
+}
+
+impl MotionVector {
+    fn new(full_px: bool, f_code: u8) -> Self {
+        MotionVector {
+            full_px,
+            r_size: f_code.saturating_sub(1),
+            h: 0,
+            v: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.h = 0;
+        self.v = 0;
+    }
+
+    /// Motion vector in half-pel units.
+    fn half_pel(&self) -> (i32, i32) {
+        if self.full_px {
+            (self.h << 1, self.v << 1)
+        } else {
+            (self.h, self.v)
+        }
+    }
+}
+
+#[allow(dead_code)]
+struct Container<'a> {
+    mb_row: i32,
+    mb_col: i32,
+    mb_addr: i32,
     mb_width: i32,
     mb_height: i32,
     mb_size: i32,
     width: u16,
     height: u16,
     quantizer_scale: u8,
+    intra_quant_matrix: [u8; 64],
+    non_intra_quant_matrix: [u8; 64],
     dc_predictor: [i32; 3],
+    picture_type: u8,
+    macroblock_type: u8,
+    // The first address increment of a slice is relative to the
+    // slice's start, i.e., no macroblocks are skipped.
+    slice_begin: bool,
+    motion_forward: MotionVector,
+    motion_backward: MotionVector,
+    // Reference frames for forward and backward prediction.
+    forward: Option<&'a Frame>,
+    backward: Option<&'a Frame>,
+    // Macroblocks that have been decoded, indexed by address.
+    // Everything else gets concealed at the end of the picture.
+    decoded: Vec<bool>,
     frame: Frame,
 }
 
 #[inline(always)]
 fn clamp(n: i32) -> u8 {
-    if n > 255 {
-        return 255;
-    } else if n < 0 {
-        return 0;
-    }
-    return n as u8;
+    n.clamp(0, 255) as u8
 }
 
 fn decode_dc_diff(coded: u8, size: u8) -> i16 {
     if coded & (1 << (size - 1)) != 0 {
-        return coded.into();
+        coded.into()
     } else {
-        return (-(1i16 << size)) | i16::from(coded + 1);
+        (-(1i16 << size)) | i16::from(coded + 1)
     }
 }
 
@@ -801,6 +1540,7 @@ pub trait FrameProcessor {
     fn process(&mut self, f: &Frame);
 }
 
+#[derive(Default)]
 pub struct PersistFrames {
     /// Count the number of persisted frames.
     frame_count: i32,
@@ -815,6 +1555,7 @@ impl FrameProcessor for PersistFrames {
         let f = OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open(fname)
             .unwrap();
         let mut writer = io::BufWriter::new(f);
@@ -825,7 +1566,8 @@ impl FrameProcessor for PersistFrames {
             frame.height.into(),
             &frame.to_bgr(),
             &mut writer,
-        );
+        )
+        .unwrap();
         self.frame_count += 1;
     }
 }
@@ -836,7 +1578,7 @@ impl PersistFrames {
     }
 }
 
-impl Container {
+impl<'a> Container<'a> {
     fn new(width: u16, height: u16) -> Self {
         let mb_width = (i32::from(width) + 15) / 16;
         let mb_height = (i32::from(height) + 15) / 16;
@@ -845,49 +1587,63 @@ impl Container {
             mb_row: 0,
             mb_col: 0,
             mb_addr: -1,
-            mb_width: mb_width,
-            mb_height: mb_height,
+            mb_width,
+            mb_height,
             mb_size: mb_width * mb_height,
-            width: width,
-            height: height,
+            width,
+            height,
             quantizer_scale: 0,
+            intra_quant_matrix: VIDEO_INTRA_QUANT_MATRIX,
+            non_intra_quant_matrix: VIDEO_NON_INTRA_QUANT_MATRIX,
             dc_predictor: [128; 3],
+            picture_type: FRAME_TYPE_I,
+            macroblock_type: 0,
+            slice_begin: false,
+            motion_forward: MotionVector::default(),
+            motion_backward: MotionVector::default(),
+            forward: None,
+            backward: None,
+            decoded: vec![false; (mb_width * mb_height) as usize],
             frame: Frame::new(width, height),
         }
     }
 
-    fn parse_slice<T>(&mut self, f: &mut std::io::BufReader<T>, slice_nr: u8) -> io::Result<()>
+    fn parse_slice<T>(
+        &mut self,
+        f: &mut std::io::BufReader<T>,
+        slice_nr: u8,
+    ) -> Result<(), DecodeError>
     where
         T: std::io::Read + std::io::Seek,
     {
         trace!(
             "Slice start code at stream offset 0x{:x} bytes. slice_nr={}.",
-            f.stream_position().unwrap() - 4,
+            f.stream_position()? - 4,
             slice_nr
         );
 
         f.seek(SeekFrom::Current(4))?;
 
         self.dc_predictor = [128; 3];
+        self.motion_forward.reset();
+        self.motion_backward.reset();
+        self.slice_begin = true;
 
         self.mb_addr = (i32::from(slice_nr) - 1) * self.mb_width - 1;
 
         let mut stream: MyBitReader<T> = bitstream_io::BitReader::new(f);
-        self.quantizer_scale = stream.read::<u8>(5).unwrap();
+        self.quantizer_scale = stream.read::<u8>(5)?;
         trace!("slice quantizer_scale={}", self.quantizer_scale);
 
         // Extra slice info
-        loop {
-            if stream.read::<u8>(1).unwrap() == 0b1 {
-                println!("extra slice info");
-                stream.read::<u8>(8).unwrap();
-            } else {
-                break;
-            }
+        while stream.read::<u8>(1)? == 0b1 {
+            trace!("extra slice info");
+            stream.read::<u8>(8)?;
         }
 
         loop {
-            self.parse_macroblock(&mut stream, slice_nr);
+            self.parse_macroblock(&mut stream, slice_nr)?;
+            self.decoded[self.mb_addr as usize] = true;
 
             if self.mb_addr >= self.mb_size - 1 {
                 trace!("mb_addr >= mb_size - 1");
@@ -914,216 +1670,509 @@ impl Container {
         &mut self,
         bs: &mut MyBitReader<T>,
         slice: u8,
-    ) -> Option<()> {
-        let mut addr_inc = read_huffman(&VIDEO_MACROBLOCK_ADDRESS_INCREMENT, bs).unwrap();
-        trace!("addr_inc={}", addr_inc);
+    ) -> Result<(), DecodeError> {
+        let mut increment = 0;
+        let mut addr_inc = read_huffman(&VIDEO_MACROBLOCK_ADDRESS_INCREMENT, bs)?;
 
+        // Macroblock stuffing
         while addr_inc == 34 {
-            addr_inc = read_huffman(&VIDEO_MACROBLOCK_ADDRESS_INCREMENT, bs).unwrap();
+            addr_inc = read_huffman(&VIDEO_MACROBLOCK_ADDRESS_INCREMENT, bs)?;
         }
 
+        // Macroblock escape
         while addr_inc == 35 {
-            unimplemented!("");
+            increment += 33;
+            addr_inc = read_huffman(&VIDEO_MACROBLOCK_ADDRESS_INCREMENT, bs)?;
         }
+        increment += i32::from(addr_inc);
+        trace!("addr_inc={}", increment);
+
+        if self.slice_begin {
+            self.slice_begin = false;
+            self.mb_addr += increment;
+        } else {
+            if self.mb_addr + increment >= self.mb_size {
+                return Err(DecodeError::InvalidMacroblockAddress(
+                    self.mb_addr + increment,
+                ));
+            }
 
-        let macro_type = parse_macroblock_type(bs).unwrap();
+            if increment > 1 {
+                // Skipped macroblocks reset the DC predictors and, in
+                // P-pictures, the motion vectors. Skipped macroblocks
+                // in B-pictures keep the vectors and macroblock type
+                // of the previous macroblock.
+                self.dc_predictor = [128; 3];
+                if self.picture_type == FRAME_TYPE_P {
+                    self.motion_forward.reset();
+                }
+            }
 
-        // Can only deal with I-frames for now.
-        assert!((macro_type & 0b1_0000) != 0);
+            while increment > 1 {
+                self.mb_addr += 1;
+                self.mb_row = self.mb_addr / self.mb_width;
+                self.mb_col = self.mb_addr % self.mb_width;
+                self.predict_macroblock();
+                self.decoded[self.mb_addr as usize] = true;
+                increment -= 1;
+            }
+            self.mb_addr += 1;
+        }
 
-        self.mb_addr += i32::from(addr_inc);
         self.mb_row = self.mb_addr / self.mb_width;
         self.mb_col = self.mb_addr % self.mb_width;
 
+        if self.mb_row >= self.mb_height {
+            return Err(DecodeError::InvalidMacroblockAddress(self.mb_addr));
+        }
+
+        let table: &[(i16, u8)] = match self.picture_type {
+            FRAME_TYPE_P => &VIDEO_MACROBLOCK_TYPE_PREDICTIVE,
+            FRAME_TYPE_B => &VIDEO_MACROBLOCK_TYPE_B,
+            FRAME_TYPE_D => &VIDEO_MACROBLOCK_TYPE_D,
+            _ => &VIDEO_MACROBLOCK_TYPE_INTRA,
+        };
+        self.macroblock_type = parse_macroblock_type(table, bs)?;
+        let intra = (self.macroblock_type & MACROBLOCK_INTRA) != 0;
+
         trace!(
-            "mb_addr={}, mb_row={}, mb_col={}, addr_inc={}, type={}, slice_nr={}",
+            "mb_addr={}, mb_row={}, mb_col={}, type={}, slice_nr={}",
             self.mb_addr,
             self.mb_row,
             self.mb_col,
-            addr_inc,
-            macro_type,
+            self.macroblock_type,
             slice
         );
 
-        if (macro_type & 0b1_0000) == 1 {
-            self.quantizer_scale = bs.read::<u8>(5).unwrap();
-            trace!("quantizer_scale={}", self.quantizer_scale);
+        if self.picture_type == FRAME_TYPE_D {
+            return self.parse_dc_macroblock(bs);
         }
 
-        // Ignore motion vectors and block patterns since they are irrelevant for I-frames.
+        if (self.macroblock_type & MACROBLOCK_QUANT) != 0 {
+            self.quantizer_scale = bs.read::<u8>(5)?;
+            trace!("quantizer_scale={}", self.quantizer_scale);
+        }
 
-        for i in 0..6 {
-            let mut block_data = [0i32; 64];
-            let plane_index = if i < 4 { 0 } else { i - 3 };
-            let predictor = self.dc_predictor[plane_index];
+        if intra {
+            // Intra-coded macroblocks reset the motion vectors.
+            self.motion_forward.reset();
+            self.motion_backward.reset();
+        } else {
+            // Non-intra macroblocks reset the DC predictors.
+            self.dc_predictor = [128; 3];
 
-            let table = if i < 4 {
-                VIDEO_DCT_SIZE_LUMINANCE
-            } else {
-                VIDEO_DCT_SIZE_CHROMINANCE
-            };
+            self.decode_motion_vectors(bs)?;
+            self.predict_macroblock();
+        }
 
-            let dct_size: u8 = parse_dct_dc_size(&table, bs).unwrap();
-            trace!(
-                "block={}, dct_size={}, predictor={}",
-                i,
-                dct_size,
-                predictor
-            );
+        let cbp = if (self.macroblock_type & MACROBLOCK_PATTERN) != 0 {
+            read_huffman(&VIDEO_CODE_BLOCK_PATTERN, bs)?
+        } else if intra {
+            0b11_1111
+        } else {
+            0
+        };
 
-            if dct_size > 0 {
-                let dc_diff_coded = bs.read::<u8>(dct_size.into()).unwrap();
-                let dc_diff_decoded = decode_dc_diff(dc_diff_coded, dct_size);
-                trace!(
-                    "block={}, dct_diff={}, decoded_diff={}",
-                    i,
-                    dc_diff_coded,
-                    dc_diff_decoded
-                );
-                block_data[0] = predictor + i32::from(dc_diff_decoded);
-            } else {
-                block_data[0] = predictor;
+        for i in 0..6 {
+            if (cbp & (0b10_0000 >> i)) != 0 {
+                self.decode_block(bs, i)?;
             }
+        }
 
-            self.dc_predictor[plane_index] = block_data[0];
+        Ok(())
+    }
 
-            block_data[0] <<= 3 + 5;
+    fn decode_motion_vectors<T: Read>(
+        &mut self,
+        bs: &mut MyBitReader<T>,
+    ) -> Result<(), DecodeError> {
+        if (self.macroblock_type & MACROBLOCK_MOTION_FORWARD) != 0 {
+            let r_size = self.motion_forward.r_size;
+            self.motion_forward.h = decode_motion_vector(bs, r_size, self.motion_forward.h)?;
+            self.motion_forward.v = decode_motion_vector(bs, r_size, self.motion_forward.v)?;
+        } else if self.picture_type == FRAME_TYPE_P {
+            // No motion information in a P-picture resets the vectors.
+            self.motion_forward.reset();
+        }
 
-            assert!((macro_type & 0b1_0000) != 0);
-            // For n = 1 to be valid, must be an I-frame.
-            let mut n = 1;
+        if (self.macroblock_type & MACROBLOCK_MOTION_BACKWARD) != 0 {
+            let r_size = self.motion_backward.r_size;
+            self.motion_backward.h = decode_motion_vector(bs, r_size, self.motion_backward.h)?;
+            self.motion_backward.v = decode_motion_vector(bs, r_size, self.motion_backward.v)?;
+        }
+        Ok(())
+    }
 
-            loop {
-                let mut level;
-                let run;
+    /// Fill the current macroblock with the motion compensated
+    /// prediction from the reference frames.
+    fn predict_macroblock(&mut self) {
+        let (fw_h, fw_v) = self.motion_forward.half_pel();
 
-                let coeff = read_huffman(&VIDEO_DCT_COEFF, bs).unwrap();
+        if self.picture_type == FRAME_TYPE_B {
+            let (bw_h, bw_v) = self.motion_backward.half_pel();
+            let (forward, backward) = match (self.forward, self.backward) {
+                (Some(forward), Some(backward)) => (forward, backward),
+                _ => return,
+            };
 
-                if (coeff == 0x0001) && (n > 0) && (bs.read::<u8>(1).unwrap() == 0) {
-                    break;
+            if (self.macroblock_type & MACROBLOCK_MOTION_FORWARD) != 0 {
+                self.copy_macroblock(forward, fw_h, fw_v, false);
+                if (self.macroblock_type & MACROBLOCK_MOTION_BACKWARD) != 0 {
+                    // Interpolated prediction: average of both directions.
+                    self.copy_macroblock(backward, bw_h, bw_v, true);
                 }
+            } else {
+                self.copy_macroblock(backward, bw_h, bw_v, false);
+            }
+        } else if let Some(forward) = self.forward {
+            self.copy_macroblock(forward, fw_h, fw_v, false);
+        }
+    }
 
-                if coeff == 0xffff {
-                    run = bs.read::<u8>(6).unwrap();
-                    level = i32::from(bs.read::<u8>(8).unwrap());
-                    if level == 0 {
-                        level = i32::from(bs.read::<u8>(8).unwrap());
-                    } else if level == 128 {
-                        level = i32::from(bs.read::<u8>(8).unwrap()) - 256;
-                    } else if level > 128 {
-                        level -= 256;
-                    }
-                } else {
-                    run = (coeff >> 8).try_into().unwrap();
-                    level = (coeff & 0xff).try_into().unwrap();
+    /// Replaces every macroblock that was not decoded, e.g., because
+    /// its slice was damaged, with the co-located macroblock of the
+    /// forward reference frame. Without a reference frame of the same
+    /// size, the macroblocks are filled with mid-grey.
+    fn conceal(&mut self) {
+        let reference = self
+            .forward
+            .filter(|f| f.y.width == self.frame.y.width && f.y.height == self.frame.y.height);
+        let mut addr = 0;
+        while addr < self.mb_size {
+            if self.decoded[addr as usize] {
+                addr += 1;
+                continue;
+            }
+
+            let begin = addr;
+            while addr < self.mb_size && !self.decoded[addr as usize] {
+                self.mb_addr = addr;
+                self.mb_row = addr / self.mb_width;
+                self.mb_col = addr % self.mb_width;
 
-                    if bs.read::<u8>(1).unwrap() == 1 {
-                        level = -level;
+                if let Some(forward) = reference {
+                    self.copy_macroblock(forward, 0, 0, false);
+                } else {
+                    for i in 0..6 {
+                        let (d, dw, di) = self.block_destination(i);
+                        block_set(d, di, dw, 0, 8, 8, |_, _| 128);
                     }
                 }
+                addr += 1;
+            }
+
+            trace!("concealed macroblocks {}..{}", begin, addr);
+            self.frame.concealed.push(begin as u32..addr as u32);
+        }
+    }
+
+    fn copy_macroblock(&mut self, s: &Frame, motion_h: i32, motion_v: i32, interpolate: bool) {
+        self.process_macroblock(&s.y, 0, motion_h, motion_v, interpolate);
+        // Chrominance vectors are half the luminance vectors, rounded
+        // towards zero.
+        self.process_macroblock(&s.cb, 1, motion_h / 2, motion_v / 2, interpolate);
+        self.process_macroblock(&s.cr, 2, motion_h / 2, motion_v / 2, interpolate);
+    }
 
-                n += run;
+    /// Copy the half-pel displaced source block of plane `plane_index`
+    /// (0 = Y, 1 = Cb, 2 = Cr) into the current macroblock. With
+    /// `interpolate`, the prediction is averaged with the block's
+    /// current content instead.
+    fn process_macroblock(
+        &mut self,
+        s: &Plane,
+        plane_index: usize,
+        motion_h: i32,
+        motion_v: i32,
+        interpolate: bool,
+    ) {
+        let d = match plane_index {
+            1 => &mut self.frame.cb,
+            2 => &mut self.frame.cr,
+            _ => &mut self.frame.y,
+        };
+        let block_size: i32 = if plane_index == 0 { 16 } else { 8 };
+        let dw = i32::from(d.width);
+
+        let hp = motion_h >> 1;
+        let vp = motion_v >> 1;
+        let odd_h = (motion_h & 1) == 1;
+        let odd_v = (motion_v & 1) == 1;
+
+        let sx = self.mb_col * block_size + hp;
+        let sy = self.mb_row * block_size + vp;
+
+        // Vectors pointing outside the reference frame are not
+        // allowed and indicate a corrupt stream.
+        if sx < 0
+            || sy < 0
+            || sx + block_size + i32::from(odd_h) > i32::from(s.width)
+            || sy + block_size + i32::from(odd_v) > i32::from(s.height)
+            || s.width != d.width
+        {
+            return;
+        }
 
-                if n >= 64 {
-                    panic!();
+        let si = usize::try_from(sy * dw + sx).unwrap();
+        let di = usize::try_from((self.mb_row * dw + self.mb_col) * block_size).unwrap();
+        let dw = usize::try_from(dw).unwrap();
+        let s = &s.data;
+
+        let predict = |si: usize| -> u32 {
+            match (odd_h, odd_v) {
+                (false, false) => u32::from(s[si]),
+                (false, true) => (u32::from(s[si]) + u32::from(s[si + dw]) + 1) >> 1,
+                (true, false) => (u32::from(s[si]) + u32::from(s[si + 1]) + 1) >> 1,
+                (true, true) => {
+                    (u32::from(s[si])
+                        + u32::from(s[si + 1])
+                        + u32::from(s[si + dw])
+                        + u32::from(s[si + dw + 1])
+                        + 2)
+                        >> 2
                 }
+            }
+        };
+
+        let block_size = usize::try_from(block_size).unwrap();
+        if interpolate {
+            block_set(&mut d.data, di, dw, si, dw, block_size, |si, dv| {
+                ((u32::from(dv) + predict(si) + 1) >> 1) as u8
+            });
+        } else {
+            block_set(&mut d.data, di, dw, si, dw, block_size, |si, _| {
+                predict(si) as u8
+            });
+        }
+    }
 
-                let de_zig_zagged = VIDEO_ZIG_ZAG[usize::from(n)];
-                n += 1;
+    fn decode_block<T: Read>(
+        &mut self,
+        bs: &mut MyBitReader<T>,
+        i: usize,
+    ) -> Result<(), DecodeError> {
+        let intra = (self.macroblock_type & MACROBLOCK_INTRA) != 0;
+        let mut block_data = [0i32; 64];
+        let mut n = 0;
+
+        let quant_matrix = if intra {
+            block_data[0] = self.decode_dc(bs, i)? << (3 + 5);
+
+            // The DC coefficient of intra blocks is coded separately.
+            n = 1;
+            self.intra_quant_matrix
+        } else {
+            self.non_intra_quant_matrix
+        };
 
-                level <<= 1;
+        loop {
+            let mut level;
+            let run;
 
-                if macro_type & 0b1_0000 == 0 {
-                    level += if level < 0 { -1 } else { 1 };
-                }
+            let coeff = read_huffman(&VIDEO_DCT_COEFF, bs)?;
 
-                level = (level
-                    * i32::from(self.quantizer_scale)
-                    * i32::from(VIDEO_INTRA_QUANT_MATRIX[usize::from(de_zig_zagged)]))
-                    >> 4;
+            if (coeff == 0x0001) && (n > 0) && (bs.read::<u8>(1)? == 0) {
+                break;
+            }
 
-                if (level & 1) == 0 {
-                    level -= if level > 0 { 1 } else { -1 };
+            if coeff == 0xffff {
+                run = bs.read::<u8>(6)?;
+                level = i32::from(bs.read::<u8>(8)?);
+                if level == 0 {
+                    level = i32::from(bs.read::<u8>(8)?);
+                } else if level == 128 {
+                    level = i32::from(bs.read::<u8>(8)?) - 256;
+                } else if level > 128 {
+                    level -= 256;
                 }
+            } else {
+                run = (coeff >> 8).try_into().unwrap();
+                level = (coeff & 0xff).into();
 
-                if level > 2047 {
-                    level = 2047;
-                } else if level < -2048 {
-                    level = -2048;
+                if bs.read::<u8>(1)? == 1 {
+                    level = -level;
                 }
+            }
 
-                block_data[usize::from(de_zig_zagged)] =
-                    level * VIDEO_PREMULTIPLIER_MATRIX[usize::from(de_zig_zagged)];
+            n += run;
+
+            if n >= 64 {
+                return Err(DecodeError::CoefficientOverflow);
             }
 
-            if log::log_enabled!(target: "Global", log::Level::Trace) {
-                let mut block_str = "".to_string();
-                for i in 0..64 {
-                    write!(block_str, "{} ", block_data[i]).ok()?;
-                }
-                trace!("{}", block_str);
+            let de_zig_zagged = usize::from(VIDEO_ZIG_ZAG[usize::from(n)]);
+            n += 1;
+
+            level <<= 1;
+
+            if !intra {
+                level += if level < 0 { -1 } else { 1 };
             }
 
-            let mut d = match i {
-                4 => &mut self.frame.cb.data,
-                5 => &mut self.frame.cr.data,
-                _ => &mut self.frame.y.data,
-            };
+            level =
+                (level * i32::from(self.quantizer_scale) * i32::from(quant_matrix[de_zig_zagged]))
+                    >> 4;
 
-            // dw ... destination width
-            let dw = if i < 4 {
-                self.frame.y.width
-            } else {
-                self.frame.cr.width
-            };
+            if (level & 1) == 0 {
+                level -= if level > 0 { 1 } else { -1 };
+            }
 
-            // di ... destination index
-            let mut di;
-            if i < 4 {
-                di = (self.mb_row * i32::from(self.frame.y.width) + self.mb_col) << 4;
-                if (i & 1) != 0 {
-                    di += 8;
-                }
-                if (i & 2) != 0 {
-                    di += i32::from(self.frame.y.width << 3);
-                }
+            level = level.clamp(-2048, 2047);
+
+            block_data[de_zig_zagged] = level * VIDEO_PREMULTIPLIER_MATRIX[de_zig_zagged];
+        }
+
+        if log::log_enabled!(target: "Global", log::Level::Trace) {
+            let block_str: Vec<String> = block_data.iter().map(|c| c.to_string()).collect();
+            trace!("{}", block_str.join(" "));
+        }
+
+        let (d, dw, di) = self.block_destination(i);
+
+        if n == 1 {
+            // Only the DC coefficient is set.
+            let value = (block_data[0] + 128) >> 8;
+            if intra {
+                let clamped = clamp(value);
+                block_set(d, di, dw, 0, 8, 8, |_, _| clamped);
             } else {
-                di = ((self.mb_row * i32::from(self.frame.y.width)) << 2) + (self.mb_col << 3);
-            }
-
-            if macro_type & 0b1_0000 != 0 {
-                if n == 1 {
-                    let clamped = clamp((block_data[0] + 128) >> 8);
-                    block_set(
-                        &mut d,
-                        di.try_into().unwrap(),
-                        dw.into(),
-                        // 0, 8, 8, &[clamped; 64]);
-                        0,
-                        8,
-                        8,
-                        |i| clamped,
-                    );
-                    block_data[0] = 0;
-                } else {
-                    plm_video_idct(&mut block_data);
-                    // let mut clamped = [0u8; 64];
-                    // for n in 0..64 {
-                    //     clamped[n] = clamp(block_data[n]);
-                    // }
-                    block_set(&mut d, di.try_into().unwrap(), dw.into(), 0, 8, 8, |i| {
-                        clamp(block_data[i])
-                    });
-                    // 0, 8, 8, &clamped);
-                }
+                block_set(d, di, dw, 0, 8, 8, |_, dv| clamp(i32::from(dv) + value));
+            }
+        } else {
+            plm_video_idct(&mut block_data);
+            if intra {
+                block_set(d, di, dw, 0, 8, 8, |si, _| clamp(block_data[si]));
             } else {
-                // Can only do I frames
-                unimplemented!("");
+                // Add the residual to the predicted macroblock.
+                block_set(d, di, dw, 0, 8, 8, |si, dv| {
+                    clamp(i32::from(dv) + block_data[si])
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decodes the differentially coded DC coefficient of block `i`
+    /// of an intra-coded macroblock.
+    fn decode_dc<T: Read>(
+        &mut self,
+        bs: &mut MyBitReader<T>,
+        i: usize,
+    ) -> Result<i32, DecodeError> {
+        let plane_index = if i < 4 { 0 } else { i - 3 };
+        let predictor = self.dc_predictor[plane_index];
+
+        let table = if i < 4 {
+            VIDEO_DCT_SIZE_LUMINANCE
+        } else {
+            VIDEO_DCT_SIZE_CHROMINANCE
+        };
+
+        let dct_size: u8 = parse_dct_dc_size(&table, bs)?;
+        trace!(
+            "block={}, dct_size={}, predictor={}",
+            i,
+            dct_size,
+            predictor
+        );
+
+        let dc = if dct_size > 0 {
+            let dc_diff_coded = bs.read::<u8>(dct_size.into())?;
+            let dc_diff_decoded = decode_dc_diff(dc_diff_coded, dct_size);
+            trace!(
+                "block={}, dct_diff={}, decoded_diff={}",
+                i,
+                dc_diff_coded,
+                dc_diff_decoded
+            );
+            predictor + i32::from(dc_diff_decoded)
+        } else {
+            predictor
+        };
+
+        self.dc_predictor[plane_index] = dc;
+        Ok(dc)
+    }
+
+    /// Decodes a macroblock of a D-picture. Each block only carries a
+    /// DC coefficient and the macroblock is terminated by an
+    /// end_of_macroblock bit.
+    fn parse_dc_macroblock<T: Read>(&mut self, bs: &mut MyBitReader<T>) -> Result<(), DecodeError> {
+        for i in 0..6 {
+            // Without AC coefficients, the IDCT yields the DC value
+            // for every pixel.
+            let value = clamp(self.decode_dc(bs, i)?);
+            let (d, dw, di) = self.block_destination(i);
+            block_set(d, di, dw, 0, 8, 8, |_, _| value);
+        }
+
+        // end_of_macroblock is a one bit code, "1".
+        if bs.read::<u8>(1)? != 1 {
+            trace!("missing end_of_macroblock");
+            return Err(DecodeError::InvalidVlc);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the plane, its width and the index of the top-left
+    /// pixel of block `i` of the current macroblock.
+    fn block_destination(&mut self, i: usize) -> (&mut [u8], usize, usize) {
+        let luma_width = i32::from(self.frame.y.width);
+
+        // di ... destination index
+        let mut di;
+        if i < 4 {
+            di = (self.mb_row * luma_width + self.mb_col) << 4;
+            if (i & 1) != 0 {
+                di += 8;
             }
+            if (i & 2) != 0 {
+                di += luma_width << 3;
+            }
+        } else {
+            di = ((self.mb_row * luma_width) << 2) + (self.mb_col << 3);
+        }
+        let di = usize::try_from(di).unwrap();
+
+        match i {
+            4 => (&mut self.frame.cb.data, self.frame.cb.width.into(), di),
+            5 => (&mut self.frame.cr.data, self.frame.cr.width.into(), di),
+            _ => (&mut self.frame.y.data, self.frame.y.width.into(), di),
         }
+    }
+}
 
-        Some(())
+fn decode_motion_vector<T: Read>(
+    bs: &mut MyBitReader<T>,
+    r_size: u8,
+    motion: i32,
+) -> Result<i32, DecodeError> {
+    let fscale = 1i32 << r_size;
+    let m_code = i32::from(read_huffman(&VIDEO_MOTION, bs)?);
+
+    let d = if m_code != 0 && fscale != 1 {
+        let r = bs.read::<i32>(r_size.into())?;
+        let d = ((m_code.abs() - 1) << r_size) + r + 1;
+        if m_code < 0 {
+            -d
+        } else {
+            d
+        }
+    } else {
+        m_code
+    };
+
+    // Wrap the vector into the range [-16 * fscale, 16 * fscale - 1].
+    let mut motion = motion + d;
+    if motion > (fscale << 4) - 1 {
+        motion -= fscale << 5;
+    } else if motion < -(fscale << 4) {
+        motion += fscale << 5;
     }
+
+    Ok(motion)
 }
 
 /**
@@ -1143,42 +2192,6 @@ fn advance_to_next_start_code<F: Read + Seek>(f: &mut BufReader<F>) -> io::Resul
     }
 }
 
-/**
- * @param b: buffer with RGB pixel values
- */
-fn write_ppm<W: Write>(frame: &Frame, writer: &mut W) -> io::Result<()> {
-    let start = Instant::now();
-
-    write!(writer, "P3\n")?;
-    write!(writer, "{} {}\n", frame.width, frame.height)?;
-    write!(writer, "255\n")?;
-
-    let b = frame.to_rgb();
-
-    for row in 0..frame.height {
-        // let slice_start = usize::try_from(row * width * 3).unwrap();
-        // let slice_end   = usize::try_from((row+1) * width * 3).unwrap();
-        // let slice = &b[slice_start..slice_end];
-        // let s = slice.iter().map(|val| format!("{}", val)).collect::<Vec<String>>().join(" ");
-
-        for col in 0..frame.width {
-            let idx =
-                usize::try_from(((i32::from(row) * i32::from(frame.width)) + i32::from(col)) * 3)
-                    .unwrap();
-            write!(writer, "{} {} {} ", b[idx], b[idx + 1], b[idx + 2])?;
-        }
-        write!(writer, "\n")?;
-    }
-
-    let duration = start.elapsed();
-
-    if PROFILE {
-        println!("write_ppm() took {:?}", duration);
-    }
-
-    Ok(())
-}
-
 // MyBitReader<'a, T: std::io::Read> = bitstream_io::BitReader<&'a mut std::io::BufReader<T>, bitstream_io::BigEndian>;
 
 /**
@@ -1199,6 +2212,8 @@ fn next_start_code<T: Read + Seek>(r: &mut std::io::BufReader<T>) -> io::Result<
     }
 }
 
+// The `0 * 8 + i` style indexing mirrors the reference implementation.
+#[allow(clippy::identity_op, clippy::erasing_op)]
 fn plm_video_idct(block: &mut [i32; 64]) {
     let [mut b1, mut b3, mut b4, mut b6, mut b7, mut tmp1, mut tmp2, mut m0, mut x0, mut x1, mut x2, mut x3, mut x4, mut y3, mut y4, mut y5, mut y6, mut y7]: [i32; 18];
 
@@ -1263,166 +2278,653 @@ fn plm_video_idct(block: &mut [i32; 64]) {
     }
 }
 
-pub struct MpegDecoder {
+/// Maximum number of pictures waiting for their turn to be
+/// displayed. Conforming streams never need more than one reference
+/// picture to be held back.
+const MAX_REORDER_DEPTH: usize = 4;
+
+/// Reorders pictures from coding order into display order based on
+/// their temporal reference.
+struct ReorderBuffer {
+    pending: Vec<Arc<Frame>>,
+    // Temporal reference of the next picture to display.
+    next: u16,
+}
+
+impl ReorderBuffer {
+    fn new() -> Self {
+        ReorderBuffer {
+            pending: vec![],
+            next: 0,
+        }
+    }
+
+    fn push(&mut self, frame: Arc<Frame>) {
+        self.pending.push(frame);
+    }
+
+    /// Returns the next picture in display order, if it has been
+    /// decoded already.
+    fn pop(&mut self) -> Option<Arc<Frame>> {
+        let idx = match self
+            .pending
+            .iter()
+            .position(|f| f.temporal_reference == self.next)
+        {
+            Some(idx) => idx,
+            // Do not wait forever for a picture that got lost.
+            None if self.pending.len() > MAX_REORDER_DEPTH => self.lowest()?,
+            None => return None,
+        };
+        let frame = self.pending.remove(idx);
+        // Temporal references are 10 bit wide.
+        self.next = (frame.temporal_reference + 1) % 1024;
+        Some(frame)
+    }
+
+    /// Returns the remaining pictures in display order, regardless of
+    /// gaps. Used at the end of a group of pictures and the stream.
+    fn flush(&mut self) -> Option<Arc<Frame>> {
+        match self.lowest() {
+            Some(idx) => Some(self.pending.remove(idx)),
+            None => {
+                self.next = 0;
+                None
+            }
+        }
+    }
+
+    fn lowest(&self) -> Option<usize> {
+        self.pending
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, f)| f.temporal_reference)
+            .map(|(idx, _)| idx)
+    }
+}
+
+pub struct MpegDecoder<R: Read + Seek = MpegVideoStream> {
     pub stats: bool,
-    reader: BufReader<MpegVideoStream>,
+    reader: BufReader<R>,
     parse_picture_durations: Vec<std::time::Duration>,
+    // The two most recently decoded reference (I- or P-) pictures.
+    // P-pictures predict from `backward`, the newer one, B-pictures
+    // from both.
+    forward: Option<Arc<Frame>>,
+    backward: Option<Arc<Frame>>,
+    reorder: ReorderBuffer,
+    // Time stamps of the system layer. `None` if the source is not
+    // demultiplexed by the decoder.
+    timestamps: Option<Arc<Mutex<VideoTimestamps>>>,
+    // Temporal reference and PTS of the most recent picture with a
+    // PTS. The temporal reference counts from the start of the
+    // current group of pictures.
+    pts_anchor: Option<(i32, u64)>,
+    // Number of pictures of the current group of pictures so far.
+    group_len: i32,
+    // Most recent sequence header. Kept across calls to
+    // `parse_mpeg` as a seek may skip the one of the sequence.
+    sequence_header: Option<SequenceHeader>,
+    index: Option<SeekIndex>,
+    // Frames in display order not handed out yet.
+    ready: VecDeque<Arc<Frame>>,
+    // Whether pictures of the current group of pictures may follow.
+    in_group: bool,
 }
 
 impl MpegDecoder {
-    pub fn from_reader(reader: io::BufReader<MpegVideoStream>) -> io::Result<Self> {
-        Ok(Self {
-            stats: false,
-            parse_picture_durations: vec![],
-            reader,
-        })
+    pub fn new(path: &str) -> Result<Self, DecodeError> {
+        let f = OpenOptions::new().read(true).open(path)?;
+
+        Self::from_stream(f)
     }
+}
 
-    pub fn new(path: &str) -> io::Result<Self> {
+impl<S: Read> MpegDecoder<MpegVideoStream<S>> {
+    /// Decodes the video of an ISO 11172-1 system stream or of a
+    /// video elementary stream, depending on what `source` starts
+    /// with. The source does not need to be seekable.
+    pub fn from_stream(source: S) -> Result<Self, DecodeError> {
+        let stream = MpegVideoStream::new(source);
+        let timestamps = stream.timestamps();
+        let mut decoder = Self::from_reader(io::BufReader::new(stream))?;
+        decoder.timestamps = Some(timestamps);
+        Ok(decoder)
+    }
 
-        let mut f = OpenOptions::new()
-        .read(true)
-        .open(path)?;
+    /// Ids of the video streams listed in the system header. Empty
+    /// for video elementary streams.
+    pub fn video_stream_ids(&mut self) -> Result<Vec<u8>, DecodeError> {
+        Ok(self
+            .reader
+            .get_mut()
+            .system_header()?
+            .map(SystemHeader::video_stream_ids)
+            .unwrap_or_default())
+    }
 
-        Ok(MpegDecoder {
-            stats: false,
-            reader: io::BufReader::new(MpegVideoStream::new(&mut f)),
-            parse_picture_durations: vec![],
-        })
+    /// Selects the video stream to decode, see
+    /// `MpegVideoStream::select_video_stream`.
+    pub fn select_video_stream(&mut self, stream_id: u8) -> Result<(), DecodeError> {
+        self.reader.get_mut().select_video_stream(stream_id)
     }
+}
 
-    pub fn parse_mpeg<T: FrameProcessor>(
-        &mut self,
-        frame_handler: &mut T,
-    ) -> io::Result<()> {
+/// Decodes frames on demand, see `MpegDecoder::next_frame`.
+impl<R: Read + Seek> Iterator for MpegDecoder<R> {
+    type Item = Result<Frame, DecodeError>;
 
-        let mut buf: [u8; 4] = [0; 4];
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
 
-        let mut seqhdr: Option<SequenceHeader> = None;
+/// Seeks stop bisecting once the remaining range is this short and
+/// look at each group of pictures in it instead.
+const SEEK_SCAN_DISTANCE: u64 = 256 * 1024;
 
-        loop {
-            match self.reader.read_exact(&mut buf) {
-                Ok(_) => {}
-                Err(e) => match e.kind() {
-                    std::io::ErrorKind::UnexpectedEof => break,
-                    _ => return Err(e),
-                },
+impl<S: Read + Seek> MpegDecoder<MpegVideoStream<S>> {
+    /// Moves to the I-picture presented `time` after the first one,
+    /// or the closest one before it. Decoding resumes there with the
+    /// next call to `parse_mpeg`. Pictures are located by their PTS
+    /// in system streams and by their time code otherwise.
+    ///
+    /// Without an index, see `set_index`, the source is searched by
+    /// bisection for the I-pictures that start a group of pictures.
+    /// Either way it needs to be seekable. After an error the
+    /// position of the decoder is undefined.
+    pub fn seek_to_time(&mut self, time: Duration) -> Result<(), DecodeError> {
+        self.discard_buffer()?;
+        let target = ((time.as_nanos() * 9 + 50_000) / 100_000) as u64;
+
+        let found = match &self.index {
+            Some(index) => index.find(target).map(|e| (*e, index.time(e))),
+            None => self.search_group(target)?,
+        };
+        match found {
+            Some((entry, time)) => self.jump_to_key_frame(&entry, time),
+            None => self.jump(0, None),
+        }
+    }
+
+    /// Moves to key frame `n` of the index, i.e., its `n`th
+    /// I-picture. See `seek_to_time`.
+    pub fn seek_to_key_frame(&mut self, n: usize) -> Result<(), DecodeError> {
+        let (entry, time) = match &self.index {
+            Some(index) if n < index.entries().len() => {
+                let entry = index.entries()[n];
+                (entry, index.time(&entry))
             }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("no key frame {} in the index", n),
+                )
+                .into())
+            }
+        };
+        self.discard_buffer()?;
+        self.jump_to_key_frame(&entry, time)
+    }
 
-            if is_start_code(&buf, SEQUENCE_HEADER_START_VALUE) {
-                trace!(
-                    "Sequence start code at offset {}.",
-                    self.reader.stream_position().unwrap() - 4
-                );
-
-                seqhdr = Some(SequenceHeader::new(&mut self.reader));
-
-                trace!("width: {}", seqhdr.as_ref().unwrap().hsize());
-                trace!("height: {}", seqhdr.as_ref().unwrap().vsize());
-                trace!(
-                    "aspect ratio: {}",
-                    seqhdr.as_ref().unwrap().aspect_ratio_str()
-                );
-                trace!("frame rate: {}", seqhdr.as_ref().unwrap().frame_rate());
-            } else if is_start_code(&buf, GROUP_OF_PICTURES_START_VALUE) {
-                trace!(
-                    "Group of Pictures start code at offset {}.",
-                    self.reader.stream_position().unwrap() - 4
-                );
-
-                let mut count = 0;
-                let hdr = GroupOfPictures::new(&mut self.reader).unwrap();
-
-                trace!(
-                    "hour: {} minute: {} sec: {} frame: {}",
-                    hdr.hour(),
-                    hdr.min(),
-                    hdr.sec(),
-                    hdr.frame()
-                );
-
-                loop {
-                    count += 1;
-
-                    let start = Instant::now();
-
-                    match self.parse_picture(&seqhdr.as_ref().unwrap()) {
-                        Err(e) => match e.kind() {
-                            std::io::ErrorKind::UnexpectedEof => break,
-                            _ => return Err(e),
-                        },
-
-                        Ok(frame) => {
-                            if frame.width > 0 && frame.height > 0 {
-                                if self.stats {
-                                    self.parse_picture_durations.push(start.elapsed());
-                                }
-
-                                frame_handler.process(&frame);
-                            }
-                            ()
-                        }
-                    }
+    /// Records the position of every I-picture of the stream. Reads
+    /// the whole source once and leaves the decoder at its start.
+    pub fn build_index(&mut self) -> Result<SeekIndex, DecodeError> {
+        self.discard_buffer()?;
+        let frame_rate = self.find_sequence_header()?.frame_rate();
+
+        let mut index = SeekIndex::default();
+        let mut group = None;
+        let mut from = 0;
+        while let Some(key_frame) = self.reader.get_mut().find_key_frame(from, false)? {
+            group = key_frame.group.or(group);
+            // Pictures before the first group cannot be timed.
+            if let Some(group) = &group {
+                index.push(key_frame.entry(group, frame_rate));
+            }
+            from = key_frame.next;
+        }
+        self.jump(0, None)?;
+        Ok(index)
+    }
 
-                    self.reader.read_exact(&mut buf)?;
-                    self.reader.seek_relative(-4)?;
+    /// Uses `index` for seeking instead of searching the source.
+    pub fn set_index(&mut self, index: SeekIndex) {
+        self.index = Some(index);
+    }
 
-                    if !is_start_code(&buf, PICTURE_START_VALUE) {
-                        break;
-                    }
-                }
-                trace!("{} pictures in group.", count);
-            } else {
-                self.reader.seek_relative(-3)?;
+    /// Finds the last group that starts at or before `target`,
+    /// relative to the first group, together with its time.
+    fn search_group(&mut self, target: u64) -> Result<Option<(IndexEntry, u64)>, DecodeError> {
+        let frame_rate = self.find_sequence_header()?.frame_rate();
+        let stream = self.reader.get_mut();
+        let mut best = match stream.find_group(0)? {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+        let entry =
+            |(key_frame, group): &(KeyFrame, GroupOfPictures)| key_frame.entry(group, frame_rate);
+        let first = entry(&best);
+        let key = |group: &(KeyFrame, GroupOfPictures)| group_time(&first, &entry(group));
+
+        let mut end = stream.source_len()?;
+        while end.saturating_sub(best.0.next) > SEEK_SCAN_DISTANCE {
+            let mid = best.0.next + (end - best.0.next) / 2;
+            match stream.find_group(mid)? {
+                Some(group) if key(&group) <= target => best = group,
+                _ => end = mid,
             }
         }
+        while let Some(group) = stream.find_group(best.0.next)? {
+            if key(&group) > target {
+                break;
+            }
+            best = group;
+        }
+        Ok(Some((entry(&best), key(&best))))
+    }
 
-        if self.stats {
-            self.parse_picture_durations.sort();
-            println!(
-                "len={},min={:?},p50={:?},p95={:?},p99={:?},max={:?}",
-                self.parse_picture_durations.len(),
-                self.parse_picture_durations[0],
-                self.parse_picture_durations[self.parse_picture_durations.len() / 2],
-                self.parse_picture_durations[(self.parse_picture_durations.len() * 95) / 100],
-                self.parse_picture_durations[(self.parse_picture_durations.len() * 99) / 100],
-                self.parse_picture_durations[self.parse_picture_durations.len() - 1]
-            );
+    /// Continues decoding at the I-picture `entry` that is presented
+    /// `time` after the first one.
+    fn jump_to_key_frame(&mut self, entry: &IndexEntry, time: u64) -> Result<(), DecodeError> {
+        // Pictures from an index may be the first thing a fresh
+        // decoder sees. They need the sequence header.
+        self.find_sequence_header()?;
+        // Without PTS, pictures are timed relative to this one.
+        let anchor = match entry.pts {
+            Some(_) => None,
+            None => Some((i32::from(entry.temporal_reference), time)),
         };
+        self.jump(entry.offset, anchor)?;
+        if !entry.group_start {
+            self.resume_group()?;
+        }
         Ok(())
     }
 
-    pub fn parse_picture(
-        &mut self,
-        seqhdr: &SequenceHeader,
-    ) -> io::Result<Frame> {
-        let mut buf: [u8; 4] = [0; 4];
-
-        self.reader.read_exact(&mut buf)?;
-        assert!(is_start_code(&buf, PICTURE_START_VALUE));
+    /// Continues in the middle of a group of pictures, at the first
+    /// I-picture from the read position on. The B-pictures that follow
+    /// it but come before it in display order cannot be decoded and are
+    /// dropped, as at the start of an open group.
+    fn resume_group(&mut self) -> Result<(), DecodeError> {
+        loop {
+            if next_start_code(&mut self.reader)? == PICTURE_START_VALUE {
+                let mut buf = [0; 6];
+                self.reader.read_exact(&mut buf)?;
+                self.reader.seek_relative(-6)?;
+                let header = PictureHeader::from_prefix([buf[4], buf[5]]);
+                if header.frame_type() == FRAME_TYPE_I {
+                    self.in_group = true;
+                    return Ok(());
+                }
+            }
+            self.reader.seek_relative(4)?;
+        }
+    }
 
-        trace!(
-            "Picture start code at offset {}.",
-            self.reader.stream_position().unwrap() - 4
-        );
+    /// Moves to the I-picture presented at or before picture `n`,
+    /// counted in display order at the frame rate of the sequence.
+    /// See `seek_to_time`.
+    pub fn seek_to_frame(&mut self, n: u64) -> Result<(), DecodeError> {
+        let frame_rate = self.find_sequence_header()?.frame_rate();
+        if frame_rate <= 0.0 {
+            return Err(DecodeError::ForbiddenValue("frame rate"));
+        }
+        self.seek_to_time(Duration::from_secs_f64(n as f64 / f64::from(frame_rate)))
+    }
+
+    /// The sequence header, read from the start of the stream if
+    /// decoding has not come across it yet.
+    fn find_sequence_header(&mut self) -> Result<&SequenceHeader, DecodeError> {
+        if self.sequence_header.is_none() {
+            self.discard_buffer()?;
+            self.reader.get_mut().jump(0)?;
+            while next_start_code(&mut self.reader)? != SEQUENCE_HEADER_START_VALUE {
+                self.reader.seek_relative(4)?;
+            }
+            self.reader.seek_relative(4)?;
+            self.sequence_header = Some(SequenceHeader::new(&mut self.reader)?);
+            self.discard_buffer()?;
+        }
+        Ok(self.sequence_header.as_ref().unwrap())
+    }
+
+    /// Empties the read buffer before the stream moves. Must happen
+    /// while the buffered data can still be revisited.
+    fn discard_buffer(&mut self) -> Result<(), DecodeError> {
+        let pos = self.reader.stream_position()?;
+        self.reader.seek(SeekFrom::Start(pos))?;
+        Ok(())
+    }
+
+    /// Continues decoding at `offset` of the source with no reference
+    /// pictures.
+    fn jump(&mut self, offset: u64, pts_anchor: Option<(i32, u64)>) -> Result<(), DecodeError> {
+        self.reader.get_mut().jump(offset)?;
+        self.forward = None;
+        self.backward = None;
+        self.reorder = ReorderBuffer::new();
+        self.ready.clear();
+        self.in_group = false;
+        self.pts_anchor = pts_anchor;
+        self.group_len = 0;
+        Ok(())
+    }
+}
+
+impl<S: Read> MpegDecoder<Lookahead<S>> {
+    /// Decodes a video elementary stream from a source that cannot
+    /// seek, e.g., a pipe. The decoder works on a bounded lookahead
+    /// buffer instead.
+    pub fn from_unseekable(source: S) -> Result<Self, DecodeError> {
+        Self::from_reader(io::BufReader::new(Lookahead::new(source)))
+    }
+}
+
+impl<R: Read + Seek> MpegDecoder<R> {
+    pub fn from_reader(reader: io::BufReader<R>) -> Result<Self, DecodeError> {
+        Ok(Self {
+            stats: false,
+            parse_picture_durations: vec![],
+            reader,
+            forward: None,
+            backward: None,
+            reorder: ReorderBuffer::new(),
+            timestamps: None,
+            pts_anchor: None,
+            group_len: 0,
+            sequence_header: None,
+            index: None,
+            ready: VecDeque::new(),
+            in_group: false,
+        })
+    }
+
+    /// Decodes the stream to the end and hands every frame in
+    /// display order to `frame_handler`.
+    pub fn parse_mpeg<T: FrameProcessor>(
+        &mut self,
+        frame_handler: &mut T,
+    ) -> Result<(), DecodeError> {
+        while let Some(frame) = self.next_shared_frame()? {
+            frame_handler.process(&frame);
+        }
+
+        if self.stats {
+            self.parse_picture_durations.sort();
+            println!(
+                "len={},min={:?},p50={:?},p95={:?},p99={:?},max={:?}",
+                self.parse_picture_durations.len(),
+                self.parse_picture_durations[0],
+                self.parse_picture_durations[self.parse_picture_durations.len() / 2],
+                self.parse_picture_durations[(self.parse_picture_durations.len() * 95) / 100],
+                self.parse_picture_durations[(self.parse_picture_durations.len() * 99) / 100],
+                self.parse_picture_durations[self.parse_picture_durations.len() - 1]
+            );
+        };
+        Ok(())
+    }
+
+    /// Decodes up to the next frame in display order. Returns `None`
+    /// at the end of the stream. Pictures that could not be decoded
+    /// are skipped.
+    ///
+    /// I- and P-pictures are still held by the decoder to predict
+    /// from, so they are copied. Use `next_shared_frame` to avoid the
+    /// copy.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, DecodeError> {
+        Ok(self
+            .next_shared_frame()?
+            .map(|frame| Arc::try_unwrap(frame).unwrap_or_else(|frame| (*frame).clone())))
+    }
+
+    /// Same as `next_frame`, but returns reference pictures shared
+    /// with the decoder instead of copying them.
+    pub fn next_shared_frame(&mut self) -> Result<Option<Arc<Frame>>, DecodeError> {
+        loop {
+            if let Some(frame) = self.ready.pop_front() {
+                return Ok(Some(frame));
+            }
+            if !self.step()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Reads the next picture of the current group of pictures or the
+    /// next start code. Frames that become due are queued in `ready`.
+    /// Returns false at the end of the stream.
+    fn step(&mut self) -> Result<bool, DecodeError> {
+        let mut buf: [u8; 4] = [0; 4];
+
+        if self.in_group {
+            match self.reader.read_exact(&mut buf) {
+                Ok(_) => self.reader.seek_relative(-4)?,
+                Err(e) => match e.kind() {
+                    std::io::ErrorKind::UnexpectedEof => {}
+                    _ => return Err(e.into()),
+                },
+            }
+            // The group ends at the first other start code. Its
+            // header is read by the next step.
+            if is_start_code(&buf, PICTURE_START_VALUE) {
+                self.in_group = self.decode_picture()?;
+            } else {
+                trace!("{} pictures in group.", self.group_len);
+                self.in_group = false;
+            }
+            return Ok(true);
+        }
+
+        match self.reader.read_exact(&mut buf) {
+            Ok(_) => {}
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::UnexpectedEof => {
+                    while let Some(frame) = self.reorder.flush() {
+                        self.output(frame);
+                    }
+                    return Ok(false);
+                }
+                _ => return Err(e.into()),
+            },
+        }
+
+        if is_start_code(&buf, SEQUENCE_HEADER_START_VALUE) {
+            trace!(
+                "Sequence start code at offset {}.",
+                self.reader.stream_position()? - 4
+            );
+
+            let hdr = SequenceHeader::new(&mut self.reader)?;
+
+            trace!("width: {}", hdr.hsize());
+            trace!("height: {}", hdr.vsize());
+            trace!("aspect ratio: {}", hdr.aspect_ratio_str());
+            trace!("frame rate: {}", hdr.frame_rate());
+
+            self.sequence_header = Some(hdr);
+        } else if is_start_code(&buf, START_EXTENSION) && self.sequence_header.is_some() {
+            // MPEG-1 decoders discard extension data. An MPEG-2
+            // stream, however, always carries a sequence
+            // extension that changes the meaning of everything
+            // that follows. It is the extension with identifier 1.
+            let mut id = [0; 1];
+            self.reader.read_exact(&mut id)?;
+            if (id[0] >> 4) == SEQUENCE_EXTENSION_ID {
+                return Err(DecodeError::Unsupported("MPEG-2 video"));
+            }
+            self.reader.seek_relative(-1)?;
+        } else if is_start_code(&buf, GROUP_OF_PICTURES_START_VALUE) {
+            trace!(
+                "Group of Pictures start code at offset {}.",
+                self.reader.stream_position()? - 4
+            );
+
+            if self.sequence_header.is_none() {
+                return Err(DecodeError::UnexpectedStartCode(
+                    GROUP_OF_PICTURES_START_VALUE,
+                ));
+            }
+
+            // Pictures of the previous group are all due now.
+            while let Some(frame) = self.reorder.flush() {
+                self.output(frame);
+            }
 
-        let hdr = PictureHeader::new(&mut self.reader).unwrap();
+            let hdr = GroupOfPictures::new(&mut self.reader)?;
+
+            // Temporal references start over.
+            if let Some((temporal_reference, pts)) = self.pts_anchor {
+                self.pts_anchor = Some((temporal_reference - self.group_len, pts));
+            }
+            self.group_len = 0;
+
+            trace!(
+                "hour: {} minute: {} sec: {} frame: {}",
+                hdr.hour(),
+                hdr.min(),
+                hdr.sec(),
+                hdr.frame()
+            );
+
+            // A picture has to follow, possibly after extension and
+            // user data.
+            match self.skip_extension_data() {
+                Err(e) if e.kind() != io::ErrorKind::UnexpectedEof => return Err(e.into()),
+                _ => self.in_group = self.decode_picture()?,
+            }
+        } else {
+            self.reader.seek_relative(-3)?;
+        }
+        Ok(true)
+    }
+
+    /// Moves past extension and user data to the next other start
+    /// code.
+    fn skip_extension_data(&mut self) -> io::Result<()> {
+        let mut start_code = next_start_code(&mut self.reader)?;
+        while start_code == START_EXTENSION || start_code == START_USER_DATA {
+            self.reader.seek_relative(4)?;
+            start_code = next_start_code(&mut self.reader)?;
+        }
+        Ok(())
+    }
+
+    /// Decodes the picture at the read position. Returns false if the
+    /// stream ended.
+    fn decode_picture(&mut self) -> Result<bool, DecodeError> {
+        let seqhdr = self
+            .sequence_header
+            .clone()
+            .ok_or(DecodeError::UnexpectedStartCode(PICTURE_START_VALUE))?;
+        let start = Instant::now();
+
+        let frame = match self.parse_picture(&seqhdr) {
+            Err(e) if e.is_eof() => return Ok(false),
+            Err(e) => return Err(e),
+            Ok(frame) => Arc::new(frame),
+        };
+
+        if frame.width > 0 && frame.height > 0 {
+            if self.stats {
+                self.parse_picture_durations.push(start.elapsed());
+            }
+
+            if frame.picture_type == FRAME_TYPE_I || frame.picture_type == FRAME_TYPE_P {
+                self.forward = self.backward.take();
+                self.backward = Some(Arc::clone(&frame));
+            }
+        }
+
+        self.reorder.push(frame);
+        while let Some(frame) = self.reorder.pop() {
+            self.output(frame);
+        }
+        Ok(true)
+    }
+
+    /// PTS of a picture. Pictures without a PTS in the system layer
+    /// are timed relative to the most recent one that had a PTS, by
+    /// their temporal reference, modulo `TIMESTAMP_WRAP` like the
+    /// time stamps of the system layer.
+    fn presentation_time_stamp(
+        &mut self,
+        temporal_reference: u16,
+        pts: Option<u64>,
+        frame_rate: f32,
+    ) -> u64 {
+        let temporal_reference = i32::from(temporal_reference);
+        self.group_len = self.group_len.max(temporal_reference + 1);
+
+        if let Some(pts) = pts {
+            self.pts_anchor = Some((temporal_reference, pts));
+            return pts;
+        }
+
+        let (anchor_temporal_reference, anchor_pts) = *self.pts_anchor.get_or_insert((0, 0));
+        if frame_rate <= 0.0 {
+            return anchor_pts;
+        }
+        let ticks = f64::from(temporal_reference - anchor_temporal_reference) * 90000.0
+            / f64::from(frame_rate);
+        (anchor_pts as i64 + ticks.round() as i64).rem_euclid(TIMESTAMP_WRAP as i64) as u64
+    }
+
+    /// Queues a frame in display order. Pictures that could not be
+    /// decoded are dropped here.
+    fn output(&mut self, frame: Arc<Frame>) {
+        if frame.width > 0 && frame.height > 0 {
+            self.ready.push_back(frame);
+        }
+    }
+
+    pub fn parse_picture(&mut self, seqhdr: &SequenceHeader) -> Result<Frame, DecodeError> {
+        let mut buf: [u8; 4] = [0; 4];
+
+        self.reader.read_exact(&mut buf)?;
+        if !is_start_code(&buf, PICTURE_START_VALUE) {
+            return Err(DecodeError::UnexpectedStartCode(buf[3]));
+        }
+
+        let offset = self.reader.stream_position()? - 4;
+        trace!("Picture start code at offset {}.", offset);
+
+        let hdr = PictureHeader::new(&mut self.reader)?;
         trace!(
             "seq nr: {}, frame type: {}",
             hdr.sequence_nr(),
             hdr.frame_type()
         );
 
-        if hdr.frame_type() != FRAME_TYPE_I {
-            let frame_type = if hdr.frame_type() == FRAME_TYPE_I {
-                "I-frame"
-            } else {
-                "non-I-frame"
-            };
+        let timestamps = self
+            .timestamps
+            .as_ref()
+            .and_then(|t| t.lock().unwrap().take(offset));
+        let pts = self.presentation_time_stamp(
+            hdr.sequence_nr(),
+            timestamps.map(|(pts, _)| pts),
+            seqhdr.frame_rate(),
+        );
+
+        // P-pictures need a reference picture to predict from,
+        // B-pictures two. An f_code of zero is forbidden.
+        let decodable = match hdr.frame_type() {
+            FRAME_TYPE_I | FRAME_TYPE_D => true,
+            FRAME_TYPE_P => self.backward.is_some() && hdr.forward_f_code() != 0,
+            FRAME_TYPE_B => {
+                self.forward.is_some()
+                    && self.backward.is_some()
+                    && hdr.forward_f_code() != 0
+                    && hdr.backward_f_code() != 0
+            }
+            _ => false,
+        };
+
+        if !decodable {
             trace!(
-                "Skipping {} @ offset {}",
-                frame_type,
-                self.reader.stream_position().unwrap()
+                "Skipping frame of type {} @ offset {}",
+                hdr.frame_type(),
+                self.reader.stream_position()?
             );
 
             loop {
@@ -1434,10 +2936,7 @@ impl MpegDecoder {
                     // Somehow return and continue regular control
                     // flow in caller.  This is not an error but we
                     // also cannot return a valid frame.
-                    //
-                    // The control flow should be cleaner once we
-                    // support P frames too.
-                    return Ok(Frame::new_dummy());
+                    return Ok(Frame::new_dummy(hdr.sequence_nr()));
                 }
                 self.reader.seek_relative(4)?;
             }
@@ -1447,19 +2946,68 @@ impl MpegDecoder {
         let mut start_code = next_start_code(&mut self.reader)?;
         loop {
             if !(start_code == START_EXTENSION || start_code == START_USER_DATA) {
-                assert!(start_code >= 0x01 && start_code <= 0xAF);
+                if !(0x01..=0xAF).contains(&start_code) {
+                    return Err(DecodeError::UnexpectedStartCode(start_code));
+                }
                 // f.seek_relative(-4);
                 break;
             }
+            self.reader.seek_relative(4)?;
             start_code = next_start_code(&mut self.reader)?;
         }
 
         let mut container = Container::new(seqhdr.hsize(), seqhdr.vsize());
+        container.intra_quant_matrix = seqhdr.intra_quant_matrix;
+        container.non_intra_quant_matrix = seqhdr.non_intra_quant_matrix;
+        container.picture_type = hdr.frame_type();
+        container.frame.picture_type = hdr.frame_type();
+        container.frame.temporal_reference = hdr.sequence_nr();
+        container.frame.pts = pts;
+        container.frame.dts = timestamps.and_then(|(_, dts)| dts);
+
+        if hdr.frame_type() == FRAME_TYPE_P || hdr.frame_type() == FRAME_TYPE_B {
+            container.motion_forward =
+                MotionVector::new(hdr.full_pel_forward_vector(), hdr.forward_f_code());
+        }
+
+        match hdr.frame_type() {
+            // I- and D-pictures do not predict. The most recent
+            // reference picture is only used to conceal errors.
+            FRAME_TYPE_I | FRAME_TYPE_D | FRAME_TYPE_P => {
+                container.forward = self.backward.as_deref();
+            }
+            FRAME_TYPE_B => {
+                container.motion_backward =
+                    MotionVector::new(hdr.full_pel_backward_vector(), hdr.backward_f_code());
+                container.forward = self.forward.as_deref();
+                container.backward = self.backward.as_deref();
+            }
+            _ => {}
+        }
 
         loop {
-            container.parse_slice(&mut self.reader, start_code).unwrap();
+            if let Err(e) = container.parse_slice(&mut self.reader, start_code) {
+                match e {
+                    DecodeError::Io(ref io) if io.kind() != io::ErrorKind::UnexpectedEof => {
+                        return Err(e)
+                    }
+                    _ => trace!("slice {} damaged: {}", start_code, e),
+                }
 
-            self.reader.read_exact(&mut buf)?;
+                // Resynchronize at the next start code. The
+                // macroblocks lost in between are concealed below.
+                match advance_to_next_start_code(&mut self.reader) {
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    r => r?,
+                }
+            }
+
+            if let Err(e) = self.reader.read_exact(&mut buf) {
+                match e.kind() {
+                    io::ErrorKind::UnexpectedEof => break,
+                    _ => return Err(e.into()),
+                }
+            }
             self.reader.seek_relative(-4)?;
 
             if !is_slice_start_code(&buf) {
@@ -1468,6 +3016,8 @@ impl MpegDecoder {
             start_code = buf[3];
         }
 
+        container.conceal();
+
         trace!("frame.y={:x?}", &container.frame.y.data[0..16]);
         trace!(
             "frame.y={:x?}",
@@ -1476,7 +3026,7 @@ impl MpegDecoder {
         trace!("frame.cr={:x?}", &container.frame.cr.data[0..16]);
         trace!("frame.cb={:x?}", &container.frame.cb.data[0..16]);
 
-        return Ok(container.frame);
+        Ok(container.frame)
     }
 }
 
@@ -1491,15 +3041,13 @@ mod tests {
 
     #[test]
     fn end_to_end() {
-        let fns = vec![
+        let fns = [
             "tests/sample_960x400_ocean_with_audio.mpeg",
             "tests/bjork-all-is-full-of-love.mpg",
         ];
         for filename in fns.iter() {
             let mut decoder = MpegDecoder::new(filename).unwrap();
-            decoder
-                .parse_mpeg(&mut NoopFrameProcessor {})
-                .unwrap();
+            decoder.parse_mpeg(&mut NoopFrameProcessor {}).unwrap();
         }
     }
 
@@ -1517,7 +3065,7 @@ mod tests {
                0,   0,   0,   0,   0,   0, 0, 0,
                0,   0,   0,   0,   0,   0, 0, 0
         ];
-        let mut m2: [i32; 8 * 8] = m.clone();
+        let mut m2: [i32; 8 * 8] = m;
 
         idct_23002_2::idct_23002_2(&mut m);
 
@@ -1593,8 +3141,8 @@ mod tests {
         buf.extend(&[0, 0b0000_1000, 0, 0, 0, 0, 0, 0]);
         let cursor = io::Cursor::new(buf);
         let mut reader = io::BufReader::new(cursor);
-        let seqhdr = SequenceHeader::new(&mut reader);
-        let decoder = MpegDecoder::from_reader(reader).unwrap();
+        let seqhdr = SequenceHeader::new(&mut reader).unwrap();
+        let mut decoder = MpegDecoder::from_reader(reader).unwrap();
         decoder.parse_picture(&seqhdr).unwrap();
     }
 
@@ -1630,6 +3178,918 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sequence_header_quant_matrices() {
+        use bitstream_io::BitWrite;
+
+        let mut buf = Vec::new();
+        let mut bw = bitstream_io::BitWriter::endian(&mut buf, bitstream_io::BigEndian);
+        bw.write(12, 320u16).unwrap();
+        bw.write(12, 240u16).unwrap();
+        bw.write(4, 1u8).unwrap();
+        bw.write(4, 3u8).unwrap();
+        bw.write(18, 1150u32).unwrap();
+        bw.write_bit(true).unwrap();
+        bw.write(10, 20u16).unwrap();
+        bw.write_bit(false).unwrap();
+        // load_intra_quantizer_matrix
+        bw.write_bit(true).unwrap();
+        for i in 0..64u8 {
+            bw.write(8, i + 1).unwrap();
+        }
+        // load_non_intra_quantizer_matrix
+        bw.write_bit(true).unwrap();
+        for i in 0..64u8 {
+            bw.write(8, 255 - i).unwrap();
+        }
+        assert!(bw.byte_aligned());
+        buf.extend([0x00, 0x00, 0x01, 0xb8]);
+
+        let mut cursor = io::Cursor::new(&buf);
+        let hdr = SequenceHeader::new(&mut cursor).unwrap();
+        assert_eq!(hdr.hsize(), 320);
+        assert_eq!(hdr.vsize(), 240);
+        assert_eq!(cursor.position(), 8 + 128);
+        for (i, &zz) in VIDEO_ZIG_ZAG.iter().enumerate() {
+            assert_eq!(hdr.intra_quant_matrix[zz as usize], i as u8 + 1);
+            assert_eq!(hdr.non_intra_quant_matrix[zz as usize], 255 - i as u8);
+        }
+
+        // Without the load flags the default matrices apply and the
+        // header is exactly 8 bytes long.
+        let buf = [0x14, 0x00, 0xf0, 0x13, 0x00, 0x47, 0xe0, 0xa4, 0x00];
+        let mut cursor = io::Cursor::new(&buf);
+        let hdr = SequenceHeader::new(&mut cursor).unwrap();
+        assert_eq!(cursor.position(), 8);
+        assert_eq!(hdr.intra_quant_matrix, VIDEO_INTRA_QUANT_MATRIX);
+        assert_eq!(hdr.non_intra_quant_matrix, VIDEO_NON_INTRA_QUANT_MATRIX);
+
+        // A width or height of zero is forbidden.
+        for (i, field) in [(0, "horizontal_size"), (2, "vertical_size")] {
+            let mut buf = buf;
+            buf[i] = 0;
+            buf[1] = 0;
+            let mut cursor = io::Cursor::new(&buf);
+            assert!(matches!(
+                SequenceHeader::new(&mut cursor),
+                Err(DecodeError::ForbiddenValue(f)) if f == field
+            ));
+        }
+    }
+
+    #[test]
+    fn test_picture_header_p() {
+        // temporal reference 3, P-picture, full_pel_forward_vector and
+        // forward_f_code 5.
+        let buf = [0x00, 0b1101_0111, 0xff, 0b1111_1110, 0b1000_0000];
+        let mut cursor = io::Cursor::new(buf);
+        let hdr = PictureHeader::new(&mut cursor).unwrap();
+        assert_eq!(hdr.sequence_nr(), 3);
+        assert_eq!(hdr.frame_type(), FRAME_TYPE_P);
+        assert!(hdr.full_pel_forward_vector());
+        assert_eq!(hdr.forward_f_code(), 5);
+        assert_eq!(cursor.position(), 5);
+    }
+
+    #[test]
+    fn test_picture_header_b() {
+        // temporal reference 1, B-picture, forward_f_code 1 and
+        // full_pel_backward_vector with backward_f_code 7.
+        let buf = [0x00, 0b0101_1000, 0x00, 0b0000_0000, 0b1111_1000];
+        let mut cursor = io::Cursor::new(buf);
+        let hdr = PictureHeader::new(&mut cursor).unwrap();
+        assert_eq!(hdr.sequence_nr(), 1);
+        assert_eq!(hdr.frame_type(), FRAME_TYPE_B);
+        assert!(!hdr.full_pel_forward_vector());
+        assert_eq!(hdr.forward_f_code(), 1);
+        assert!(hdr.full_pel_backward_vector());
+        assert_eq!(hdr.backward_f_code(), 7);
+    }
+
+    #[test]
+    fn test_reorder_buffer() {
+        let mut reorder = ReorderBuffer::new();
+        let mut display_order = vec![];
+        // Coding order of an open group of pictures.
+        for temporal_reference in [2, 0, 1, 5, 3, 4, 6] {
+            reorder.push(Arc::new(Frame::new_dummy(temporal_reference)));
+            while let Some(frame) = reorder.pop() {
+                display_order.push(frame.temporal_reference);
+            }
+        }
+        assert_eq!(display_order, vec![0, 1, 2, 3, 4, 5, 6]);
+
+        // A lost picture stalls the output until the group ends.
+        reorder.push(Arc::new(Frame::new_dummy(9)));
+        assert!(reorder.pop().is_none());
+        assert_eq!(reorder.flush().unwrap().temporal_reference, 9);
+        assert!(reorder.flush().is_none());
+        assert_eq!(reorder.next, 0);
+    }
+
+    #[test]
+    fn test_parse_macroblock_type_predictive() {
+        // 1 01 001 00011 000001
+        let buf = [0b1010_0100, 0b0110_0000, 0b1000_0000];
+        let cursor = io::Cursor::new(buf);
+        let mut reader = io::BufReader::new(cursor);
+        let mut stream: bitstream_io::BitReader<_, bitstream_io::BigEndian> =
+            bitstream_io::BitReader::new(&mut reader);
+        let table = &VIDEO_MACROBLOCK_TYPE_PREDICTIVE;
+        assert_eq!(
+            parse_macroblock_type(table, &mut stream).unwrap(),
+            MACROBLOCK_MOTION_FORWARD | MACROBLOCK_PATTERN
+        );
+        assert_eq!(
+            parse_macroblock_type(table, &mut stream).unwrap(),
+            MACROBLOCK_PATTERN
+        );
+        assert_eq!(
+            parse_macroblock_type(table, &mut stream).unwrap(),
+            MACROBLOCK_MOTION_FORWARD
+        );
+        assert_eq!(
+            parse_macroblock_type(table, &mut stream).unwrap(),
+            MACROBLOCK_INTRA
+        );
+        assert_eq!(
+            parse_macroblock_type(table, &mut stream).unwrap(),
+            MACROBLOCK_QUANT | MACROBLOCK_INTRA
+        );
+    }
+
+    #[test]
+    fn test_code_block_pattern() {
+        // 111 (60), 0101 1 (1), 0000 0001 0 (39)
+        let buf = [0b1110_1011, 0b0000_0001, 0b0000_0000, 0b0000_0000];
+        let cursor = io::Cursor::new(buf);
+        let mut reader = io::BufReader::new(cursor);
+        let mut stream: bitstream_io::BitReader<_, bitstream_io::BigEndian> =
+            bitstream_io::BitReader::new(&mut reader);
+        assert_eq!(
+            read_huffman(&VIDEO_CODE_BLOCK_PATTERN, &mut stream).unwrap(),
+            60
+        );
+        assert_eq!(
+            read_huffman(&VIDEO_CODE_BLOCK_PATTERN, &mut stream).unwrap(),
+            1
+        );
+        assert_eq!(
+            read_huffman(&VIDEO_CODE_BLOCK_PATTERN, &mut stream).unwrap(),
+            39
+        );
+        // 0000 0000 is not a valid code in MPEG-1.
+        assert!(matches!(
+            read_huffman(&VIDEO_CODE_BLOCK_PATTERN, &mut stream),
+            Err(DecodeError::InvalidVlc)
+        ));
+    }
+
+    #[test]
+    fn test_decode_motion_vector() {
+        // 0010 1: motion code 2 with residual 1.
+        // 010: motion code 1.
+        let buf = [0b0010_1010];
+        let cursor = io::Cursor::new(buf);
+        let mut reader = io::BufReader::new(cursor);
+        let mut stream: bitstream_io::BitReader<_, bitstream_io::BigEndian> =
+            bitstream_io::BitReader::new(&mut reader);
+        assert_eq!(decode_motion_vector(&mut stream, 1, 0).unwrap(), 4);
+        // Vectors wrap around at 16 * f.
+        assert_eq!(decode_motion_vector(&mut stream, 0, 15).unwrap(), -16);
+    }
+
+    #[test]
+    fn test_parse_dc_macroblock() {
+        // address increment 1, type intra, luminance DC size 2 with
+        // diff 3, three luminance and two chrominance DC size 0,
+        // end_of_macroblock.
+        let buf = [0b1101_1110, 0b0100_1000, 0b0001_0000];
+        let cursor = io::Cursor::new(buf);
+        let mut reader = io::BufReader::new(cursor);
+        let mut stream: bitstream_io::BitReader<_, bitstream_io::BigEndian> =
+            bitstream_io::BitReader::new(&mut reader);
+        let mut c = Container::new(16, 16);
+        c.picture_type = FRAME_TYPE_D;
+        c.slice_begin = true;
+        c.parse_macroblock(&mut stream, 1).unwrap();
+        assert!(c.frame.y.data.iter().all(|&p| p == 131));
+        assert!(c.frame.cb.data.iter().all(|&p| p == 128));
+        assert!(c.frame.cr.data.iter().all(|&p| p == 128));
+    }
+
+    #[test]
+    fn test_coefficient_overflow() {
+        // Non-intra block with two escape coded coefficients: run
+        // 63, level 1. The second one lands beyond the 64th
+        // coefficient.
+        let buf = [
+            0b0000_0111,
+            0b1111_0000,
+            0b0001_0000,
+            0b0111_1111,
+            0b0000_0001,
+        ];
+        let cursor = io::Cursor::new(buf);
+        let mut reader = io::BufReader::new(cursor);
+        let mut stream: bitstream_io::BitReader<_, bitstream_io::BigEndian> =
+            bitstream_io::BitReader::new(&mut reader);
+        let mut c = Container::new(16, 16);
+        c.picture_type = FRAME_TYPE_P;
+        c.macroblock_type = MACROBLOCK_PATTERN;
+        assert!(matches!(
+            c.decode_block(&mut stream, 0),
+            Err(DecodeError::CoefficientOverflow)
+        ));
+    }
+
+    #[test]
+    fn test_iso11172_stream_errors() {
+        // Not a pack start code.
+        let mut cursor = io::Cursor::new([0x00, 0x00, 0x01, 0xb3]);
+        assert!(matches!(
+            iso11172_stream(&mut cursor, &mut Vec::new()),
+            Err(DecodeError::UnexpectedStartCode(
+                SEQUENCE_HEADER_START_VALUE
+            ))
+        ));
+
+        // A video packet announcing 16 bytes but carrying only 2.
+        let mut buf = vec![0x00, 0x00, 0x01, PACK_START_CODE];
+        buf.extend([0x21, 0x00, 0x01, 0x00, 0x01, 0x80, 0x00, 0x01]);
+        buf.extend([
+            0x00,
+            0x00,
+            0x01,
+            VIDEO_STREAM_0_START_CODE,
+            0x00,
+            0x10,
+            0x0f,
+            0x00,
+        ]);
+        let mut cursor = io::Cursor::new(buf);
+        assert!(matches!(
+            iso11172_stream(&mut cursor, &mut Vec::new()),
+            Err(DecodeError::TruncatedPacket)
+        ));
+    }
+
+    /// Sequence header of a 16x32 picture.
+    fn sequence_header_16x32() -> SequenceHeader {
+        SequenceHeader {
+            raw: [0x01, 0x00, 0x20, 0x13, 0xff, 0xff, 0xe0, 0x00],
+            intra_quant_matrix: VIDEO_INTRA_QUANT_MATRIX,
+            non_intra_quant_matrix: VIDEO_NON_INTRA_QUANT_MATRIX,
+        }
+    }
+
+    /// A 16x32 I-picture with two slices of one macroblock each. The
+    /// first slice has an invalid macroblock type, the second one is
+    /// intact with a luminance DC of 131.
+    fn damaged_picture() -> Vec<u8> {
+        let mut buf = vec![0x00, 0x00, 0x01, PICTURE_START_VALUE];
+        buf.extend([0x00, 0x08, 0x00, 0x00]);
+        buf.extend([0x00, 0x00, 0x01, 0x01, 0x0a, 0x00]);
+        buf.extend([0x00, 0x00, 0x01, 0x02, 0x0b, 0x7a, 0x52, 0x91, 0x10]);
+        buf.extend([0x00, 0x00, 0x01, 0xb7]);
+        buf
+    }
+
+    #[test]
+    fn test_conceal_damaged_slice() {
+        let buf = damaged_picture();
+        let seqhdr = sequence_header_16x32();
+
+        // Without a reference picture, the damaged macroblock is
+        // mid-grey.
+        let reader = io::BufReader::new(io::Cursor::new(buf.clone()));
+        let mut decoder = MpegDecoder::from_reader(reader).unwrap();
+        let frame = decoder.parse_picture(&seqhdr).unwrap();
+        assert_eq!(frame.concealed_macroblocks(), &[Range { start: 0, end: 1 }]);
+        assert!(frame.y.data[..16 * 16].iter().all(|&p| p == 128));
+        assert!(frame.y.data[16 * 16..].iter().all(|&p| p == 131));
+
+        // Otherwise, it is copied from the reference picture.
+        let mut reference = Frame::new(16, 32);
+        reference.y.data.fill(50);
+        let reader = io::BufReader::new(io::Cursor::new(buf));
+        let mut decoder = MpegDecoder::from_reader(reader).unwrap();
+        decoder.backward = Some(Arc::new(reference));
+        let frame = decoder.parse_picture(&seqhdr).unwrap();
+        assert_eq!(frame.concealed_macroblocks(), &[Range { start: 0, end: 1 }]);
+        assert!(frame.y.data[..16 * 16].iter().all(|&p| p == 50));
+        assert!(frame.y.data[16 * 16..].iter().all(|&p| p == 131));
+
+        // A reference picture of another size cannot stand in.
+        let mut reference = Frame::new(32, 32);
+        reference.y.data.fill(50);
+        let reader = io::BufReader::new(io::Cursor::new(damaged_picture()));
+        let mut decoder = MpegDecoder::from_reader(reader).unwrap();
+        decoder.backward = Some(Arc::new(reference));
+        let frame = decoder.parse_picture(&seqhdr).unwrap();
+        assert!(frame.y.data[..16 * 16].iter().all(|&p| p == 128));
+    }
+
+    #[test]
+    fn test_unseekable_source() {
+        let buf = damaged_picture();
+        let mut decoder = MpegDecoder::from_unseekable(&buf[..]).unwrap();
+        let frame = decoder.parse_picture(&sequence_header_16x32()).unwrap();
+        assert_eq!(frame.concealed_macroblocks(), &[Range { start: 0, end: 1 }]);
+        assert!(frame.y.data[16 * 16..].iter().all(|&p| p == 131));
+
+        // Streams have to start with a sequence header or a pack.
+        let mut decoder = MpegDecoder::from_stream(&buf[..]).unwrap();
+        assert!(matches!(
+            decoder.parse_picture(&sequence_header_16x32()),
+            Err(DecodeError::UnexpectedStartCode(PICTURE_START_VALUE))
+        ));
+    }
+
+    /// Parses a packet of `stream_id` with the given header fields
+    /// and a payload of [1, 2, 3].
+    fn parse_packet(stream_id: u8, header: &[u8]) -> Result<Packet, DecodeError> {
+        let mut buf = ((header.len() + 3) as u16).to_be_bytes().to_vec();
+        buf.extend(header);
+        buf.extend([1, 2, 3]);
+        Packet::parse(&mut &buf[..], stream_id)
+    }
+
+    #[test]
+    fn test_packet_header() {
+        let pts = timestamp_bytes(0b0010, 0x1_2345_6789);
+        let pts_dts = [timestamp_bytes(0b0011, 9000), timestamp_bytes(0b0001, 5400)].concat();
+        let std_buffer = [0x60, 0x2e];
+
+        for (header, pts, dts) in [
+            (vec![0x0f], None, None),
+            ([&[0xff; 16][..], &[0x0f]].concat(), None, None),
+            ([&std_buffer[..], &[0x0f]].concat(), None, None),
+            (pts.to_vec(), Some(0x1_2345_6789), None),
+            ([&std_buffer[..], &pts].concat(), Some(0x1_2345_6789), None),
+            (pts_dts.clone(), Some(9000), Some(5400)),
+            (
+                [&[0xff; 2], &std_buffer[..], &pts_dts].concat(),
+                Some(9000),
+                Some(5400),
+            ),
+        ] {
+            for stream_id in [VIDEO_STREAM_0_START_CODE, AUDIO_STREAM_0_START_CODE, 0xbd] {
+                let packet = parse_packet(stream_id, &header).unwrap();
+                assert_eq!((packet.pts, packet.dts), (pts, dts), "{:x?}", header);
+                assert_eq!(packet.data, [1, 2, 3]);
+            }
+        }
+
+        // Private stream 2 packets have no header fields, padding
+        // packets no content.
+        let packet = parse_packet(PRIVATE_STREAM_2, &[0xff, 0x0f]).unwrap();
+        assert_eq!(packet.data, [0xff, 0x0f, 1, 2, 3]);
+        let packet = parse_packet(PADDING_STREAM, &[0xff; 100]).unwrap();
+        assert!(packet.data.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_packet_header() {
+        let mut bad_marker = timestamp_bytes(0b0010, 9000);
+        bad_marker[2] &= 0xfe;
+        let bad_dts_prefix =
+            [timestamp_bytes(0b0011, 9000), timestamp_bytes(0b0010, 5400)].concat();
+
+        for header in [
+            [&[0xff; 17][..], &[0x0f]].concat(),
+            bad_marker.to_vec(),
+            bad_dts_prefix,
+            vec![0x00],
+            vec![0x4f],
+        ] {
+            assert!(
+                matches!(
+                    parse_packet(VIDEO_STREAM_0_START_CODE, &header),
+                    Err(DecodeError::ForbiddenValue(_))
+                ),
+                "{:x?}",
+                header
+            );
+        }
+
+        // Header fields beyond the end of the packet.
+        let buf = [0x00, 0x03, 0x21, 0x00, 0x01];
+        assert!(matches!(
+            Packet::parse(&mut &buf[..], VIDEO_STREAM_0_START_CODE),
+            Err(DecodeError::TruncatedPacket)
+        ));
+    }
+
+    #[test]
+    fn test_timestamps() {
+        // Three I-pictures at 25 frames/s. Only the first and the last
+        // picture have a PTS, the last one also a DTS.
+        let mut video = vec![0x00, 0x00, 0x01, SEQUENCE_HEADER_START_VALUE];
+        video.extend(sequence_header_16x32().raw);
+        video.extend([0x00, 0x00, 0x01, GROUP_OF_PICTURES_START_VALUE]);
+        video.extend([0x00, 0x08, 0x00, 0x00]);
+        let picture = damaged_picture();
+        let mut pictures = vec![];
+        for temporal_reference in 0..3 {
+            let mut picture = picture[..picture.len() - 4].to_vec();
+            picture[5] |= temporal_reference << 6;
+            pictures.push(picture);
+        }
+        let mut buf = vec![0x00, 0x00, 0x01, PACK_START_CODE];
+        buf.extend(timestamp_bytes(0b0010, 4000));
+        buf.extend([0x80, 0x00, 0x01]);
+        for (i, picture) in pictures.iter().enumerate() {
+            let mut header = match i {
+                0 => timestamp_bytes(0b0010, 9000).to_vec(),
+                1 => vec![0x0f],
+                _ => [
+                    timestamp_bytes(0b0011, 16200),
+                    timestamp_bytes(0b0001, 12600),
+                ]
+                .concat(),
+            };
+            if i == 0 {
+                header.extend(&video);
+            }
+            buf.extend([0x00, 0x00, 0x01, VIDEO_STREAM_0_START_CODE]);
+            buf.extend(((header.len() + picture.len()) as u16).to_be_bytes());
+            buf.extend(header);
+            buf.extend(picture);
+        }
+        buf.extend([0x00, 0x00, 0x01, ISO_11172_END_CODE]);
+
+        struct Timestamps(Vec<(u16, u64, Option<u64>)>);
+        impl FrameProcessor for Timestamps {
+            fn process(&mut self, frame: &Frame) {
+                self.0
+                    .push((frame.temporal_reference, frame.pts(), frame.dts()));
+            }
+        }
+
+        let mut decoder = MpegDecoder::from_stream(&buf[..]).unwrap();
+        let mut timestamps = Timestamps(vec![]);
+        decoder.parse_mpeg(&mut timestamps).unwrap();
+        assert_eq!(
+            timestamps.0,
+            [(0, 9000, None), (1, 12600, None), (2, 16200, Some(12600))]
+        );
+        assert_eq!(
+            decoder.reader.get_ref().system_clock_reference(),
+            Some(4000)
+        );
+
+        // Video elementary streams start at 0.
+        let buf = [video, pictures.concat()].concat();
+        let mut decoder = MpegDecoder::from_stream(&buf[..]).unwrap();
+        let mut timestamps = Timestamps(vec![]);
+        decoder.parse_mpeg(&mut timestamps).unwrap();
+        assert_eq!(
+            timestamps.0,
+            [(0, 0, None), (1, 3600, None), (2, 7200, None)]
+        );
+    }
+
+    /// Eight groups of one I-picture each at 25 frames/s, 64 KiB
+    /// apart to have seeks bisect. Returns a video elementary stream
+    /// and a system stream. The groups of the system stream all have
+    /// the same time code but a PTS.
+    fn seek_test_streams() -> (Vec<u8>, Vec<u8>) {
+        let picture = damaged_picture();
+        let picture = &picture[..picture.len() - 4];
+        let group = |frame: u8| {
+            let mut group = vec![0x00, 0x00, 0x01, GROUP_OF_PICTURES_START_VALUE];
+            group.extend([0x00, 0x08, frame >> 1, (frame & 1) << 7]);
+            group.extend(picture);
+            group
+        };
+        let mut sequence = vec![0x00, 0x00, 0x01, SEQUENCE_HEADER_START_VALUE];
+        sequence.extend(sequence_header_16x32().raw);
+
+        let mut video = sequence.clone();
+        for i in 0..8 {
+            video.extend(group(i));
+            video.extend([0x00, 0x00, 0x01, START_USER_DATA]);
+            video.extend([0xff; 64 * 1024]);
+        }
+
+        let mut system = vec![];
+        for i in 0..8u64 {
+            system.extend([0x00, 0x00, 0x01, PACK_START_CODE]);
+            system.extend(timestamp_bytes(0b0010, i * 3600));
+            system.extend([0x80, 0x00, 0x01]);
+            let mut data = timestamp_bytes(0b0010, 9000 + i * 3600).to_vec();
+            if i == 0 {
+                data.extend(&sequence);
+            }
+            data.extend(group(0));
+            system.extend([0x00, 0x00, 0x01, VIDEO_STREAM_0_START_CODE]);
+            system.extend((data.len() as u16).to_be_bytes());
+            system.extend(data);
+            system.extend([0x00, 0x00, 0x01, PADDING_STREAM, 0xea, 0x60]);
+            system.extend([0xff; 60000]);
+        }
+        system.extend([0x00, 0x00, 0x01, ISO_11172_END_CODE]);
+        (video, system)
+    }
+
+    /// PTS of the frames decoded from the current position on.
+    fn decode_pts(decoder: &mut MpegDecoder<MpegVideoStream<io::Cursor<Vec<u8>>>>) -> Vec<u64> {
+        struct Timestamps(Vec<u64>);
+        impl FrameProcessor for Timestamps {
+            fn process(&mut self, frame: &Frame) {
+                self.0.push(frame.pts());
+            }
+        }
+        let mut timestamps = Timestamps(vec![]);
+        decoder.parse_mpeg(&mut timestamps).unwrap();
+        timestamps.0
+    }
+
+    #[test]
+    fn test_seek() {
+        let (video, system) = seek_test_streams();
+        let decode = decode_pts;
+
+        let mut decoder = MpegDecoder::from_stream(io::Cursor::new(video)).unwrap();
+        decoder.seek_to_frame(3).unwrap();
+        assert_eq!(decode(&mut decoder), [3, 4, 5, 6, 7].map(|i| i * 3600));
+        decoder.seek_to_time(Duration::from_millis(110)).unwrap();
+        assert_eq!(decode(&mut decoder)[0], 7200);
+        decoder.seek_to_frame(0).unwrap();
+        assert_eq!(decode(&mut decoder).len(), 8);
+        decoder.seek_to_frame(100).unwrap();
+        assert_eq!(decode(&mut decoder), [7 * 3600]);
+
+        // System streams seek by PTS.
+        let mut decoder = MpegDecoder::from_stream(io::Cursor::new(system)).unwrap();
+        assert_eq!(decode(&mut decoder).len(), 8);
+        decoder.seek_to_frame(5).unwrap();
+        assert_eq!(decode(&mut decoder), [5, 6, 7].map(|i| 9000 + i * 3600));
+        decoder.seek_to_time(Duration::from_millis(40)).unwrap();
+        assert_eq!(decode(&mut decoder)[0], 9000 + 3600);
+    }
+
+    #[test]
+    fn test_convert() {
+        // Black, white and red above a black row. The width is odd
+        // and the last chroma sample covers a single column.
+        let mut frame = Frame::new(3, 2);
+        frame.y.data[..3].copy_from_slice(&[16, 235, 81]);
+        frame.y.data[16..19].copy_from_slice(&[16, 16, 16]);
+        frame.cb.data[..2].copy_from_slice(&[128, 90]);
+        frame.cr.data[..2].copy_from_slice(&[128, 240]);
+
+        #[rustfmt::skip]
+        assert_eq!(frame.to_rgb(), [
+            0, 0, 0, 254, 254, 254, 253, 0, 0,
+            0, 0, 0, 0, 0, 0, 178, 0, 0,
+        ]);
+        assert_eq!(frame.to_format(PixelFormat::Gray8), [0, 254, 75, 0, 0, 0]);
+        assert_eq!(&frame.to_format(PixelFormat::Bgr24)[6..9], [0, 0, 253]);
+        assert_eq!(
+            &frame.to_format(PixelFormat::Rgba32)[8..12],
+            [253, 0, 0, 255]
+        );
+        assert_eq!(
+            &frame.to_format(PixelFormat::Bgra32)[8..12],
+            [0, 0, 253, 255]
+        );
+        assert_eq!(
+            &frame.to_format(PixelFormat::Argb32)[8..12],
+            [255, 253, 0, 0]
+        );
+
+        // Padding between rows stays untouched.
+        let mut dest = [0xaa; 2 * 14];
+        frame
+            .convert_into(PixelFormat::Argb32, &mut dest, 14)
+            .unwrap();
+        assert_eq!(&dest[12..14], [0xaa, 0xaa]);
+        assert_eq!(&dest[14..18], [255, 0, 0, 0]);
+        assert_eq!(&dest[26..], [0xaa, 0xaa]);
+    }
+
+    #[test]
+    fn test_convert_short_buffer() {
+        let frame = Frame::new(3, 2);
+        let invalid_input =
+            |r: io::Result<()>| matches!(r, Err(e) if e.kind() == io::ErrorKind::InvalidInput);
+        assert!(invalid_input(frame.convert_into(
+            PixelFormat::Rgb24,
+            &mut [0; 17],
+            9
+        )));
+        assert!(invalid_input(frame.convert_into(
+            PixelFormat::Rgb24,
+            &mut [0; 18],
+            8
+        )));
+        assert!(frame
+            .convert_into(PixelFormat::Rgb24, &mut [0; 18], 9)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_frame_accessors() {
+        let mut frame = Frame::new(20, 30);
+        frame.picture_type = FRAME_TYPE_B;
+        frame.temporal_reference = 7;
+        frame.cb.data[16 + 3] = 42;
+        assert_eq!((frame.width(), frame.height()), (20, 30));
+        assert_eq!((frame.coded_width(), frame.coded_height()), (32, 32));
+        assert_eq!(frame.picture_type(), PictureType::B);
+        assert_eq!(frame.temporal_reference(), 7);
+        assert_eq!((frame.y().stride(), frame.y().data().len()), (32, 32 * 32));
+        let cb = frame.cb();
+        assert_eq!((cb.width(), cb.height(), cb.stride()), (16, 16, 16));
+        assert_eq!(cb.row(1)[3], 42);
+        assert_eq!(cb.row(1).len(), 16);
+        assert_eq!(frame.cr().data().len(), 16 * 16);
+
+        let picture = damaged_picture();
+        let reader = io::BufReader::new(io::Cursor::new(picture));
+        let mut decoder = MpegDecoder::from_reader(reader).unwrap();
+        let frame = decoder.parse_picture(&sequence_header_16x32()).unwrap();
+        assert_eq!(frame.picture_type(), PictureType::I);
+        assert_eq!((frame.width(), frame.height()), (16, 32));
+    }
+
+    #[test]
+    fn test_next_frame() {
+        let (video, _) = seek_test_streams();
+        let pts = |frame: Result<Frame, DecodeError>| frame.unwrap().pts();
+
+        let mut decoder = MpegDecoder::from_stream(io::Cursor::new(video)).unwrap();
+        let first: Vec<_> = decoder.by_ref().take(2).map(pts).collect();
+        assert_eq!(first, [0, 3600]);
+        assert_eq!(decoder.next_frame().unwrap().unwrap().pts(), 7200);
+        let shared = decoder.next_shared_frame().unwrap().unwrap();
+        assert_eq!(shared.pts(), 3 * 3600);
+        // The I-picture is the forward reference of the next picture.
+        assert_eq!(Arc::strong_count(&shared), 2);
+        // Pulling and pushing frames mixes.
+        assert_eq!(decode_pts(&mut decoder), [4, 5, 6, 7].map(|i| i * 3600));
+        assert!(decoder.next_frame().unwrap().is_none());
+        assert!(decoder.next().is_none());
+
+        decoder.seek_to_frame(6).unwrap();
+        let rest: Vec<_> = decoder.map(pts).collect();
+        assert_eq!(rest, [6 * 3600, 7 * 3600]);
+    }
+
+    #[test]
+    fn test_extension_data() {
+        let (video, _) = seek_test_streams();
+        let gop = video
+            .windows(4)
+            .position(|w| w == [0x00, 0x00, 0x01, GROUP_OF_PICTURES_START_VALUE])
+            .unwrap();
+        let with_extension = |extension: &[u8]| {
+            let mut buf = video[..gop].to_vec();
+            buf.extend([0x00, 0x00, 0x01, START_EXTENSION]);
+            buf.extend(extension);
+            // Also after the header of the first group.
+            buf.extend(&video[gop..gop + 8]);
+            buf.extend([0x00, 0x00, 0x01, START_EXTENSION]);
+            buf.extend(extension);
+            buf.extend(&video[gop + 8..]);
+            buf
+        };
+
+        // MPEG-1 decoders skip extension data. Its first four bits
+        // are not the identifier of a sequence extension.
+        let mut decoder =
+            MpegDecoder::from_stream(io::Cursor::new(with_extension(&[0x34, 0x12]))).unwrap();
+        assert_eq!(
+            decode_pts(&mut decoder),
+            (0..8).map(|i| i * 3600).collect::<Vec<_>>()
+        );
+
+        // A sequence extension makes it an MPEG-2 stream.
+        let buf = with_extension(&[0x14, 0x8a, 0x00, 0x01, 0x00, 0x00]);
+        let mut decoder = MpegDecoder::from_stream(&buf[..]).unwrap();
+        assert!(matches!(
+            decoder.next(),
+            Some(Err(DecodeError::Unsupported("MPEG-2 video")))
+        ));
+    }
+
+    #[test]
+    fn test_pts_wrap() {
+        let picture = damaged_picture();
+        let mut decoder = MpegDecoder::from_stream(&picture[..]).unwrap();
+        // The I-picture carries a PTS just before the wrap, the pictures
+        // around it are timed from it at 25 frames/s.
+        assert_eq!(
+            decoder.presentation_time_stamp(2, Some(TIMESTAMP_WRAP - 1800), 25.0),
+            TIMESTAMP_WRAP - 1800
+        );
+        assert_eq!(decoder.presentation_time_stamp(3, None, 25.0), 1800);
+        assert_eq!(
+            decoder.presentation_time_stamp(0, None, 25.0),
+            TIMESTAMP_WRAP - 9000
+        );
+    }
+
+    #[test]
+    fn test_send() {
+        // Decoding can move to another thread.
+        fn assert_send<T: Send>() {}
+        assert_send::<MpegDecoder<MpegVideoStream<std::fs::File>>>();
+        assert_send::<MpegDecoder<Lookahead<std::fs::File>>>();
+        assert_send::<Frame>();
+    }
+
+    #[test]
+    fn test_seek_index() {
+        let (video, system) = seek_test_streams();
+
+        let mut decoder = MpegDecoder::from_stream(io::Cursor::new(video.clone())).unwrap();
+        let index = decoder.build_index().unwrap();
+        let entries = index.entries();
+        assert_eq!(entries.len(), 8);
+        assert_eq!(entries[0].offset(), 12);
+        assert_eq!(
+            entries[7].offset() as usize,
+            12 + 7 * (video.len() - 12) / 8
+        );
+        assert_eq!(entries[3].time_code(), 3 * 3600);
+        assert!(entries.iter().all(|e| e.pts().is_none()));
+        // Building the index rewinds.
+        assert_eq!(decode_pts(&mut decoder).len(), 8);
+
+        let mut buf = vec![];
+        index.write_to(&mut buf).unwrap();
+        let index = SeekIndex::read_from(&mut &buf[..]).unwrap();
+
+        // The index takes over from searching, even if it is wrong.
+        let mut decoder = MpegDecoder::from_stream(io::Cursor::new(video.clone())).unwrap();
+        let mut wrong = SeekIndex::default();
+        for (i, entry) in index.entries().iter().enumerate() {
+            wrong.push(match i {
+                2 => IndexEntry {
+                    offset: index.entries()[4].offset,
+                    ..*entry
+                },
+                _ => *entry,
+            });
+        }
+        decoder.set_index(wrong);
+        decoder.seek_to_frame(2).unwrap();
+        assert_eq!(decode_pts(&mut decoder).len(), 4);
+        decoder.set_index(index);
+        decoder.seek_to_frame(6).unwrap();
+        assert_eq!(decode_pts(&mut decoder), [6 * 3600, 7 * 3600]);
+        decoder.seek_to_key_frame(1).unwrap();
+        assert_eq!(decode_pts(&mut decoder).len(), 7);
+        assert!(decoder.seek_to_key_frame(8).is_err());
+
+        // A fresh decoder has not read the sequence header yet.
+        let mut decoder = MpegDecoder::from_stream(io::Cursor::new(video.clone())).unwrap();
+        decoder.set_index(SeekIndex::read_from(&mut &buf[..]).unwrap());
+        decoder.seek_to_key_frame(3).unwrap();
+        assert_eq!(decoder.next_frame().unwrap().unwrap().pts(), 3 * 3600);
+        let mut decoder = MpegDecoder::from_stream(io::Cursor::new(video)).unwrap();
+        decoder.set_index(SeekIndex::read_from(&mut &buf[..]).unwrap());
+        decoder.seek_to_frame(5).unwrap();
+        assert_eq!(decoder.next_frame().unwrap().unwrap().pts(), 5 * 3600);
+
+        let mut decoder = MpegDecoder::from_stream(io::Cursor::new(system)).unwrap();
+        let index = decoder.build_index().unwrap();
+        let pts: Vec<_> = index.entries().iter().map(|e| e.pts()).collect();
+        assert_eq!(
+            pts,
+            (0..8).map(|i| Some(9000 + i * 3600)).collect::<Vec<_>>()
+        );
+        decoder.set_index(index);
+        decoder.seek_to_frame(5).unwrap();
+        assert_eq!(decode_pts(&mut decoder), [5, 6, 7].map(|i| 9000 + i * 3600));
+
+        // A group with I-pictures after the first one, followed by a
+        // group of one I-picture. The B-picture in between refers to
+        // the first two I-pictures.
+        let picture = damaged_picture();
+        let slices = &picture[8..picture.len() - 4];
+        let picture = |header: &[u8]| {
+            let mut buf = vec![0x00, 0x00, 0x01, PICTURE_START_VALUE];
+            buf.extend(header);
+            buf.extend(slices);
+            buf
+        };
+        let intra = |temporal_reference: u8| picture(&[0x00, temporal_reference << 6 | 0x08, 0, 0]);
+        let mut video = vec![0x00, 0x00, 0x01, SEQUENCE_HEADER_START_VALUE];
+        video.extend(sequence_header_16x32().raw);
+        video.extend([
+            0x00,
+            0x00,
+            0x01,
+            GROUP_OF_PICTURES_START_VALUE,
+            0x00,
+            0x08,
+            0,
+            0,
+        ]);
+        video.extend(intra(0));
+        video.extend(intra(2));
+        video.extend(picture(&[0x00, 1 << 6 | 0x18, 0x00, 0x01, 0x08]));
+        video.extend(intra(3));
+        video.extend([
+            0x00,
+            0x00,
+            0x01,
+            GROUP_OF_PICTURES_START_VALUE,
+            0x00,
+            0x08,
+            2,
+            0,
+        ]);
+        video.extend(intra(0));
+
+        let mut decoder = MpegDecoder::from_stream(io::Cursor::new(video.clone())).unwrap();
+        assert_eq!(decode_pts(&mut decoder), [0, 1, 2, 3, 4].map(|i| i * 3600));
+        let index = decoder.build_index().unwrap();
+        let entries: Vec<_> = index
+            .entries()
+            .iter()
+            .map(|e| (e.starts_group(), e.temporal_reference(), e.time_code()))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (true, 0, 0),
+                (false, 2, 7200),
+                (false, 3, 10800),
+                (true, 0, 14400)
+            ]
+        );
+        let offset = index.entries()[1].offset() as usize;
+        assert_eq!(
+            video[offset..offset + 4],
+            [0x00, 0x00, 0x01, PICTURE_START_VALUE]
+        );
+
+        decoder.set_index(index);
+        // The B-picture cannot be decoded without the first I-picture.
+        decoder.seek_to_key_frame(1).unwrap();
+        assert_eq!(decode_pts(&mut decoder), [2, 3, 4].map(|i| i * 3600));
+        decoder.seek_to_frame(3).unwrap();
+        assert_eq!(decode_pts(&mut decoder), [3, 4].map(|i| i * 3600));
+        decoder.seek_to_frame(1).unwrap();
+        assert_eq!(decode_pts(&mut decoder).len(), 5);
+    }
+
+    #[test]
+    fn test_system_header() {
+        let data = [
+            0x00, 0x0c, 0x80, 0x1b, 0x83, 0x06, 0xe1, 0xff, 0xc0, 0xc0, 0x20, 0xe0, 0xe0, 0x2e,
+        ];
+        let hdr = SystemHeader::parse(&mut &data[..]).unwrap();
+        assert_eq!(hdr.rate_bound(), 3521);
+        assert_eq!((hdr.audio_bound(), hdr.video_bound()), (1, 1));
+        assert!(hdr.fixed() && !hdr.constrained_parameters());
+        assert!(hdr.system_audio_lock() && hdr.system_video_lock());
+        let bounds: Vec<_> = hdr
+            .buffer_bounds()
+            .iter()
+            .map(|b| (b.stream_id(), b.buffer_size_bound()))
+            .collect();
+        assert_eq!(bounds, [(0xc0, 32 * 128), (0xe0, 46 * 1024)]);
+        hdr.validate().unwrap();
+
+        let invalid = |offset: usize, value: u8| {
+            let mut data = data.to_vec();
+            data[offset] = value;
+            SystemHeader::parse(&mut &data[..]).unwrap().validate()
+        };
+        // Marker bits.
+        assert!(invalid(4, 0x82).is_err());
+        assert!(invalid(6, 0xc1).is_err());
+        assert!(invalid(12, 0xa0).is_err());
+        // Reserved byte.
+        assert!(invalid(7, 0x00).is_err());
+        // video_bound above 16.
+        assert!(invalid(6, 0xf1).is_err());
+        // Audio buffer bound in units of 1024 bytes.
+        assert!(invalid(9, 0xe0).is_err());
+        // Duplicate and reserved stream ids.
+        assert!(invalid(11, 0xc0).is_err());
+        assert!(invalid(11, 0xba).is_err());
+        // Data after the stream table.
+        let mut data = data.to_vec();
+        data[1] += 1;
+        data.push(0x00);
+        let hdr = SystemHeader::parse(&mut &data[..]).unwrap();
+        assert_eq!(hdr.stream_ids(), [0xc0, 0xe0]);
+        assert!(hdr.validate().is_err());
+
+        // Too short for the fixed fields.
+        assert!(matches!(
+            SystemHeader::parse(&mut &[0x00, 0x05, 0x80, 0x1b, 0x83, 0x06, 0xe1][..]),
+            Err(DecodeError::TruncatedPacket)
+        ));
+    }
+
     #[test]
     fn test_iso11172_stream() {
         let f = OpenOptions::new()
@@ -0,0 +1,170 @@
+use std::io::{Read, SeekFrom};
+
+/// Amount of data before the read position that is retained for
+/// backward seeks. Must exceed the capacity of a `BufReader` stacked
+/// on top, as it seeks relative to its underlying reader.
+const HISTORY: usize = 64 * 1024;
+
+/// Makes a non-seekable reader, e.g., a pipe or a socket, usable by
+/// the decoder. The decoder only ever seeks a short distance from
+/// the current position, mostly backwards after peeking at a start
+/// code. `Lookahead` keeps a bounded window of recently read data to
+/// serve these seeks. Seeking forward reads and discards data.
+pub struct Lookahead<R: Read> {
+    source: R,
+    // Data read from the source. The first byte is at offset
+    // `window_begin` of the source.
+    window: Vec<u8>,
+    window_begin: u64,
+    pos: u64,
+    end_of_source: bool,
+}
+
+impl<R: Read> std::io::Read for Lookahead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.fill()?;
+
+        let begin = (self.pos - self.window_begin) as usize;
+        if begin >= self.window.len() {
+            return Ok(0);
+        }
+
+        let n = buf.len().min(self.window.len() - begin);
+        buf[..n].copy_from_slice(&self.window[begin..begin + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read> std::io::Seek for Lookahead<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+            SeekFrom::End(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "cannot seek relative to the end of a stream",
+                ))
+            }
+        };
+
+        match target {
+            Some(target) if target >= self.window_begin => {
+                self.pos = target;
+                Ok(target)
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "seek target no longer buffered",
+            )),
+        }
+    }
+}
+
+impl<R: Read> Lookahead<R> {
+    pub fn new(source: R) -> Lookahead<R> {
+        Self {
+            source,
+            window: vec![],
+            window_begin: 0,
+            pos: 0,
+            end_of_source: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.source
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.source
+    }
+
+    /// Forgets the buffered data, e.g., after the source was moved
+    /// to a different position. Reading continues from the source at
+    /// the current read position.
+    pub(crate) fn reset(&mut self) {
+        self.window.clear();
+        self.window_begin = self.pos;
+        self.end_of_source = false;
+    }
+
+    /// Reads from the source until there is data at the read position
+    /// or the source is exhausted.
+    fn fill(&mut self) -> std::io::Result<()> {
+        let mut buf = [0; 8 * 1024];
+
+        loop {
+            // Only discard old data once it is twice the history to
+            // not move the window on every read.
+            let behind = (self.pos - self.window_begin) as usize;
+            if behind > 2 * HISTORY {
+                let n = (behind - HISTORY).min(self.window.len());
+                self.window.drain(..n);
+                self.window_begin += n as u64;
+            }
+
+            if self.end_of_source || self.pos < self.window_begin + self.window.len() as u64 {
+                return Ok(());
+            }
+
+            match self.source.read(&mut buf) {
+                Ok(0) => self.end_of_source = true,
+                Ok(n) => self.window.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek};
+
+    #[test]
+    fn test_seek() {
+        let data: Vec<u8> = (0..=255).collect();
+        let mut r = Lookahead::new(&data[..]);
+
+        let mut buf = [0; 8];
+        r.read_exact(&mut buf).unwrap();
+        r.seek(SeekFrom::Current(-4)).unwrap();
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [4, 5, 6, 7, 8, 9, 10, 11]);
+
+        // Forward seeks skip data.
+        r.seek(SeekFrom::Current(100)).unwrap();
+        r.read_exact(&mut buf[..1]).unwrap();
+        assert_eq!(buf[0], 112);
+        assert_eq!(r.stream_position().unwrap(), 113);
+
+        // Reading past the end of the source.
+        r.seek(SeekFrom::Start(300)).unwrap();
+        assert_eq!(r.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_bounded_memory() {
+        let data: Vec<u8> = (0..512 * 1024).map(|i| (i / 1024) as u8).collect();
+        let mut r = Lookahead::new(&data[..]);
+        let mut buf = [0; 1000];
+        let mut total = 0;
+        loop {
+            let n = r.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            assert_eq!(&buf[..n], &data[total..total + n]);
+            total += n;
+            assert!(r.window.len() <= 2 * HISTORY + 8 * 1024);
+        }
+        assert_eq!(total, data.len());
+
+        // Recent data can still be revisited, old data not.
+        r.seek(SeekFrom::Current(-(HISTORY as i64))).unwrap();
+        assert!(r.seek(SeekFrom::Start(0)).is_err());
+    }
+}
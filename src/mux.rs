@@ -0,0 +1,774 @@
+use super::audio::{read_fully, Header};
+use super::{timestamp_bytes, DecodeError, FRAME_RATES, GROUP_OF_PICTURES_START_VALUE};
+use super::{AUDIO_STREAM_0_START_CODE, VIDEO_STREAM_0_START_CODE};
+use super::{ISO_11172_END_CODE, PACK_START_CODE, PICTURE_START_VALUE};
+use super::{SEQUENCE_HEADER_START_VALUE, SYSTEM_HEADER_START_CODE};
+use std::collections::VecDeque;
+use std::io::{self, BufReader, Bytes, Read, Write};
+
+use log::warn;
+
+/// Time between the arrival of data in the decoder and its decoding,
+/// in units of the 90 kHz system clock. Packs are sent at most this
+/// early, and later if the STD buffer of the stream has no room yet.
+/// Time stamps of both streams start at this value.
+const PRELOAD: u64 = 45000;
+
+/// Length of the packet start code and length field.
+const PACKET_PREFIX_LEN: usize = 6;
+
+/// STD buffer sizes announced in the system header and in the first
+/// packet of each stream, as scale and size. Video buffers are counted
+/// in units of 1024 bytes, audio buffers in units of 128 bytes.
+const VIDEO_BUFFER: (bool, u16) = (true, 46);
+const AUDIO_BUFFER: (bool, u16) = (false, 32);
+
+/// A picture or audio frame with its time stamps in units of the 90
+/// kHz system clock.
+struct AccessUnit {
+    data: Vec<u8>,
+    pts: u64,
+    dts: u64,
+}
+
+/// Splits an elementary stream into access units.
+trait Units {
+    fn next_unit(&mut self) -> Result<Option<AccessUnit>, DecodeError>;
+}
+
+/// Pictures of a video elementary stream. Sequence and group of
+/// pictures headers belong to the picture after them.
+struct VideoUnits<R: Read> {
+    source: Bytes<BufReader<R>>,
+    // Data read past the end of the last unit.
+    pending: Vec<u8>,
+    frame_rate: f32,
+    // Pictures in coding order so far, and of them the ones before
+    // the current group of pictures.
+    pictures: u64,
+    group_begin: u64,
+}
+
+impl<R: Read> VideoUnits<R> {
+    fn new(source: R) -> Self {
+        VideoUnits {
+            source: BufReader::new(source).bytes(),
+            pending: vec![],
+            frame_rate: 0.0,
+            pictures: 0,
+            group_begin: 0,
+        }
+    }
+}
+
+impl<R: Read> Units for VideoUnits<R> {
+    fn next_unit(&mut self) -> Result<Option<AccessUnit>, DecodeError> {
+        let mut data = std::mem::take(&mut self.pending);
+        let mut picture = None;
+        let mut scanned = 0;
+        loop {
+            // Look for start codes in what was read so far.
+            while scanned + 4 <= data.len() {
+                let at = scanned;
+                scanned += 1;
+                if data[at..at + 3] != [0, 0, 1] {
+                    continue;
+                }
+                match data[at + 3] {
+                    SEQUENCE_HEADER_START_VALUE
+                    | GROUP_OF_PICTURES_START_VALUE
+                    | PICTURE_START_VALUE
+                        if picture.is_some() =>
+                    {
+                        self.pending = data.split_off(at);
+                        return Ok(Some(self.unit(data, picture.unwrap())));
+                    }
+                    SEQUENCE_HEADER_START_VALUE => {
+                        // Frame rate code after the picture size and
+                        // the aspect ratio.
+                        if let Some(&b) = data.get(at + 7) {
+                            self.frame_rate = FRAME_RATES
+                                .get(usize::from(b & 0x0F))
+                                .copied()
+                                .unwrap_or(0.0);
+                        } else {
+                            scanned = at;
+                        }
+                    }
+                    GROUP_OF_PICTURES_START_VALUE => self.group_begin = self.pictures,
+                    PICTURE_START_VALUE => match data.get(at + 4..at + 6) {
+                        Some(b) => picture = Some(u64::from(b[0]) << 2 | u64::from(b[1] >> 6)),
+                        None => scanned = at,
+                    },
+                    _ => {}
+                }
+                if scanned == at {
+                    break;
+                }
+            }
+
+            match self.source.next() {
+                Some(b) => data.push(b?),
+                None => {
+                    return Ok(match picture {
+                        Some(temporal_reference) => Some(self.unit(data, temporal_reference)),
+                        // Anything after the last picture, e.g., the
+                        // sequence end code, is appended to it.
+                        None if data.is_empty() => None,
+                        None => Some(AccessUnit {
+                            data,
+                            pts: PRELOAD,
+                            dts: PRELOAD,
+                        }),
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> VideoUnits<R> {
+    /// Times a picture. Pictures are decoded one picture period apart.
+    /// They are presented in the order of their temporal reference
+    /// within the group of pictures, one period after decoding at the
+    /// earliest to leave room for B-pictures.
+    fn unit(&mut self, data: Vec<u8>, temporal_reference: u64) -> AccessUnit {
+        let period = if self.frame_rate > 0.0 {
+            90000.0 / f64::from(self.frame_rate)
+        } else {
+            0.0
+        };
+        let at = |n: u64| PRELOAD + (n as f64 * period).round() as u64;
+        let unit = AccessUnit {
+            data,
+            pts: at(self.group_begin + temporal_reference + 1),
+            dts: at(self.pictures),
+        };
+        self.pictures += 1;
+        unit
+    }
+}
+
+/// Frames of an MPEG audio elementary stream. Data between frames is
+/// dropped.
+struct AudioUnits<R: Read> {
+    source: BufReader<R>,
+    // Samples per channel so far.
+    samples: u64,
+}
+
+impl<R: Read> Units for AudioUnits<R> {
+    fn next_unit(&mut self) -> Result<Option<AccessUnit>, DecodeError> {
+        let mut b = [0; 4];
+        if !read_fully(&mut self.source, &mut b)? {
+            return Ok(None);
+        }
+        while !Header::is_header(&b) {
+            b.copy_within(1.., 0);
+            if !read_fully(&mut self.source, &mut b[3..])? {
+                return Ok(None);
+            }
+        }
+
+        let header = Header::parse(&b)?;
+        let mut data = vec![0; header.frame_len()];
+        data[..4].copy_from_slice(&b);
+        if !read_fully(&mut self.source, &mut data[4..])? {
+            return Ok(None);
+        }
+
+        let pts = PRELOAD + self.samples * 90000 / u64::from(header.sample_rate);
+        self.samples += u64::from(header.samples());
+        Ok(Some(AccessUnit {
+            data,
+            pts,
+            dts: pts,
+        }))
+    }
+}
+
+/// An elementary stream being multiplexed.
+struct Track<'a> {
+    stream_id: u8,
+    buffer: (bool, u16),
+    // Size of the STD buffer in bytes.
+    buffer_size: usize,
+    // Data sent but not decoded yet, as the decoding time stamp of
+    // the access unit and the number of its bytes.
+    buffered: VecDeque<(u64, usize)>,
+    units: Box<dyn Units + 'a>,
+    // Data not sent yet.
+    data: VecDeque<u8>,
+    // Offsets in `data` of the access units starting there, with
+    // their time stamps.
+    starts: VecDeque<(usize, u64, u64)>,
+    // Decoding time stamp of the data at the front.
+    dts: u64,
+    // Whether a packet was sent, the first carries the buffer size.
+    started: bool,
+    done: bool,
+}
+
+impl<'a> Track<'a> {
+    fn new(stream_id: u8, buffer: (bool, u16), units: Box<dyn Units + 'a>) -> Self {
+        let (scale, size) = buffer;
+        Track {
+            stream_id,
+            buffer,
+            buffer_size: usize::from(size) * if scale { 1024 } else { 128 },
+            buffered: VecDeque::new(),
+            units,
+            data: VecDeque::new(),
+            starts: VecDeque::new(),
+            dts: 0,
+            started: false,
+            done: false,
+        }
+    }
+
+    /// Reads access units until `len` bytes are pending or the stream
+    /// ends.
+    fn fill(&mut self, len: usize) -> Result<(), DecodeError> {
+        while self.data.len() < len && !self.done {
+            match self.units.next_unit()? {
+                Some(unit) => {
+                    if self.data.is_empty() {
+                        self.dts = unit.dts;
+                    }
+                    self.starts.push_back((self.data.len(), unit.pts, unit.dts));
+                    self.data.extend(unit.data);
+                }
+                None => self.done = true,
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the first `len` bytes and counts them as sent.
+    fn consume(&mut self, len: usize) {
+        // They leave the buffer with the access units they belong to.
+        let (mut begin, mut dts) = (0, self.dts);
+        for &(offset, _, next_dts) in &self.starts {
+            if offset >= len {
+                break;
+            }
+            if offset > begin {
+                self.buffered.push_back((dts, offset - begin));
+            }
+            (begin, dts) = (offset, next_dts);
+        }
+        if len > begin {
+            self.buffered.push_back((dts, len - begin));
+        }
+
+        self.data.drain(..len);
+        while let Some(&(offset, _, dts)) = self.starts.front() {
+            if offset >= len {
+                break;
+            }
+            self.dts = dts;
+            self.starts.pop_front();
+        }
+        for start in &mut self.starts {
+            start.0 -= len;
+        }
+        if let Some(&(0, _, dts)) = self.starts.front() {
+            self.dts = dts;
+        }
+    }
+
+    /// Bytes in the buffer at `time`. Access units leave it at their
+    /// decoding time stamp.
+    fn occupancy(&self, time: u64) -> usize {
+        self.buffered
+            .iter()
+            .filter(|&&(dts, _)| dts > time)
+            .map(|&(_, len)| len)
+            .sum()
+    }
+
+    /// The earliest time from `time` on at which `len` bytes, at most
+    /// the buffer size, fit into the buffer.
+    fn room_at(&self, time: u64, len: usize) -> u64 {
+        let mut occupancy = self.occupancy(time);
+        let mut at = time;
+        for &(dts, bytes) in self.buffered.iter().filter(|&&(dts, _)| dts > time) {
+            if occupancy + len <= self.buffer_size {
+                break;
+            }
+            occupancy -= bytes;
+            at = dts;
+        }
+        at
+    }
+}
+
+/// Multiplexes a video elementary stream and optionally an MPEG audio
+/// elementary stream into an ISO 11172-1 system stream, e.g., to turn
+/// a .m1v file into a .mpg file. Every pack holds one packet of
+/// `packet_size` bytes at most, including the headers. Packets that
+/// start an access unit carry its time stamps.
+pub struct MpegMuxer<W: Write> {
+    writer: W,
+    packet_size: usize,
+    // In bytes/s, a multiple of 50.
+    mux_rate: u32,
+}
+
+impl<W: Write> MpegMuxer<W> {
+    pub fn new(writer: W) -> Self {
+        MpegMuxer {
+            writer,
+            packet_size: 2048,
+            mux_rate: 1_250_000,
+        }
+    }
+
+    /// Size of the packs in bytes, including all headers. Defaults to
+    /// 2048. Video CDs use 2324.
+    pub fn set_packet_size(&mut self, packet_size: usize) -> io::Result<()> {
+        if !(64..=65535).contains(&packet_size) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "packet size"));
+        }
+        self.packet_size = packet_size;
+        Ok(())
+    }
+
+    /// Rate in bytes/s at which the packs are delivered to the
+    /// decoder, rounded up to a multiple of 50. Defaults to 10 Mbit/s.
+    /// Has to exceed the bit rate of the elementary streams together.
+    pub fn set_mux_rate(&mut self, mux_rate: u32) -> io::Result<()> {
+        if mux_rate == 0 || mux_rate.div_ceil(50) >= (1 << 22) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "mux rate"));
+        }
+        self.mux_rate = mux_rate.div_ceil(50) * 50;
+        Ok(())
+    }
+
+    /// Writes a system stream with the video stream 0xE0.
+    pub fn mux_video<V: Read>(&mut self, video: V) -> Result<(), DecodeError> {
+        self.mux_tracks(vec![Track::new(
+            VIDEO_STREAM_0_START_CODE,
+            VIDEO_BUFFER,
+            Box::new(VideoUnits::new(video)),
+        )])
+    }
+
+    /// Writes a system stream with the video stream 0xE0 and the audio
+    /// stream 0xC0.
+    pub fn mux<V: Read, A: Read>(&mut self, video: V, audio: A) -> Result<(), DecodeError> {
+        let audio = AudioUnits {
+            source: BufReader::new(audio),
+            samples: 0,
+        };
+        self.mux_tracks(vec![
+            Track::new(
+                VIDEO_STREAM_0_START_CODE,
+                VIDEO_BUFFER,
+                Box::new(VideoUnits::new(video)),
+            ),
+            Track::new(AUDIO_STREAM_0_START_CODE, AUDIO_BUFFER, Box::new(audio)),
+        ])
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn mux_tracks(&mut self, mut tracks: Vec<Track>) -> Result<(), DecodeError> {
+        let system_header = self.system_header(&tracks);
+        let mut clock = 0;
+        let mut first = true;
+
+        loop {
+            for track in &mut tracks {
+                track.fill(self.packet_size)?;
+            }
+            // Packs are sent at the mux rate, but not earlier than
+            // needed and not before the buffer of the decoder has room
+            // for a full packet. Of the streams that can be sent
+            // first, the data that is decoded first goes.
+            let (scr, track) = match tracks
+                .iter_mut()
+                .filter(|t| !t.data.is_empty())
+                .map(|t| {
+                    let len = t.data.len().min(self.packet_size).min(t.buffer_size);
+                    let scr = clock.max(t.dts.saturating_sub(PRELOAD));
+                    (t.room_at(scr, len), t)
+                })
+                .min_by_key(|(scr, t)| (*scr, t.dts))
+            {
+                Some(next) => next,
+                None => break,
+            };
+            track.buffered.retain(|&(dts, _)| dts > scr);
+            if scr > track.dts {
+                warn!(
+                    "mux rate too low, packet of stream 0x{:x} late",
+                    track.stream_id
+                );
+            }
+
+            let mut pack = vec![0x00, 0x00, 0x01, PACK_START_CODE];
+            pack.extend(timestamp_bytes(0b0010, scr));
+            let rate = self.mux_rate / 50;
+            pack.extend([
+                0x80 | (rate >> 15) as u8,
+                (rate >> 7) as u8,
+                (rate << 1) as u8 | 1,
+            ]);
+            if first {
+                pack.extend(&system_header);
+                first = false;
+            }
+            let room = track.buffer_size - track.occupancy(scr);
+            self.packet(&mut pack, track, room)?;
+
+            self.writer.write_all(&pack)?;
+            clock = scr + pack.len() as u64 * 90000 / u64::from(self.mux_rate);
+        }
+
+        self.writer
+            .write_all(&[0x00, 0x00, 0x01, ISO_11172_END_CODE])?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Appends the next packet of `track` to `pack`, with `limit`
+    /// bytes of data at most.
+    fn packet(
+        &mut self,
+        pack: &mut Vec<u8>,
+        track: &mut Track,
+        limit: usize,
+    ) -> Result<(), DecodeError> {
+        let mut header = vec![];
+        if !track.started {
+            let (scale, size) = track.buffer;
+            header.extend([0x40 | u8::from(scale) << 5 | (size >> 8) as u8, size as u8]);
+            track.started = true;
+        }
+
+        // Room for the time stamps and the payload.
+        let room = self.packet_size - pack.len() - PACKET_PREFIX_LEN - header.len();
+        track.fill(room)?;
+        match track.starts.iter().find(|s| s.0 < (room - 10).min(limit)) {
+            Some(&(_, pts, dts)) if pts != dts => {
+                header.extend(timestamp_bytes(0b0011, pts));
+                header.extend(timestamp_bytes(0b0001, dts));
+            }
+            Some(&(_, pts, _)) => header.extend(timestamp_bytes(0b0010, pts)),
+            None => header.push(0x0F),
+        }
+
+        let len = track
+            .data
+            .len()
+            .min(self.packet_size - pack.len() - PACKET_PREFIX_LEN - header.len())
+            .min(limit);
+        pack.extend([0x00, 0x00, 0x01, track.stream_id]);
+        pack.extend(((header.len() + len) as u16).to_be_bytes());
+        pack.extend(header);
+        pack.extend(track.data.range(..len));
+        track.consume(len);
+        Ok(())
+    }
+
+    fn system_header(&self, tracks: &[Track]) -> Vec<u8> {
+        let rate = self.mux_rate / 50;
+        let audio = tracks
+            .iter()
+            .filter(|t| t.stream_id == AUDIO_STREAM_0_START_CODE)
+            .count();
+        let mut data = vec![
+            0x80 | (rate >> 15) as u8,
+            (rate >> 7) as u8,
+            (rate << 1) as u8 | 1,
+            // Audio bound, variable rate, no CSPS.
+            (audio as u8) << 2,
+            // Audio and video locked to the system clock, video bound.
+            0b1110_0001,
+            0xFF,
+        ];
+        for track in tracks {
+            let (scale, size) = track.buffer;
+            data.extend([
+                track.stream_id,
+                0xC0 | u8::from(scale) << 5 | (size >> 8) as u8,
+                size as u8,
+            ]);
+        }
+
+        let mut header = vec![0x00, 0x00, 0x01, SYSTEM_HEADER_START_CODE];
+        header.extend((data.len() as u16).to_be_bytes());
+        header.extend(data);
+        header
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{decode, i420};
+    use crate::{iso11172_stream, next_system_item, Frame, SystemItem};
+    use crate::{MpegAudioDecoder, MpegAudioStream, MpegEncoder};
+
+    fn video(pictures: usize) -> Vec<u8> {
+        let mut encoder = MpegEncoder::new(vec![], 32, 32, 25.0).unwrap();
+        encoder.set_group_size(4).unwrap();
+        for i in 0..pictures {
+            encoder
+                .encode_i420(&vec![i as u8; 32 * 32 * 3 / 2])
+                .unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    /// Silent Layer II frames, mono at 48 kHz and 384 kbit/s. Each
+    /// lasts 1152 samples or 2160 ticks of the system clock.
+    fn audio(frames: usize) -> Vec<u8> {
+        let mut frame = vec![0; 1152];
+        frame[..4].copy_from_slice(&[0xFF, 0xFD, 0xE4, 0xC0]);
+        frame.repeat(frames)
+    }
+
+    fn items(stream: &[u8]) -> Vec<SystemItem> {
+        let mut stream = stream;
+        let mut items = vec![];
+        while let Some(item) = next_system_item(&mut stream).unwrap() {
+            items.push(item);
+        }
+        items
+    }
+
+    /// Decoding time stamps and sizes of the access units.
+    fn access_units(mut units: impl Units) -> Vec<(u64, usize)> {
+        let mut sizes = vec![];
+        while let Some(unit) = units.next_unit().unwrap() {
+            sizes.push((unit.dts, unit.data.len()));
+        }
+        sizes
+    }
+
+    /// Checks that no STD buffer holds more than the bound in the
+    /// system header. Packets enter the buffer at the SCR of their
+    /// pack, the access units in `units` leave it at their DTS.
+    fn assert_buffers_bounded(stream: &[u8], units: &[(u8, Vec<(u64, usize)>)]) {
+        let items = items(stream);
+        let bounds = match &items[1] {
+            SystemItem::SystemHeader(header) => header.buffer_bounds(),
+            _ => panic!("no system header after the first pack header"),
+        };
+        let mut scr = 0;
+        let mut received = vec![0; units.len()];
+        for item in &items {
+            match item {
+                SystemItem::Pack(pack) => scr = pack.scr,
+                SystemItem::Packet(stream_id, packet) => {
+                    let i = units.iter().position(|u| u.0 == *stream_id).unwrap();
+                    received[i] += packet.data.len();
+                    let decoded: usize = units[i]
+                        .1
+                        .iter()
+                        .filter(|&&(dts, _)| dts <= scr)
+                        .map(|&(_, len)| len)
+                        .sum();
+                    let bound = bounds.iter().find(|b| b.stream_id() == *stream_id);
+                    let occupancy = received[i].saturating_sub(decoded);
+                    assert!(
+                        occupancy <= bound.unwrap().buffer_size_bound() as usize,
+                        "{} bytes of stream 0x{:x} buffered at {}",
+                        occupancy,
+                        stream_id,
+                        scr
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_mux_buffers() {
+        // Pictures of noise at about 1.1 Mbit/s, as on Video CDs.
+        // Half a second of them is more than the video buffer holds.
+        let mut encoder = MpegEncoder::new(vec![], 64, 64, 25.0).unwrap();
+        encoder.set_quantizer_scale(3).unwrap();
+        let mut noise = 1u32;
+        for _ in 0..50 {
+            let picture: Vec<u8> = (0..64 * 64 * 3 / 2)
+                .map(|_| {
+                    noise = noise.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (noise >> 24) as u8
+                })
+                .collect();
+            encoder.encode_i420(&picture).unwrap();
+        }
+        let video = encoder.finish().unwrap();
+        let audio = audio(40);
+
+        let mut muxer = MpegMuxer::new(vec![]);
+        muxer.set_packet_size(2324).unwrap();
+        muxer.set_mux_rate(176_400).unwrap();
+        muxer.mux(&video[..], &audio[..]).unwrap();
+        let stream = muxer.into_inner();
+
+        let audio_units = AudioUnits {
+            source: BufReader::new(&audio[..]),
+            samples: 0,
+        };
+        assert_buffers_bounded(
+            &stream,
+            &[
+                (
+                    VIDEO_STREAM_0_START_CODE,
+                    access_units(VideoUnits::new(&video[..])),
+                ),
+                (AUDIO_STREAM_0_START_CODE, access_units(audio_units)),
+            ],
+        );
+        assert_eq!(decode(&stream).len(), 50);
+    }
+
+    #[test]
+    fn test_mux_video() {
+        let video = video(10);
+        let mut muxer = MpegMuxer::new(vec![]);
+        muxer.set_packet_size(256).unwrap();
+        muxer.set_mux_rate(176_400).unwrap();
+        assert!(muxer.set_packet_size(10).is_err());
+        assert!(muxer.set_mux_rate(0).is_err());
+        muxer.mux_video(&video[..]).unwrap();
+        let stream = muxer.into_inner();
+
+        let packs: Vec<usize> = (0..stream.len() - 3)
+            .filter(|&i| stream[i..i + 4] == [0x00, 0x00, 0x01, PACK_START_CODE])
+            .collect();
+        assert_eq!(packs[0], 0);
+        assert!(packs.windows(2).all(|w| w[1] - w[0] <= 256));
+        assert_eq!(
+            stream[stream.len() - 4..],
+            [0x00, 0x00, 0x01, ISO_11172_END_CODE]
+        );
+
+        let mut demuxed = vec![];
+        iso11172_stream(&mut &stream[..], &mut demuxed).unwrap();
+        assert_eq!(demuxed, video);
+
+        match &items(&stream)[1] {
+            SystemItem::SystemHeader(header) => {
+                header.validate().unwrap();
+                assert_eq!(header.rate_bound(), 3528);
+                assert_eq!(header.video_stream_ids(), [VIDEO_STREAM_0_START_CODE]);
+                assert_eq!(header.audio_bound(), 0);
+            }
+            _ => panic!("no system header after the first pack header"),
+        }
+
+        // I-pictures are presented one picture period after decoding.
+        let pts: Vec<u64> = decode(&stream).iter().map(Frame::pts).collect();
+        let expected: Vec<u64> = (1..=10).map(|n| PRELOAD + n * 3600).collect();
+        assert_eq!(pts, expected);
+    }
+
+    #[test]
+    fn test_mux_reordered() {
+        // Groups of I-, P- and B-pictures in coding order.
+        let video = std::fs::read("test/regression/ipb.m1v").unwrap();
+        let mut muxer = MpegMuxer::new(vec![]);
+        muxer.set_packet_size(512).unwrap();
+        muxer.mux_video(&video[..]).unwrap();
+        let stream = muxer.into_inner();
+
+        let mut scr = 0;
+        for item in items(&stream) {
+            match item {
+                SystemItem::Pack(pack) => scr = pack.scr,
+                SystemItem::Packet(_, packet) => {
+                    if let Some(dts) = packet.dts {
+                        assert!(dts < packet.pts.unwrap());
+                    }
+                    assert!(packet.dts.or(packet.pts).is_none_or(|t| scr <= t));
+                }
+                _ => {}
+            }
+        }
+
+        let elementary = decode(&video);
+        let system = decode(&stream);
+        assert_eq!(system.len(), elementary.len());
+        for (s, e) in system.iter().zip(&elementary) {
+            assert_eq!(s.pts(), e.pts() + PRELOAD + 3600);
+            assert!(i420(s) == i420(e));
+        }
+    }
+
+    #[test]
+    fn test_mux_audio() {
+        let (video, audio) = (video(10), audio(20));
+        let mut muxer = MpegMuxer::new(vec![]);
+        muxer.set_packet_size(256).unwrap();
+        muxer.mux(&video[..], &audio[..]).unwrap();
+        let stream = muxer.into_inner();
+
+        let items = items(&stream);
+        match &items[1] {
+            SystemItem::SystemHeader(header) => {
+                header.validate().unwrap();
+                assert_eq!(header.audio_stream_ids(), [AUDIO_STREAM_0_START_CODE]);
+                let bounds: Vec<u32> = header
+                    .buffer_bounds()
+                    .iter()
+                    .map(|b| b.buffer_size_bound())
+                    .collect();
+                assert_eq!(bounds, [46 * 1024, 32 * 128]);
+            }
+            _ => panic!("no system header after the first pack header"),
+        }
+
+        // Both streams cover 400 ms, their packets are interleaved.
+        let ids: Vec<u8> = items
+            .iter()
+            .filter_map(|item| match item {
+                SystemItem::Packet(id, _) => Some(*id),
+                _ => None,
+            })
+            .collect();
+        let last_video = ids.iter().rposition(|&id| id == VIDEO_STREAM_0_START_CODE);
+        assert!(ids.iter().position(|&id| id == AUDIO_STREAM_0_START_CODE) < last_video);
+
+        let audio_units = AudioUnits {
+            source: BufReader::new(&audio[..]),
+            samples: 0,
+        };
+        assert_buffers_bounded(
+            &stream,
+            &[
+                (
+                    VIDEO_STREAM_0_START_CODE,
+                    access_units(VideoUnits::new(&video[..])),
+                ),
+                (AUDIO_STREAM_0_START_CODE, access_units(audio_units)),
+            ],
+        );
+
+        let mut demuxed = MpegAudioStream::new(&stream[..], AUDIO_STREAM_0_START_CODE).unwrap();
+        let mut data: Vec<u8> = vec![];
+        while let Some(packet) = demuxed.next_packet().unwrap() {
+            if let Some(pts) = packet.pts() {
+                assert_eq!((pts - PRELOAD) % 2160, 0);
+            }
+            data.extend(packet.data());
+        }
+        assert_eq!(data, audio);
+
+        let stream_audio = MpegAudioStream::new(&stream[..], AUDIO_STREAM_0_START_CODE).unwrap();
+        let mut decoder = MpegAudioDecoder::new(stream_audio);
+        let mut frames = 0;
+        while decoder.next_frame().unwrap().is_some() {
+            frames += 1;
+        }
+        assert_eq!(frames, 20);
+        assert_eq!(decode(&stream).len(), 10);
+    }
+}
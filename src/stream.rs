@@ -1,32 +1,597 @@
-use super::iso11172_stream;
+use super::{is_audio_stream, is_video_stream, next_system_item};
+use super::{DecodeError, GroupOfPictures, IndexEntry, Lookahead, PictureHeader, FRAME_TYPE_I};
+use super::{SystemHeader, SystemItem, PACK_START_CODE, VIDEO_STREAM_0_START_CODE};
+use super::{GROUP_OF_PICTURES_START_VALUE, PICTURE_START_VALUE, SEQUENCE_HEADER_START_VALUE};
+use std::collections::VecDeque;
 use std::fs::File;
-use std::io::SeekFrom;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+
+use log::trace;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Layer {
+    // ISO 11172-1 system stream, i.e., packs and packets.
+    System,
+    // Video elementary stream, e.g., a .m1v file.
+    Video,
+}
+
+/// Bound on the number of time stamps kept for packets the decoder
+/// has not reached yet. The decoder only reads a few packets ahead.
+const MAX_PENDING_TIMESTAMPS: usize = 1024;
+
+/// Time stamps of a packet of the video stream.
+struct PacketTimestamps {
+    // Range of the payload in the video data.
+    begin: u64,
+    end: u64,
+    pts: u64,
+    dts: Option<u64>,
+}
+
+/// Time stamps of the video packets, shared between the demultiplexer
+/// and the decoder. A time stamp applies to the first picture whose
+/// start code begins in the packet.
+#[derive(Default)]
+pub(crate) struct VideoTimestamps {
+    packets: VecDeque<PacketTimestamps>,
+}
+
+impl VideoTimestamps {
+    fn push(&mut self, packet: PacketTimestamps) {
+        if self.packets.len() == MAX_PENDING_TIMESTAMPS {
+            self.packets.pop_front();
+        }
+        self.packets.push_back(packet);
+    }
+
+    fn clear(&mut self) {
+        self.packets.clear();
+    }
+
+    /// PTS and DTS of the picture whose start code begins at `offset`
+    /// of the video data. Pictures must be passed in stream order.
+    pub(crate) fn take(&mut self, offset: u64) -> Option<(u64, Option<u64>)> {
+        while self.packets.front().is_some_and(|p| p.end <= offset) {
+            self.packets.pop_front();
+        }
+        if self.packets.front().is_some_and(|p| p.begin <= offset) {
+            return self.packets.pop_front().map(|p| (p.pts, p.dts));
+        }
+        None
+    }
+}
+
+/// Payload of the video packets of a system stream, demultiplexed
+/// one packet at a time. Video elementary streams are passed through
+/// as they are.
+struct VideoPackets<R: Read> {
+    source: R,
+    // Unknown until the first start code has been read.
+    layer: Option<Layer>,
+    // Bytes read from the source to detect the layer that have not
+    // been consumed yet.
+    pending: Vec<u8>,
+    // Item read ahead while looking for the system header.
+    queued: Option<SystemItem>,
+    system_header: Option<SystemHeader>,
+    // The video stream to return.
+    stream_id: u8,
+    // Whether any video data has been returned yet.
+    started: bool,
+    // System clock reference of the most recent pack.
+    scr: Option<u64>,
+    timestamps: Arc<Mutex<VideoTimestamps>>,
+    // Amount of video data returned before the current packet.
+    packet_begin: u64,
+    packet: Vec<u8>,
+    offset: usize,
+}
+
+impl<R: Read> VideoPackets<R> {
+    fn new(source: R, layer: Option<Layer>, stream_id: u8) -> VideoPackets<R> {
+        VideoPackets {
+            source,
+            layer,
+            pending: vec![],
+            queued: None,
+            system_header: None,
+            stream_id,
+            started: false,
+            scr: None,
+            timestamps: Arc::default(),
+            packet_begin: 0,
+            packet: vec![],
+            offset: 0,
+        }
+    }
+
+    /// Both kinds of streams begin with a start code, possibly after
+    /// zero stuffing. System streams with a pack start code, video
+    /// streams with a sequence header. The start code is kept to be
+    /// read again, video streams also keep the bytes before it.
+    fn detect_layer(&mut self) -> std::io::Result<Layer> {
+        if let Some(layer) = self.layer {
+            return Ok(layer);
+        }
+
+        let mut window = [0xFF; 4];
+        while window[..3] != [0x00, 0x00, 0x01] {
+            let mut b = [0];
+            match self.source.read(&mut b) {
+                Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            self.pending.push(b[0]);
+            window.copy_within(1.., 0);
+            window[3] = b[0];
+        }
+
+        let layer = match window[3] {
+            PACK_START_CODE => {
+                // Packs are parsed from their start code on.
+                self.pending.drain(..self.pending.len() - 4);
+                Layer::System
+            }
+            SEQUENCE_HEADER_START_VALUE => Layer::Video,
+            code => return Err(DecodeError::UnexpectedStartCode(code).into()),
+        };
+        trace!("detected {:?} layer stream", layer);
+        self.layer = Some(layer);
+        Ok(layer)
+    }
+
+    fn next_item(&mut self) -> Result<Option<SystemItem>, DecodeError> {
+        if let Some(item) = self.queued.take() {
+            return Ok(Some(item));
+        }
+
+        let mut source = (&self.pending[..]).chain(&mut self.source);
+        let item = next_system_item(&mut source);
+        let unread = source.into_inner().0.len();
+        self.pending.drain(..self.pending.len() - unread);
+
+        match &item {
+            Ok(Some(SystemItem::Pack(pack))) => self.scr = Some(pack.scr),
+            Ok(Some(SystemItem::SystemHeader(hdr))) => self.system_header = Some(hdr.clone()),
+            _ => {}
+        }
+        item
+    }
+
+    /// Reads ahead to the system header. It follows the first pack
+    /// header, before any packet.
+    fn system_header(&mut self) -> Result<Option<&SystemHeader>, DecodeError> {
+        if self.detect_layer()? == Layer::System {
+            while self.system_header.is_none() && self.queued.is_none() {
+                match self.next_item()? {
+                    Some(item @ SystemItem::Packet(..)) => self.queued = Some(item),
+                    Some(_) => {}
+                    None => break,
+                }
+            }
+        }
+        Ok(self.system_header.as_ref())
+    }
+}
+
+impl<R: Read> std::io::Read for VideoPackets<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.detect_layer()? == Layer::Video {
+            if !self.pending.is_empty() {
+                let n = buf.len().min(self.pending.len());
+                buf[..n].copy_from_slice(&self.pending[..n]);
+                self.pending.drain(..n);
+                return Ok(n);
+            }
+            return self.source.read(buf);
+        }
+
+        while self.offset == self.packet.len() {
+            match self.next_item()? {
+                Some(SystemItem::Packet(stream_id, packet)) if stream_id == self.stream_id => {
+                    self.packet_begin += self.packet.len() as u64;
+                    self.packet = packet.data;
+                    self.offset = 0;
+                    if let Some(pts) = packet.pts {
+                        self.timestamps.lock().unwrap().push(PacketTimestamps {
+                            begin: self.packet_begin,
+                            end: self.packet_begin + self.packet.len() as u64,
+                            pts,
+                            dts: packet.dts,
+                        });
+                    }
+                }
+                Some(_) => {}
+                None => return Ok(0),
+            }
+        }
+        self.started = true;
+
+        let n = buf.len().min(self.packet.len() - self.offset);
+        buf[..n].copy_from_slice(&self.packet[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
 
 /// Provide a Reader that strips away system level packets and only
 /// returns video level data. This encapsulates parsing of system
-/// level packets and simplifies the decoder.
-pub struct MpegVideoStream {
-    cursor: std::io::Cursor<Vec<u8>>,
+/// level packets and simplifies the decoder. Sources that contain a
+/// video elementary stream instead of a system stream are detected
+/// and returned unchanged.
+///
+/// Packets are demultiplexed on demand as the video data is read.
+/// Only a bounded window of video data is kept in memory, see
+/// `Lookahead`.
+pub struct MpegVideoStream<R: Read = File> {
+    inner: Lookahead<VideoPackets<R>>,
 }
 
-impl std::io::Read for MpegVideoStream {
+impl<R: Read> std::io::Read for MpegVideoStream<R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.cursor.read(buf)
+        self.inner.read(buf)
     }
 }
 
-impl std::io::Seek for MpegVideoStream {
+impl<R: Read> std::io::Seek for MpegVideoStream<R> {
     fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
-        self.cursor.seek(pos)
+        self.inner.seek(pos)
     }
 }
 
-impl MpegVideoStream {
-    pub fn new(f: &mut File) -> MpegVideoStream {
-        let mut buf = vec![];
-        iso11172_stream(f, &mut buf).unwrap();
+impl<R: Read> MpegVideoStream<R> {
+    pub fn new(source: R) -> MpegVideoStream<R> {
         Self {
-            cursor: std::io::Cursor::<Vec<u8>>::new(buf),
+            inner: Lookahead::new(VideoPackets::new(source, None, VIDEO_STREAM_0_START_CODE)),
+        }
+    }
+
+    /// The system header of the stream. `None` for video elementary
+    /// streams. Use `SystemHeader::video_stream_ids` to find out
+    /// which video streams there are.
+    pub fn system_header(&mut self) -> Result<Option<&SystemHeader>, DecodeError> {
+        self.inner.get_mut().system_header()
+    }
+
+    /// System clock reference of the most recently read pack, in units
+    /// of the 90 kHz system clock. `None` for video elementary streams.
+    pub fn system_clock_reference(&self) -> Option<u64> {
+        self.inner.get_ref().scr
+    }
+
+    /// Time stamps of the video data returned so far.
+    pub(crate) fn timestamps(&self) -> Arc<Mutex<VideoTimestamps>> {
+        Arc::clone(&self.inner.get_ref().timestamps)
+    }
+
+    /// Selects the video stream to return, 0xE0 to 0xEF. The default
+    /// is 0xE0. Must be called before reading any data.
+    pub fn select_video_stream(&mut self, stream_id: u8) -> Result<(), DecodeError> {
+        let packets = self.inner.get_mut();
+        if !is_video_stream(stream_id) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("0x{:x} is not a video stream id", stream_id),
+            )
+            .into());
+        }
+        if packets.started {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "cannot change the video stream after reading from it",
+            )
+            .into());
+        }
+        packets.stream_id = stream_id;
+        Ok(())
+    }
+}
+
+/// Payload of one packet of an audio stream.
+pub struct AudioPacket {
+    pts: Option<u64>,
+    data: Vec<u8>,
+}
+
+impl AudioPacket {
+    /// Presentation time stamp of the first audio frame that begins
+    /// in this packet, in units of the 90 kHz system clock. Not every
+    /// packet carries one.
+    pub fn pts(&self) -> Option<u64> {
+        self.pts
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Demultiplexes one audio stream, 0xC0 to 0xDF, of a system stream.
+/// The stream is available either packet by packet, together with the
+/// time stamps, via `next_packet`, or as the raw audio elementary
+/// stream, e.g., the content of a .mp2 file, via `Read`.
+///
+/// The audio stream is read in a pass of its own, independent of the
+/// decoder of the video stream. For both, open the source twice, e.g.,
+/// the file. Sources that can only be read once, e.g., stdin, give
+/// either the video or the audio stream.
+pub struct MpegAudioStream<R: Read = File> {
+    source: R,
+    // The start of the first pack, read to check the source.
+    pending: Vec<u8>,
+    stream_id: u8,
+    packet: Vec<u8>,
+    offset: usize,
+}
+
+impl<R: Read> std::io::Read for MpegAudioStream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.offset == self.packet.len() {
+            match self.next_packet()? {
+                Some(packet) => {
+                    self.packet = packet.data;
+                    self.offset = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.packet.len() - self.offset);
+        buf[..n].copy_from_slice(&self.packet[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+impl<R: Read> MpegAudioStream<R> {
+    /// Use `SystemHeader::audio_stream_ids` to find out which audio
+    /// streams there are. Reads the start code of the first pack.
+    /// Sources that do not start with one, possibly after zero
+    /// stuffing, are no system streams and rejected.
+    pub fn new(mut source: R, stream_id: u8) -> Result<MpegAudioStream<R>, DecodeError> {
+        if !is_audio_stream(stream_id) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("0x{:x} is not an audio stream id", stream_id),
+            )
+            .into());
+        }
+
+        let mut zeros = 0;
+        let mut b = [0];
+        loop {
+            source.read_exact(&mut b)?;
+            if b[0] != 0x00 {
+                break;
+            }
+            zeros += 1;
+        }
+        let is_pack = zeros >= 2 && b[0] == 0x01 && {
+            source.read_exact(&mut b)?;
+            b[0] == PACK_START_CODE
+        };
+        if !is_pack {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "not a system stream",
+            )
+            .into());
+        }
+
+        Ok(Self {
+            source,
+            pending: vec![0x00, 0x00, 0x01, PACK_START_CODE],
+            stream_id,
+            packet: vec![],
+            offset: 0,
+        })
+    }
+
+    /// Returns the next packet of the audio stream, `None` at the end
+    /// of the system stream. If the current packet was partially
+    /// read, returns the rest of it without time stamp.
+    pub fn next_packet(&mut self) -> Result<Option<AudioPacket>, DecodeError> {
+        if self.offset < self.packet.len() {
+            let data = self.packet.split_off(self.offset);
+            self.packet.clear();
+            self.offset = 0;
+            return Ok(Some(AudioPacket { pts: None, data }));
+        }
+
+        loop {
+            let mut source = (&self.pending[..]).chain(&mut self.source);
+            let item = next_system_item(&mut source)?;
+            let unread = source.into_inner().0.len();
+            self.pending.drain(..self.pending.len() - unread);
+
+            match item {
+                Some(SystemItem::Packet(stream_id, packet)) if stream_id == self.stream_id => {
+                    return Ok(Some(AudioPacket {
+                        pts: packet.pts,
+                        data: packet.data,
+                    }));
+                }
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+/// An I-picture found by `MpegVideoStream::find_key_frame`.
+pub(crate) struct KeyFrame {
+    // Offset of the source to jump to for decoding the picture. For
+    // video elementary streams the start code of its group if it
+    // starts one, of the picture otherwise. For system streams a
+    // pack before either.
+    pub(crate) offset: u64,
+    // Offset of the source to continue searching from.
+    pub(crate) next: u64,
+    // Header of the group of pictures if the picture starts one.
+    pub(crate) group: Option<GroupOfPictures>,
+    pub(crate) temporal_reference: u16,
+    // PTS of the picture, if the system layer has one.
+    pub(crate) pts: Option<u64>,
+}
+
+impl KeyFrame {
+    /// The index entry of the picture, which belongs to the group of
+    /// pictures `group`.
+    pub(crate) fn entry(&self, group: &GroupOfPictures, frame_rate: f32) -> IndexEntry {
+        IndexEntry {
+            offset: self.offset,
+            time_code: group.time_code(self.temporal_reference, frame_rate),
+            pts: self.pts,
+            temporal_reference: self.temporal_reference,
+            group_start: self.group.is_some(),
+        }
+    }
+}
+
+impl<R: Read + Seek> MpegVideoStream<R> {
+    /// Finds the first I-picture whose start code begins at or after
+    /// offset `from` of the source. If `group_start`, only I-pictures
+    /// that start a group of pictures count. Moves the source, call
+    /// `jump` afterwards.
+    pub(crate) fn find_key_frame(
+        &mut self,
+        from: u64,
+        group_start: bool,
+    ) -> Result<Option<KeyFrame>, DecodeError> {
+        let packets = self.inner.get_mut();
+        let layer = packets.detect_layer()?;
+        packets.source.seek(SeekFrom::Start(from))?;
+        let mut source = BufReader::new(&mut packets.source);
+
+        // Demultiplexing has to start at a pack.
+        let offset = match layer {
+            Layer::System => match find_start_code(&mut source, &[PACK_START_CODE])? {
+                Some((n, _)) => {
+                    source.seek_relative(-4)?;
+                    from + n
+                }
+                None => return Ok(None),
+            },
+            Layer::Video => from,
+        };
+
+        let video = VideoPackets::new(&mut source, Some(layer), packets.stream_id);
+        let timestamps = Arc::clone(&video.timestamps);
+        let mut video = BufReader::new(video);
+        // Amount of video data read so far.
+        let mut position = 0;
+        // Offset and header of the group of pictures the next picture
+        // starts, if any.
+        let mut group = None;
+        let (picture, header) = loop {
+            let codes = [GROUP_OF_PICTURES_START_VALUE, PICTURE_START_VALUE];
+            let start_code = match find_start_code(&mut video, &codes)? {
+                Some((n, start_code)) => {
+                    position += n + 4;
+                    start_code
+                }
+                None => return Ok(None),
+            };
+            // Both headers are at least four bytes long.
+            let mut buf = [0; 4];
+            match video.read_exact(&mut buf) {
+                Ok(()) => position += 4,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+
+            if start_code == GROUP_OF_PICTURES_START_VALUE {
+                group = Some((position - 8, GroupOfPictures { raw: buf }));
+                continue;
+            }
+            let header = PictureHeader::from_prefix([buf[0], buf[1]]);
+            if header.frame_type() == FRAME_TYPE_I && (group.is_some() || !group_start) {
+                break (position - 8, header);
+            }
+            // The first picture of a group is always an I-picture.
+            group = None;
+        };
+        let pts = timestamps.lock().unwrap().take(picture).map(|(pts, _)| pts);
+        drop(video);
+
+        let (offset, next) = match layer {
+            Layer::System => (offset, source.stream_position()?.max(offset + 1)),
+            Layer::Video => (
+                from + group.as_ref().map_or(picture, |(offset, _)| *offset),
+                from + picture + 4,
+            ),
+        };
+        Ok(Some(KeyFrame {
+            offset,
+            next,
+            group: group.map(|(_, header)| header),
+            temporal_reference: header.sequence_nr(),
+            pts,
+        }))
+    }
+
+    /// Finds the first group of pictures that starts at or after
+    /// offset `from` of the source, see `find_key_frame`. Returns its
+    /// I-picture and header.
+    pub(crate) fn find_group(
+        &mut self,
+        from: u64,
+    ) -> Result<Option<(KeyFrame, GroupOfPictures)>, DecodeError> {
+        Ok(self
+            .find_key_frame(from, true)?
+            .and_then(|key_frame| key_frame.group.map(|group| (key_frame, group))))
+    }
+
+    /// Length of the source in bytes. Moves the source, call `jump`
+    /// afterwards.
+    pub(crate) fn source_len(&mut self) -> Result<u64, DecodeError> {
+        Ok(self.inner.get_mut().source.seek(SeekFrom::End(0))?)
+    }
+
+    /// Continues with the data at `offset` of the source, which must
+    /// be the start of a pack for system streams.
+    pub(crate) fn jump(&mut self, offset: u64) -> Result<(), DecodeError> {
+        self.inner.reset();
+        let begin = self.inner.stream_position()?;
+        let packets = self.inner.get_mut();
+        packets.source.seek(SeekFrom::Start(offset))?;
+        packets.pending.clear();
+        packets.queued = None;
+        packets.packet_begin = begin;
+        packets.packet.clear();
+        packets.offset = 0;
+        packets.timestamps.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+/// Reads up to and including the next of the start codes `codes`.
+/// Returns the number of bytes before it and the start code or
+/// `None` if the end of the source came first.
+fn find_start_code<R: Read>(r: &mut R, codes: &[u8]) -> std::io::Result<Option<(u64, u8)>> {
+    let mut n = 0;
+    let mut window = [0xFF; 4];
+    loop {
+        let mut b = [0];
+        match r.read(&mut b) {
+            Ok(0) => return Ok(None),
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+        window.copy_within(1.., 0);
+        window[3] = b[0];
+        n += 1;
+        if window[..3] == [0x00, 0x00, 0x01] && codes.contains(&window[3]) {
+            return Ok(Some((n - 4, window[3])));
         }
     }
 }
@@ -34,19 +599,204 @@ impl MpegVideoStream {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::{Read, Seek};
+    use crate::timestamp_bytes;
+    use std::io::{Cursor, Read, Seek};
+
+    /// A system stream with one pack per video packet. Each payload
+    /// is followed by a padding packet.
+    fn system_stream(payloads: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = vec![];
+        for payload in payloads {
+            buf.extend([0x00, 0x00, 0x01, PACK_START_CODE]);
+            buf.extend([0x21, 0x00, 0x01, 0x00, 0x01, 0x80, 0x00, 0x01]);
+            for (stream_id, data) in [
+                (VIDEO_STREAM_0_START_CODE, &payload[..]),
+                (0xbe, &[0xff; 3]),
+            ] {
+                buf.extend([0x00, 0x00, 0x01, stream_id]);
+                buf.extend((data.len() as u16 + 1).to_be_bytes());
+                buf.push(0x0f);
+                buf.extend(data);
+            }
+        }
+        buf.extend([0x00, 0x00, 0x01, 0xb9]);
+        buf
+    }
 
     #[test]
     fn test1() {
-        let mut file = File::open("tests/bjork-v2-short-2.mpg").unwrap();
-        let mut vs = MpegVideoStream::new(&mut file);
+        let file = File::open("tests/bjork-v2-short-2.mpg").unwrap();
+        let mut vs = MpegVideoStream::new(file);
         let mut buf = [0; 8];
-        vs.read(&mut buf).unwrap();
+        vs.read_exact(&mut buf).unwrap();
         // Can only seek backwards.
         // assert_eq!(vs.seek(SeekFrom::Current(1)).unwrap_err().kind(),
         //            std::io::ErrorKind::Unsupported);
         vs.seek(SeekFrom::Current(-4)).unwrap();
         let mut buf2 = [0; 4];
-        vs.read(&mut buf2).unwrap();
+        vs.read_exact(&mut buf2).unwrap();
+    }
+
+    #[test]
+    fn test_demux() {
+        let payloads = vec![vec![1, 2, 3], vec![4, 5], vec![6]];
+        let mut vs = MpegVideoStream::new(Cursor::new(system_stream(&payloads)));
+        let mut data = vec![];
+        vs.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [1, 2, 3, 4, 5, 6]);
+
+        vs.seek(SeekFrom::Current(-4)).unwrap();
+        let mut buf = [0; 2];
+        vs.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [3, 4]);
+        assert_eq!(vs.stream_position().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_select_video_stream() {
+        let mut buf = vec![0x00, 0x00, 0x01, PACK_START_CODE];
+        buf.extend([0x21, 0x00, 0x01, 0x00, 0x01, 0x80, 0x00, 0x01]);
+        // System header listing an audio and two video streams.
+        buf.extend([0x00, 0x00, 0x01, 0xbb, 0x00, 0x0f]);
+        buf.extend([0x80, 0x1b, 0x83, 0x04, 0xe1, 0xff]);
+        buf.extend([0xc0, 0xc0, 0x20, 0xe0, 0xe0, 0x2e, 0xe1, 0xe0, 0x2e]);
+        for (stream_id, payload) in [(0xe0, [1, 2]), (0xc0, [3, 4]), (0xe1, [5, 6])] {
+            buf.extend([0x00, 0x00, 0x01, stream_id, 0x00, 0x03, 0x0f]);
+            buf.extend(payload);
+        }
+
+        let mut vs = MpegVideoStream::new(&buf[..]);
+        let hdr = vs.system_header().unwrap().unwrap();
+        assert_eq!(hdr.stream_ids(), [0xc0, 0xe0, 0xe1]);
+        assert_eq!(hdr.video_stream_ids(), [0xe0, 0xe1]);
+        assert!(vs.select_video_stream(0xc0).is_err());
+        vs.select_video_stream(0xe1).unwrap();
+        let mut data = vec![];
+        vs.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [5, 6]);
+        assert!(vs.select_video_stream(0xe0).is_err());
+
+        // Without selection, the first video stream is returned.
+        let mut vs = MpegVideoStream::new(&buf[..]);
+        let mut data = vec![];
+        vs.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [1, 2]);
+    }
+
+    #[test]
+    fn test_audio_stream() {
+        let mut buf = vec![0x00, 0x00, 0x01, PACK_START_CODE];
+        buf.extend([0x21, 0x00, 0x01, 0x00, 0x01, 0x80, 0x00, 0x01]);
+        // Audio packet with PTS.
+        buf.extend([0x00, 0x00, 0x01, 0xc0, 0x00, 0x08]);
+        buf.extend(timestamp_bytes(0b0010, 0x1_2345_6789));
+        buf.extend([1, 2, 3]);
+        buf.extend([0x00, 0x00, 0x01, 0xe0, 0x00, 0x02, 0x0f, 9]);
+        // Second audio stream, then STD buffer fields, PTS and DTS.
+        buf.extend([0x00, 0x00, 0x01, 0xc1, 0x00, 0x02, 0x0f, 8]);
+        buf.extend([0x00, 0x00, 0x01, 0xc0, 0x00, 0x0f, 0xff, 0x40, 0x20]);
+        buf.extend(timestamp_bytes(0b0011, 90000));
+        buf.extend(timestamp_bytes(0b0001, 89000));
+        buf.extend([4, 5]);
+        buf.extend([0x00, 0x00, 0x01, 0xc0, 0x00, 0x02, 0x0f, 6]);
+        buf.extend([0x00, 0x00, 0x01, 0xb9]);
+
+        let mut audio = MpegAudioStream::new(&buf[..], 0xc0).unwrap();
+        let mut packets = vec![];
+        while let Some(packet) = audio.next_packet().unwrap() {
+            packets.push((packet.pts(), packet.into_data()));
+        }
+        assert_eq!(
+            packets,
+            [
+                (Some(0x1_2345_6789), vec![1, 2, 3]),
+                (Some(90000), vec![4, 5]),
+                (None, vec![6]),
+            ]
+        );
+
+        let mut audio = MpegAudioStream::new(&buf[..], 0xc0).unwrap();
+        let mut byte = [0; 1];
+        audio.read_exact(&mut byte).unwrap();
+        let rest = audio.next_packet().unwrap().unwrap();
+        assert_eq!((rest.pts(), rest.data()), (None, &[2, 3][..]));
+        let mut data = vec![];
+        audio.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [4, 5, 6]);
+
+        let mut audio = MpegAudioStream::new(&buf[..], 0xc1).unwrap();
+        let mut data = vec![];
+        audio.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [8]);
+
+        assert!(MpegAudioStream::new(&buf[..], 0xe0).is_err());
+
+        // Zero stuffing may come first.
+        let stuffed = [&[0x00; 3][..], &buf].concat();
+        let mut audio = MpegAudioStream::new(&stuffed[..], 0xc1).unwrap();
+        assert_eq!(audio.next_packet().unwrap().unwrap().data(), [8]);
+    }
+
+    #[test]
+    fn test_audio_stream_source() {
+        // Video and audio elementary streams have no audio packets.
+        let video = [0x00, 0x00, 0x01, 0xb3, 0x14, 0x00, 0xf0, 0x13];
+        let audio = [0xff, 0xfd, 0xe4, 0xc0, 0x00, 0x00, 0x01, PACK_START_CODE];
+        for source in [&video[..], &audio] {
+            assert!(matches!(
+                MpegAudioStream::new(source, 0xc0),
+                Err(DecodeError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidInput
+            ));
+        }
+        assert!(MpegAudioStream::new(&[][..], 0xc0).err().unwrap().is_eof());
+    }
+
+    #[test]
+    fn test_elementary_stream() {
+        let buf = [0x00, 0x00, 0x01, 0xb3, 0x14, 0x00, 0xf0, 0x13];
+        let mut vs = MpegVideoStream::new(&buf[..]);
+        let mut data = vec![];
+        vs.read_to_end(&mut data).unwrap();
+        assert_eq!(data, buf);
+        assert_eq!(vs.inner.get_ref().layer, Some(Layer::Video));
+
+        // Zero stuffing before the sequence header is passed on.
+        let stuffed = [&[0x00, 0x00][..], &buf].concat();
+        let mut vs = MpegVideoStream::new(&stuffed[..]);
+        let mut data = vec![];
+        vs.read_to_end(&mut data).unwrap();
+        assert_eq!(data, stuffed);
+
+        // Too short to hold a start code.
+        let mut vs = MpegVideoStream::new(&buf[..2]);
+        let e = vs.read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        // Neither a pack nor a sequence header.
+        let picture = [0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x08];
+        let mut vs = MpegVideoStream::new(&picture[..]);
+        let e = vs.read_to_end(&mut vec![]).unwrap_err();
+        assert!(matches!(
+            DecodeError::from(e),
+            DecodeError::UnexpectedStartCode(0x00)
+        ));
+
+        // System streams may be stuffed as well.
+        let system = [&[0x00][..], &system_stream(&[vec![1, 2, 3]])].concat();
+        let mut vs = MpegVideoStream::new(&system[..]);
+        let mut data = vec![];
+        vs.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_demux_error() {
+        let mut buf = system_stream(&[vec![1, 2, 3]]);
+        // Cut the padding packet short.
+        buf.truncate(buf.len() - 6);
+        let mut vs = MpegVideoStream::new(&buf[..]);
+        let mut data = vec![];
+        let e = vs.read_to_end(&mut data).unwrap_err();
+        assert!(matches!(DecodeError::from(e), DecodeError::TruncatedPacket));
     }
 }
@@ -0,0 +1,28 @@
+use super::{Frame, FrameWriter, I420Writer, MpegDecoder};
+
+/// Decodes all frames of a video elementary or system stream.
+pub(crate) fn decode(stream: &[u8]) -> Vec<Frame> {
+    MpegDecoder::from_stream(stream)
+        .unwrap()
+        .map(Result::unwrap)
+        .collect()
+}
+
+/// The displayed part of `frame` in I420 layout.
+pub(crate) fn i420(frame: &Frame) -> Vec<u8> {
+    let mut w = I420Writer::new(vec![]);
+    w.write_frame(frame).unwrap();
+    w.into_inner()
+}
+
+/// Peak signal-to-noise ratio between two pictures in dB.
+pub(crate) fn psnr(a: &[u8], b: &[u8]) -> f64 {
+    assert_eq!(a.len(), b.len());
+    let mse = a
+        .iter()
+        .zip(b)
+        .map(|(&a, &b)| (f64::from(a) - f64::from(b)).powi(2))
+        .sum::<f64>()
+        / a.len() as f64;
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
@@ -0,0 +1,411 @@
+use super::checksum::crc32_update;
+use super::{Frame, FrameProcessor, PixelFormat, Plane};
+use std::io::{self, Write};
+
+/// Writes decoded frames in some image or video format.
+pub trait FrameWriter {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()>;
+}
+
+/// A color component of a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Component {
+    Y,
+    Cb,
+    Cr,
+}
+
+impl Component {
+    fn plane(self, frame: &Frame) -> &Plane {
+        match self {
+            Component::Y => frame.y(),
+            Component::Cb => frame.cb(),
+            Component::Cr => frame.cr(),
+        }
+    }
+
+    /// Displayed width and height of the component. The chroma
+    /// planes are subsampled by two, rounding up.
+    fn size(self, frame: &Frame) -> (usize, usize) {
+        match self {
+            Component::Y => (frame.width(), frame.height()),
+            Component::Cb | Component::Cr => {
+                (frame.width().div_ceil(2), frame.height().div_ceil(2))
+            }
+        }
+    }
+}
+
+/// Writes the displayed part of a component, row by row.
+fn write_component<W: Write>(w: &mut W, frame: &Frame, component: Component) -> io::Result<()> {
+    let plane = component.plane(frame);
+    let (width, height) = component.size(frame);
+    for row in 0..height {
+        w.write_all(&plane.row(row)[..width])?;
+    }
+    Ok(())
+}
+
+/// Binary portable pixmaps (P6) in RGB. Writing several frames
+/// produces a sequence of images.
+pub struct PpmWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PpmWriter<W> {
+    pub fn new(writer: W) -> Self {
+        PpmWriter { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> FrameWriter for PpmWriter<W> {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        write!(
+            self.writer,
+            "P6\n{} {}\n255\n",
+            frame.width(),
+            frame.height()
+        )?;
+        self.writer.write_all(&frame.to_rgb())
+    }
+}
+
+/// Binary portable graymaps (P5) of one component of the frames,
+/// without color conversion.
+pub struct PgmWriter<W: Write> {
+    writer: W,
+    component: Component,
+}
+
+impl<W: Write> PgmWriter<W> {
+    pub fn new(writer: W, component: Component) -> Self {
+        PgmWriter { writer, component }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> FrameWriter for PgmWriter<W> {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let (width, height) = self.component.size(frame);
+        write!(self.writer, "P5\n{} {}\n255\n", width, height)?;
+        write_component(&mut self.writer, frame, self.component)
+    }
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// Largest block of uncompressed deflate data.
+const MAX_STORED_BLOCK: usize = 65535;
+
+/// RGB PNG images. The image data is stored without compression,
+/// which needs no more than the standard library.
+pub struct PngWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PngWriter<W> {
+    pub fn new(writer: W) -> Self {
+        PngWriter { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_chunk(&mut self, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+        self.writer.write_all(&(data.len() as u32).to_be_bytes())?;
+        self.writer.write_all(kind)?;
+        self.writer.write_all(data)?;
+        let crc = crc32_update(crc32_update(!0, kind), data);
+        self.writer.write_all(&(!crc).to_be_bytes())
+    }
+}
+
+impl<W: Write> FrameWriter for PngWriter<W> {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let (width, height) = (frame.width() as u32, frame.height() as u32);
+        let mut header = Vec::with_capacity(13);
+        header.extend(width.to_be_bytes());
+        header.extend(height.to_be_bytes());
+        // 8 bit RGB, deflate, no filtering, not interlaced.
+        header.extend([8, 2, 0, 0, 0]);
+
+        // Every row starts with its filter type, 0 for none. The
+        // conversion leaves these bytes alone.
+        let stride = frame.width() * 3;
+        let mut image = vec![0; (stride + 1) * frame.height()];
+        if !image.is_empty() {
+            frame.convert_into(PixelFormat::Rgb24, &mut image[1..], stride + 1)?;
+        }
+
+        self.writer.write_all(&PNG_SIGNATURE)?;
+        self.write_chunk(b"IHDR", &header)?;
+        self.write_chunk(b"IDAT", &zlib_stored(&image))?;
+        self.write_chunk(b"IEND", &[])
+    }
+}
+
+/// Wraps `data` into a zlib stream of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let blocks = data.len().div_ceil(MAX_STORED_BLOCK).max(1);
+    let mut out = Vec::with_capacity(2 + blocks * 5 + data.len() + 4);
+    // Deflate with a 32 KiB window, no preset dictionary.
+    out.extend([0x78, 0x01]);
+    let mut chunks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if chunks.peek().is_none() {
+        out.extend([0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        out.push(u8::from(last));
+        out.extend((chunk.len() as u16).to_le_bytes());
+        out.extend((!(chunk.len() as u16)).to_le_bytes());
+        out.extend(chunk);
+    }
+    out.extend(adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // Sums of 5552 bytes cannot overflow before the modulo.
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+/// YUV4MPEG2 video of the native 4:2:0 planes. The stream header is
+/// written with the first frame, all frames need to be of the same
+/// size.
+pub struct Y4mWriter<W: Write> {
+    writer: W,
+    // Frame rate as a fraction.
+    frame_rate: (u32, u32),
+    header_written: bool,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    /// The frame rate is given as numerator and denominator, e.g.,
+    /// (30000, 1001) for 29.97 frames/s.
+    pub fn new(writer: W, frame_rate: (u32, u32)) -> Self {
+        Y4mWriter {
+            writer,
+            frame_rate,
+            header_written: false,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> FrameWriter for Y4mWriter<W> {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        if !self.header_written {
+            // MPEG-1 sites chroma samples between the luma samples,
+            // like JPEG.
+            writeln!(
+                self.writer,
+                "YUV4MPEG2 W{} H{} F{}:{} Ip A0:0 C420jpeg",
+                frame.width(),
+                frame.height(),
+                self.frame_rate.0,
+                self.frame_rate.1
+            )?;
+            self.header_written = true;
+        }
+        self.writer.write_all(b"FRAME\n")?;
+        I420Writer::new(&mut self.writer).write_frame(frame)
+    }
+}
+
+/// Raw I420 of the displayed part of the frames: the Y, Cb and Cr
+/// planes one after the other, without any header. Meant for bit
+/// exact comparisons with other decoders.
+pub struct I420Writer<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> I420Writer<W> {
+    pub fn new(writer: W) -> Self {
+        I420Writer { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> FrameWriter for I420Writer<W> {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        for component in [Component::Y, Component::Cb, Component::Cr] {
+            write_component(&mut self.writer, frame, component)?;
+        }
+        Ok(())
+    }
+}
+
+/// Hands the frames of `MpegDecoder::parse_mpeg` to a `FrameWriter`.
+/// Frames after the first error are dropped, `finish` reports it.
+pub struct WriteFrames<T: FrameWriter> {
+    writer: T,
+    error: Option<io::Error>,
+}
+
+impl<T: FrameWriter> WriteFrames<T> {
+    pub fn new(writer: T) -> Self {
+        WriteFrames {
+            writer,
+            error: None,
+        }
+    }
+
+    /// Returns the writer, or the first error it ran into.
+    pub fn finish(self) -> io::Result<T> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.writer),
+        }
+    }
+}
+
+impl<T: FrameWriter> FrameProcessor for WriteFrames<T> {
+    fn process(&mut self, frame: &Frame) {
+        if self.error.is_none() {
+            self.error = self.writer.write_frame(frame).err();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 3x2 frame with distinct samples in the displayed part of
+    /// every plane.
+    fn frame() -> Frame {
+        let mut frame = Frame::new(3, 2);
+        frame.y.data[..3].copy_from_slice(&[16, 235, 81]);
+        frame.y.data[16..19].copy_from_slice(&[20, 21, 22]);
+        frame.cb.data[..2].copy_from_slice(&[128, 90]);
+        frame.cr.data[..2].copy_from_slice(&[128, 240]);
+        frame
+    }
+
+    #[test]
+    fn test_ppm() {
+        let frame = frame();
+        let mut w = PpmWriter::new(vec![]);
+        w.write_frame(&frame).unwrap();
+        let out = w.into_inner();
+        assert_eq!(&out[..11], b"P6\n3 2\n255\n");
+        assert_eq!(out[11..], frame.to_rgb());
+    }
+
+    #[test]
+    fn test_pgm() {
+        let frame = frame();
+        let mut w = PgmWriter::new(vec![], Component::Y);
+        w.write_frame(&frame).unwrap();
+        assert_eq!(w.into_inner(), b"P5\n3 2\n255\n\x10\xeb\x51\x14\x15\x16");
+
+        let mut w = PgmWriter::new(vec![], Component::Cr);
+        w.write_frame(&frame).unwrap();
+        assert_eq!(w.into_inner(), b"P5\n2 1\n255\n\x80\xf0");
+    }
+
+    #[test]
+    fn test_y4m() {
+        let frame = frame();
+        let mut w = Y4mWriter::new(vec![], (25, 1));
+        w.write_frame(&frame).unwrap();
+        w.write_frame(&frame).unwrap();
+        let out = w.into_inner();
+        let header = b"YUV4MPEG2 W3 H2 F25:1 Ip A0:0 C420jpeg\n";
+        assert_eq!(&out[..header.len()], header);
+        let frame_data = b"FRAME\n\x10\xeb\x51\x14\x15\x16\x80\x5a\x80\xf0";
+        assert_eq!(&out[header.len()..], [&frame_data[..], frame_data].concat());
+    }
+
+    #[test]
+    fn test_i420() {
+        let frame = frame();
+        let mut frames = WriteFrames::new(I420Writer::new(vec![]));
+        frames.process(&frame);
+        frames.process(&frame);
+        let out = frames.finish().unwrap().into_inner();
+        let frame_data = b"\x10\xeb\x51\x14\x15\x16\x80\x5a\x80\xf0";
+        assert_eq!(out, [&frame_data[..], frame_data].concat());
+
+        // Writing stops at the first error.
+        let mut buf = [0; 12];
+        let mut frames = WriteFrames::new(I420Writer::new(&mut buf[..]));
+        frames.process(&frame);
+        frames.process(&frame);
+        assert!(frames.finish().is_err());
+        assert_eq!(&buf[10..], [0x10, 0xeb]);
+    }
+
+    #[test]
+    fn test_png() {
+        let frame = frame();
+        let mut w = PngWriter::new(vec![]);
+        w.write_frame(&frame).unwrap();
+        let out = w.into_inner();
+        assert_eq!(out[..8], PNG_SIGNATURE);
+
+        // Walk the chunks and check their CRCs.
+        let mut chunks = vec![];
+        let mut rest = &out[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..8 + len]);
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(!crc32_update(crc32_update(!0, kind), data), crc);
+            chunks.push((kind.to_vec(), data.to_vec()));
+            rest = &rest[12 + len..];
+        }
+        let kinds: Vec<_> = chunks.iter().map(|(kind, _)| &kind[..]).collect();
+        assert_eq!(kinds, [&b"IHDR"[..], b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 3, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+
+        // A single stored block holds both rows behind their filter
+        // type.
+        let rgb = frame.to_rgb();
+        let image = [&[0][..], &rgb[..9], &[0], &rgb[9..]].concat();
+        let idat = &chunks[1].1;
+        assert_eq!(idat[..7], [0x78, 0x01, 0x01, 20, 0, !20, 0xff]);
+        assert_eq!(idat[7..27], image);
+        assert_eq!(idat[27..], adler32(&image).to_be_bytes());
+    }
+
+    #[test]
+    fn test_zlib_stored() {
+        // Reference value of the zlib library.
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+
+        let data: Vec<u8> = (0..MAX_STORED_BLOCK + 10).map(|i| i as u8).collect();
+        let out = zlib_stored(&data);
+        assert_eq!(out.len(), 2 + 2 * 5 + data.len() + 4);
+        assert_eq!(out[2], 0);
+        assert_eq!(out[2 + 5 + MAX_STORED_BLOCK..][..5], [1, 10, 0, 0xf5, 0xff]);
+        assert_eq!(
+            zlib_stored(&[]),
+            [0x78, 0x01, 1, 0, 0, 0xff, 0xff, 0, 0, 0, 1]
+        );
+    }
+}
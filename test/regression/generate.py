@@ -0,0 +1,525 @@
+#!/usr/bin/env python3
+"""Generates the bitstreams of the regression test.
+
+The streams are syntactically valid MPEG-1 video with random content,
+built to cover the decoder's code paths: loaded quantizer matrices,
+quantizer changes, DC and AC coefficients including escapes, skipped
+macroblocks, forward, backward and interpolated motion compensation
+with half-pel vectors and f_code > 1, intra macroblocks in predicted
+pictures, B-picture reordering and a system stream around it all.
+
+The variable length codes are typed in from ISO 11172-2 Annex B and
+do not depend on the decoder. Run from the repository root:
+
+    python3 test/regression/generate.py
+
+The output is deterministic. After changing it, regenerate the golden
+checksums as described in tests/regression.rs.
+"""
+
+import os
+import random
+
+OUT = os.path.dirname(os.path.abspath(__file__))
+
+I, P, B = 1, 2, 3
+
+QUANT, FORWARD, BACKWARD, PATTERN, INTRA = 1, 2, 4, 8, 16
+
+ZIG_ZAG = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5,
+    12, 19, 26, 33, 40, 48, 41, 34, 27, 20, 13, 6, 7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+]
+
+
+def table(codes):
+    """Strips the spaces that group the bits of the codes."""
+    return {value: code.replace(" ", "") for value, code in codes.items()}
+
+
+# Variable length codes of ISO 11172-2 Annex B, from value to code.
+# They are typed in from the standard rather than taken from the
+# decoder, so that the streams do not inherit mistakes of its tables.
+
+# Table B.1, macroblock_address_increment. Escape and stuffing are not
+# used.
+ADDRESS_INCREMENT = table({
+    1: "1", 2: "011", 3: "010", 4: "0011", 5: "0010", 6: "0001 1",
+    7: "0001 0", 8: "0000 111", 9: "0000 110", 10: "0000 1011",
+    11: "0000 1010", 12: "0000 1001", 13: "0000 1000", 14: "0000 0111",
+    15: "0000 0110", 16: "0000 0101 11", 17: "0000 0101 10",
+    18: "0000 0101 01", 19: "0000 0101 00", 20: "0000 0100 11",
+    21: "0000 0100 10", 22: "0000 0100 011", 23: "0000 0100 010",
+    24: "0000 0100 001", 25: "0000 0100 000", 26: "0000 0011 111",
+    27: "0000 0011 110", 28: "0000 0011 101", 29: "0000 0011 100",
+    30: "0000 0011 011", 31: "0000 0011 010", 32: "0000 0011 001",
+    33: "0000 0011 000",
+})
+
+# Tables B.2a to B.2c, macroblock_type in I-, P- and B-pictures.
+TYPE_I = table({
+    INTRA: "1",
+    INTRA | QUANT: "01",
+})
+TYPE_P = table({
+    FORWARD | PATTERN: "1",
+    PATTERN: "01",
+    FORWARD: "001",
+    INTRA: "0001 1",
+    FORWARD | PATTERN | QUANT: "0001 0",
+    PATTERN | QUANT: "0000 1",
+    INTRA | QUANT: "0000 01",
+})
+TYPE_B = table({
+    FORWARD | BACKWARD: "10",
+    FORWARD | BACKWARD | PATTERN: "11",
+    BACKWARD: "010",
+    BACKWARD | PATTERN: "011",
+    FORWARD: "0010",
+    FORWARD | PATTERN: "0011",
+    INTRA: "0001 1",
+    FORWARD | BACKWARD | PATTERN | QUANT: "0001 0",
+    FORWARD | PATTERN | QUANT: "0000 11",
+    BACKWARD | PATTERN | QUANT: "0000 10",
+    INTRA | QUANT: "0000 01",
+})
+
+# Table B.3, coded_block_pattern.
+CBP = table({
+    60: "111", 4: "1101", 8: "1100", 16: "1011", 32: "1010",
+    12: "1001 1", 48: "1001 0", 20: "1000 1", 40: "1000 0",
+    28: "0111 1", 44: "0111 0", 52: "0110 1", 56: "0110 0",
+    1: "0101 1", 61: "0101 0", 2: "0100 1", 62: "0100 0",
+    24: "0011 11", 36: "0011 10", 3: "0011 01", 63: "0011 00",
+    5: "0010 111", 9: "0010 110", 17: "0010 101", 33: "0010 100",
+    6: "0010 011", 10: "0010 010", 18: "0010 001", 34: "0010 000",
+    7: "0001 1111", 11: "0001 1110", 19: "0001 1101", 35: "0001 1100",
+    13: "0001 1011", 49: "0001 1010", 21: "0001 1001", 41: "0001 1000",
+    14: "0001 0111", 50: "0001 0110", 22: "0001 0101", 42: "0001 0100",
+    15: "0001 0011", 51: "0001 0010", 23: "0001 0001", 43: "0001 0000",
+    25: "0000 1111", 37: "0000 1110", 26: "0000 1101", 38: "0000 1100",
+    29: "0000 1011", 45: "0000 1010", 53: "0000 1001", 57: "0000 1000",
+    30: "0000 0111", 46: "0000 0110", 54: "0000 0101", 58: "0000 0100",
+    31: "0000 0011 1", 47: "0000 0011 0", 55: "0000 0010 1",
+    59: "0000 0010 0", 27: "0000 0001 1", 39: "0000 0001 0",
+})
+
+# Table B.4, motion codes. The last bit is the sign.
+MOTION = table({
+    0: "1", 1: "010", 2: "0010", 3: "0001 0", 4: "0000 110",
+    5: "0000 1010", 6: "0000 1000", 7: "0000 0110", 8: "0000 0101 10",
+    9: "0000 0101 00", 10: "0000 0100 10", 11: "0000 0100 010",
+    12: "0000 0100 000", 13: "0000 0011 110", 14: "0000 0011 100",
+    15: "0000 0011 010", 16: "0000 0011 000",
+})
+MOTION.update({-value: code[:-1] + "1" for value, code in MOTION.items() if value})
+
+# Tables B.5a and B.5b, dct_dc_size_luminance and _chrominance.
+DC_LUMA = table({
+    0: "100", 1: "00", 2: "01", 3: "101", 4: "110", 5: "1110",
+    6: "1111 0", 7: "1111 10", 8: "1111 110",
+})
+DC_CHROMA = table({
+    0: "00", 1: "01", 2: "10", 3: "110", 4: "1110", 5: "1111 0",
+    6: "1111 10", 7: "1111 110", 8: "1111 1110",
+})
+
+# Table B.5c, dct_coeff_first and dct_coeff_next as (run, level)
+# without the sign bit. (0, 1) is "1" as the first coefficient of
+# non-intra blocks, see Encoder.block.
+COEFF = table({
+    (0, 1): "11", (1, 1): "011", (0, 2): "0100", (2, 1): "0101",
+    (0, 3): "0010 1", (3, 1): "0011 1", (4, 1): "0011 0",
+    (1, 2): "0001 10", (5, 1): "0001 11", (6, 1): "0001 01",
+    (7, 1): "0001 00", (0, 4): "0000 110", (2, 2): "0000 100",
+    (8, 1): "0000 111", (9, 1): "0000 101",
+    (0, 5): "0010 0110", (0, 6): "0010 0001", (1, 3): "0010 0101",
+    (3, 2): "0010 0100", (10, 1): "0010 0111", (11, 1): "0010 0011",
+    (12, 1): "0010 0010", (13, 1): "0010 0000",
+    (0, 7): "0000 0010 10", (1, 4): "0000 0011 00",
+    (2, 3): "0000 0010 11", (4, 2): "0000 0011 11",
+    (5, 2): "0000 0010 01", (14, 1): "0000 0011 10",
+    (15, 1): "0000 0011 01", (16, 1): "0000 0010 00",
+    (0, 8): "0000 0001 1101", (0, 9): "0000 0001 1000",
+    (0, 10): "0000 0001 0011", (0, 11): "0000 0001 0000",
+    (1, 5): "0000 0001 1011", (2, 4): "0000 0001 0100",
+    (3, 3): "0000 0001 1100", (4, 3): "0000 0001 0010",
+    (6, 2): "0000 0001 1110", (7, 2): "0000 0001 0101",
+    (8, 2): "0000 0001 0001", (17, 1): "0000 0001 1111",
+    (18, 1): "0000 0001 1010", (19, 1): "0000 0001 1001",
+    (20, 1): "0000 0001 0111", (21, 1): "0000 0001 0110",
+    (0, 12): "0000 0000 1101 0", (0, 13): "0000 0000 1100 1",
+    (0, 14): "0000 0000 1100 0", (0, 15): "0000 0000 1011 1",
+    (1, 6): "0000 0000 1011 0", (1, 7): "0000 0000 1010 1",
+    (2, 5): "0000 0000 1010 0", (3, 4): "0000 0000 1001 1",
+    (5, 3): "0000 0000 1001 0", (9, 2): "0000 0000 1000 1",
+    (10, 2): "0000 0000 1000 0", (22, 1): "0000 0000 1111 1",
+    (23, 1): "0000 0000 1111 0", (24, 1): "0000 0000 1110 1",
+    (25, 1): "0000 0000 1110 0", (26, 1): "0000 0000 1101 1",
+    (0, 16): "0000 0000 0111 11", (0, 17): "0000 0000 0111 10",
+    (0, 18): "0000 0000 0111 01", (0, 19): "0000 0000 0111 00",
+    (0, 20): "0000 0000 0110 11", (0, 21): "0000 0000 0110 10",
+    (0, 22): "0000 0000 0110 01", (0, 23): "0000 0000 0110 00",
+    (0, 24): "0000 0000 0101 11", (0, 25): "0000 0000 0101 10",
+    (0, 26): "0000 0000 0101 01", (0, 27): "0000 0000 0101 00",
+    (0, 28): "0000 0000 0100 11", (0, 29): "0000 0000 0100 10",
+    (0, 30): "0000 0000 0100 01", (0, 31): "0000 0000 0100 00",
+    (0, 32): "0000 0000 0011 000", (0, 33): "0000 0000 0010 111",
+    (0, 34): "0000 0000 0010 110", (0, 35): "0000 0000 0010 101",
+    (0, 36): "0000 0000 0010 100", (0, 37): "0000 0000 0010 011",
+    (0, 38): "0000 0000 0010 010", (0, 39): "0000 0000 0010 001",
+    (0, 40): "0000 0000 0010 000", (1, 8): "0000 0000 0011 111",
+    (1, 9): "0000 0000 0011 110", (1, 10): "0000 0000 0011 101",
+    (1, 11): "0000 0000 0011 100", (1, 12): "0000 0000 0011 011",
+    (1, 13): "0000 0000 0011 010", (1, 14): "0000 0000 0011 001",
+    (1, 15): "0000 0000 0001 0011", (1, 16): "0000 0000 0001 0010",
+    (1, 17): "0000 0000 0001 0001", (1, 18): "0000 0000 0001 0000",
+    (6, 3): "0000 0000 0001 0100", (11, 2): "0000 0000 0001 1010",
+    (12, 2): "0000 0000 0001 1001", (13, 2): "0000 0000 0001 1000",
+    (14, 2): "0000 0000 0001 0111", (15, 2): "0000 0000 0001 0110",
+    (16, 2): "0000 0000 0001 0101", (27, 1): "0000 0000 0001 1111",
+    (28, 1): "0000 0000 0001 1110", (29, 1): "0000 0000 0001 1101",
+    (30, 1): "0000 0000 0001 1100", (31, 1): "0000 0000 0001 1011",
+})
+
+
+class BitWriter:
+    def __init__(self):
+        self.bits = []
+
+    def code(self, code):
+        self.bits.extend(int(b) for b in code)
+
+    def put(self, value, n):
+        for i in reversed(range(n)):
+            self.bits.append((value >> i) & 1)
+
+    def start_code(self, code):
+        self.align()
+        self.put(0x000001, 24)
+        self.put(code, 8)
+
+    def align(self):
+        while len(self.bits) % 8:
+            self.bits.append(0)
+
+    def bytes(self):
+        self.align()
+        return bytes(
+            int("".join(map(str, self.bits[i:i + 8])), 2)
+            for i in range(0, len(self.bits), 8)
+        )
+
+
+class Encoder:
+    def __init__(self, rng, width, height, quant_matrices=False):
+        self.rng = rng
+        self.width = width
+        self.height = height
+        self.mb_width = (width + 15) // 16
+        self.mb_height = (height + 15) // 16
+        self.quant_matrices = quant_matrices
+        self.w = BitWriter()
+
+    def sequence_header(self):
+        w = self.w
+        w.start_code(0xB3)
+        w.put(self.width, 12)
+        w.put(self.height, 12)
+        w.put(1, 4)  # square pixels
+        w.put(3, 4)  # 25 frames/s
+        w.put(1000, 18)
+        w.put(1, 1)
+        w.put(20, 10)
+        w.put(0, 1)
+        w.put(int(self.quant_matrices), 1)
+        if self.quant_matrices:
+            intra = [8] + [self.rng.randint(8, 80) for _ in range(63)]
+            for i in range(64):
+                w.put(intra[i], 8)
+        w.put(int(self.quant_matrices), 1)
+        if self.quant_matrices:
+            for i in range(64):
+                w.put(self.rng.randint(8, 40), 8)
+
+    def group(self, frame, closed):
+        w = self.w
+        w.start_code(0xB8)
+        w.put(0, 1)  # drop frame
+        w.put(0, 5)
+        w.put(0, 6)
+        w.put(1, 1)
+        w.put(frame // 25, 6)
+        w.put(frame % 25, 6)
+        w.put(int(closed), 1)
+        w.put(0, 1)
+
+    def picture(self, temporal_reference, kind, f_code=1):
+        w = self.w
+        w.start_code(0x00)
+        w.put(temporal_reference, 10)
+        w.put(kind, 3)
+        w.put(0xFFFF, 16)
+        self.f_code = f_code
+        if kind in (P, B):
+            w.put(0, 1)
+            w.put(f_code, 3)
+        if kind == B:
+            w.put(0, 1)
+            w.put(f_code, 3)
+        w.put(0, 1)
+        if self.rng.random() < 0.5:
+            w.start_code(0xB2)
+            w.put(0x4D4F58, 24)
+
+        for row in range(self.mb_height):
+            self.slice(kind, row)
+
+    def slice(self, kind, row):
+        w = self.w
+        rng = self.rng
+        w.start_code(row + 1)
+        w.put(rng.randint(2, 20), 5)
+        w.put(0, 1)
+
+        # Vectors in half-pel units, as the decoder predicts them.
+        self.pmv = {FORWARD: (0, 0), BACKWARD: (0, 0)}
+        previous = None
+        address = row * self.mb_width - 1
+        skipped = 0
+        for col in range(self.mb_width):
+            last = col == self.mb_width - 1
+            if col > 0 and not last and self.can_skip(kind, previous, col, row):
+                skipped += 1
+                if kind == P:
+                    self.pmv[FORWARD] = (0, 0)
+                continue
+            increment = row * self.mb_width + col - address
+            w.code(ADDRESS_INCREMENT[increment])
+            address = row * self.mb_width + col
+            previous = self.macroblock(kind, col, row)
+
+    def can_skip(self, kind, previous, col, row):
+        if kind == I or self.rng.random() > 0.25:
+            return False
+        if kind == P:
+            return True
+        # Skipped macroblocks of B-pictures repeat the prediction of
+        # the previous one, which must not be intra and has to stay
+        # inside the picture.
+        if previous is None or previous & INTRA:
+            return False
+        return all(self.in_bounds(col, row, self.pmv[d]) for d in (FORWARD, BACKWARD) if previous & d)
+
+    def in_bounds(self, col, row, vector):
+        for size, (h, v) in ((16, vector), (8, (int(vector[0] / 2), int(vector[1] / 2)))):
+            limit = (self.mb_width * size, self.mb_height * size)
+            for pos, motion, end in ((col * size, h, limit[0]), (row * size, v, limit[1])):
+                start = pos + (motion >> 1)
+                if start < 0 or start + size + (motion & 1) > end:
+                    return False
+        return True
+
+    def macroblock(self, kind, col, row):
+        rng = self.rng
+        w = self.w
+        if kind == I:
+            mb_type = INTRA | (QUANT if rng.random() < 0.2 else 0)
+            w.code(TYPE_I[mb_type])
+        elif kind == P:
+            mb_type = rng.choice([
+                FORWARD, FORWARD | PATTERN, PATTERN, INTRA,
+                QUANT | FORWARD | PATTERN, QUANT | PATTERN, INTRA | QUANT,
+            ])
+            w.code(TYPE_P[mb_type])
+        else:
+            mb_type = rng.choice([
+                FORWARD, BACKWARD, FORWARD | BACKWARD,
+                FORWARD | PATTERN, BACKWARD | PATTERN, FORWARD | BACKWARD | PATTERN,
+                INTRA, QUANT | FORWARD | BACKWARD | PATTERN,
+            ])
+            w.code(TYPE_B[mb_type])
+
+        if mb_type & QUANT:
+            w.put(rng.randint(1, 31), 5)
+
+        if mb_type & INTRA:
+            self.pmv = {FORWARD: (0, 0), BACKWARD: (0, 0)}
+        elif kind == P and not mb_type & FORWARD:
+            self.pmv[FORWARD] = (0, 0)
+
+        for direction in (FORWARD, BACKWARD):
+            if mb_type & direction:
+                self.motion_vector(direction, col, row)
+
+        if mb_type & INTRA:
+            cbp = 63
+        elif mb_type & PATTERN:
+            cbp = rng.randint(1, 63)
+            w.code(CBP[cbp])
+        else:
+            cbp = 0
+
+        for block in range(6):
+            if cbp & (32 >> block):
+                self.block(block, bool(mb_type & INTRA))
+        return mb_type
+
+    def motion_vector(self, direction, col, row):
+        f = 1 << (self.f_code - 1)
+        limit = 16 * f
+        while True:
+            vector = (
+                self.rng.randint(-limit, limit - 1),
+                self.rng.randint(-limit, limit - 1),
+            )
+            if self.rng.random() < 0.3:
+                vector = self.pmv[direction]
+            if self.in_bounds(col, row, vector):
+                break
+        for pmv, v in zip(self.pmv[direction], vector):
+            delta = v - pmv
+            if delta >= limit:
+                delta -= 2 * limit
+            elif delta < -limit:
+                delta += 2 * limit
+            if delta == 0:
+                self.w.code(MOTION[0])
+                continue
+            code = (abs(delta) - 1) // f + 1
+            self.w.code(MOTION[code if delta > 0 else -code])
+            if f > 1:
+                self.w.put((abs(delta) - 1) % f, self.f_code - 1)
+        self.pmv[direction] = vector
+
+    def block(self, block, intra):
+        rng = self.rng
+        w = self.w
+        n = 0
+        if intra:
+            size = rng.choice([0, 1, 2, 3, 4, 5, 6])
+            table = DC_LUMA if block < 4 else DC_CHROMA
+            w.code(table[size])
+            if size > 0:
+                diff = rng.randint(1 << (size - 1), (1 << size) - 1)
+                if rng.random() < 0.5:
+                    w.put(diff, size)
+                else:
+                    w.put((1 << size) - 1 - diff, size)
+            n = 1
+
+        count = rng.choice([0, 1, 2, 3, 5]) if intra else rng.choice([1, 2, 3, 6])
+        first = True
+        for _ in range(count):
+            run = rng.choice([0, 0, 0, 1, 2, 5, 13, 40])
+            if n + run >= 64:
+                break
+            level = rng.choice([1, 1, 1, 2, 3, 5, 9, 30, 100, 200])
+            sign = rng.random() < 0.5
+            n += run + 1
+            if run == 0 and level == 1:
+                w.code("1" if first and not intra else "11")
+                w.put(int(sign), 1)
+            elif (run, level) in COEFF:
+                w.code(COEFF[(run, level)])
+                w.put(int(sign), 1)
+            else:
+                w.code("000001")
+                w.put(run, 6)
+                signed = -level if sign else level
+                if -128 < signed < 128:
+                    w.put(signed & 0xFF, 8)
+                elif signed > 0:
+                    w.put(0, 8)
+                    w.put(signed, 8)
+                else:
+                    w.put(128, 8)
+                    w.put(signed + 256, 8)
+            first = False
+        if first and not intra:
+            # Coded blocks have at least one coefficient.
+            w.code("1")
+            w.put(0, 1)
+        w.code("10")
+
+
+def video(seed, width, height, groups, quant_matrices=False, f_code=1):
+    """A video elementary stream. `groups` lists the picture types of
+    each group of pictures in display order."""
+    e = Encoder(random.Random(seed), width, height, quant_matrices)
+    e.sequence_header()
+    pictures = []
+    frame = 0
+    for group_index, group in enumerate(groups):
+        e.group(frame, group_index == 0)
+        # Coding order: every reference picture precedes the
+        # B-pictures displayed before it.
+        pending = []
+        for tr, kind in enumerate(group):
+            if kind == B:
+                pending.append(tr)
+                continue
+            for t in [tr] + pending:
+                pictures.append(((len(e.w.bits) + 7) // 8, frame + t))
+                e.picture(t, B if t in pending else kind, f_code)
+            pending = []
+        frame += len(group)
+    e.w.start_code(0xB7)
+    return e.w.bytes(), pictures
+
+
+def timestamp(prefix, t):
+    return bytes([
+        (prefix << 4) | ((t >> 29) & 0x0E) | 1,
+        (t >> 22) & 0xFF,
+        ((t >> 14) & 0xFE) | 1,
+        (t >> 7) & 0xFF,
+        ((t << 1) & 0xFE) | 1,
+    ])
+
+
+def system(video, packet_size):
+    """Packs a video elementary stream into a system stream with one
+    pack per packet. Packets starting a picture carry its PTS and DTS."""
+    data, pictures = video
+    out = bytearray()
+    for index, begin in enumerate(range(0, len(data), packet_size)):
+        payload = data[begin:begin + packet_size]
+        scr = 1800 * index
+        out += b"\x00\x00\x01\xba" + timestamp(0b0010, scr) + bytes([0x80, 0x1B, 0x83])
+        if index == 0:
+            header = bytes([0x80, 0x1B, 0x83, 0x04, 0xE1, 0xFF, 0xE0, 0xE0, 0x2E])
+            out += b"\x00\x00\x01\xbb" + len(header).to_bytes(2, "big") + header
+        coded = [
+            (n, frame) for n, (offset, frame) in enumerate(pictures)
+            if begin <= offset < begin + len(payload)
+        ]
+        if coded:
+            n, frame = coded[0]
+            header = timestamp(0b0011, 9000 + 3600 * frame) + timestamp(0b0001, 5400 + 3600 * n)
+        else:
+            header = b"\x0f"
+        out += b"\x00\x00\x01\xe0" + (len(header) + len(payload)).to_bytes(2, "big")
+        out += header + payload
+        if index % 3 == 1:
+            out += b"\x00\x00\x01\xbe\x00\x08" + b"\xff" * 8
+    out += b"\x00\x00\x01\xb9"
+    return bytes(out)
+
+
+def main():
+    streams = {
+        "intra.m1v": video(1, 60, 30, [[I, I, I]], quant_matrices=True)[0],
+        "ipb.m1v": video(2, 64, 48, [[I, B, B, P, B, B, P], [I, P, B, P]])[0],
+        "motion.m1v": video(3, 80, 64, [[I, P, B, P, B, B, P]], f_code=2)[0],
+    }
+    streams["system.mpg"] = system(video(4, 48, 32, [[I, B, P, B, P]]), 200)
+    for name, data in streams.items():
+        with open(os.path.join(OUT, name), "wb") as f:
+            f.write(data)
+
+
+if __name__ == "__main__":
+    main()
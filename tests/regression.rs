@@ -0,0 +1,200 @@
+// Decodes the bitstreams in test/regression and compares every frame
+// against the golden checksums stored next to them. A frame is summed
+// up by the MD5 of its displayed area in I420 and by the CRC-32 of each
+// macroblock, so a mismatch is reported down to the first macroblock
+// that differs.
+//
+// The streams are made by test/regression/generate.py from the code
+// tables of ISO 11172-2, independently of the decoder. The golden
+// checksums, however, were recorded with this decoder itself by
+//
+//     UPDATE_GOLDEN=1 cargo test --test regression
+//
+// This is a regression test: it catches changes to the decoded output,
+// not mistakes that were already there when the checksums were taken.
+
+use mpeg_ox::{crc32, md5, Frame, FrameWriter, I420Writer, MpegDecoder, Plane};
+
+use std::fmt::Write;
+use std::path::PathBuf;
+
+const STREAMS: [&str; 4] = ["intra.m1v", "ipb.m1v", "motion.m1v", "system.mpg"];
+
+#[derive(Debug, PartialEq)]
+struct Checksums {
+    pts: u64,
+    md5: String,
+    // In macroblock address order.
+    macroblocks: Vec<u32>,
+    mb_width: usize,
+}
+
+fn path(name: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "test", "regression", name]
+        .iter()
+        .collect()
+}
+
+fn block(plane: &Plane, x: usize, y: usize, size: usize, out: &mut Vec<u8>) {
+    for row in y..y + size {
+        out.extend_from_slice(&plane.row(row)[x..x + size]);
+    }
+}
+
+fn checksums(frame: &Frame) -> Checksums {
+    let mut i420 = I420Writer::new(vec![]);
+    i420.write_frame(frame).unwrap();
+
+    let mb_width = frame.coded_width() / 16;
+    let mut macroblocks = vec![];
+    for row in 0..frame.coded_height() / 16 {
+        for col in 0..mb_width {
+            let mut data = vec![];
+            block(frame.y(), col * 16, row * 16, 16, &mut data);
+            block(frame.cb(), col * 8, row * 8, 8, &mut data);
+            block(frame.cr(), col * 8, row * 8, 8, &mut data);
+            macroblocks.push(crc32(&data));
+        }
+    }
+
+    Checksums {
+        pts: frame.pts(),
+        md5: md5(&i420.into_inner())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+        macroblocks,
+        mb_width,
+    }
+}
+
+fn decode(name: &str) -> Result<Vec<Checksums>, String> {
+    let decoder = MpegDecoder::new(path(name).to_str().unwrap()).map_err(|e| e.to_string())?;
+    let mut frames = vec![];
+    for (n, frame) in decoder.enumerate() {
+        let frame = frame.map_err(|e| format!("frame {}: {}", n, e))?;
+        if let Some(range) = frame.concealed_macroblocks().first() {
+            return Err(format!("frame {}: concealed macroblocks {:?}", n, range));
+        }
+        frames.push(checksums(&frame));
+    }
+    Ok(frames)
+}
+
+// One line per frame: number, PTS, MD5, width in macroblocks and the
+// CRC-32 of every macroblock.
+fn format_golden(frames: &[Checksums]) -> String {
+    let mut s = String::new();
+    for (n, f) in frames.iter().enumerate() {
+        write!(s, "{} {} {} {}", n, f.pts, f.md5, f.mb_width).unwrap();
+        for crc in &f.macroblocks {
+            write!(s, " {:08x}", crc).unwrap();
+        }
+        s.push('\n');
+    }
+    s
+}
+
+fn parse_golden(s: &str) -> Vec<Checksums> {
+    s.lines()
+        .map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            Checksums {
+                pts: fields[1].parse().unwrap(),
+                md5: fields[2].to_string(),
+                mb_width: fields[3].parse().unwrap(),
+                macroblocks: fields[4..]
+                    .iter()
+                    .map(|crc| u32::from_str_radix(crc, 16).unwrap())
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+/// Describes the first difference between the decoded frames and the
+/// golden ones.
+fn compare(actual: &[Checksums], golden: &[Checksums]) -> Option<String> {
+    for (n, (a, g)) in actual.iter().zip(golden).enumerate() {
+        if a.macroblocks.len() != g.macroblocks.len() || a.mb_width != g.mb_width {
+            return Some(format!(
+                "frame {}: {} macroblocks in rows of {}, expected {} in rows of {}",
+                n,
+                a.macroblocks.len(),
+                a.mb_width,
+                g.macroblocks.len(),
+                g.mb_width
+            ));
+        }
+        let mut pairs = a.macroblocks.iter().zip(&g.macroblocks);
+        if let Some(addr) = pairs.position(|(a, g)| a != g) {
+            return Some(format!(
+                "frame {} (pts {}): macroblock {} (row {}, column {}) differs",
+                n,
+                a.pts,
+                addr,
+                addr / a.mb_width,
+                addr % a.mb_width
+            ));
+        }
+        if a.md5 != g.md5 {
+            return Some(format!("frame {}: MD5 {}, expected {}", n, a.md5, g.md5));
+        }
+        if a.pts != g.pts {
+            return Some(format!("frame {}: pts {}, expected {}", n, a.pts, g.pts));
+        }
+    }
+    if actual.len() != golden.len() {
+        return Some(format!(
+            "{} frames, expected {}",
+            actual.len(),
+            golden.len()
+        ));
+    }
+    None
+}
+
+#[test]
+fn test_regression() {
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    let mut failures = vec![];
+
+    for name in STREAMS {
+        let frames = match decode(name) {
+            Ok(frames) => frames,
+            Err(e) => {
+                failures.push(format!("{}: {}", name, e));
+                continue;
+            }
+        };
+        let golden = path(&format!("{}.golden", name));
+        if update {
+            std::fs::write(&golden, format_golden(&frames)).unwrap();
+            continue;
+        }
+
+        let expected = std::fs::read_to_string(&golden).unwrap();
+        if let Some(e) = compare(&frames, &parse_golden(&expected)) {
+            failures.push(format!("{}: {}", name, e));
+        }
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn test_compare() {
+    let golden = parse_golden("0 0 00 2 1 2 3 4\n1 3600 01 2 5 6 7 8\n");
+    assert_eq!(parse_golden(&format_golden(&golden)), golden);
+    assert_eq!(compare(&golden, &golden), None);
+
+    let mut frames = parse_golden(&format_golden(&golden));
+    frames[1].macroblocks[2] = 0;
+    assert_eq!(
+        compare(&frames, &golden).unwrap(),
+        "frame 1 (pts 3600): macroblock 2 (row 1, column 0) differs"
+    );
+
+    frames.truncate(1);
+    assert_eq!(compare(&frames, &golden).unwrap(), "1 frames, expected 2");
+}